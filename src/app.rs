@@ -1,15 +1,46 @@
 use bitcoin::util::hash::Sha256dHash;
-use std::sync::{Arc, Mutex};
+use error_chain::ChainedError;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
 use {daemon, index, signal::Waiter, store};
 
 use errors::*;
 
+/// The index as of `tip`, taken between block updates (so that no block is seen halfway).
+pub struct IndexSnapshot {
+    tip: Sha256dHash,
+    height: usize,
+    store: store::DBSnapshot,
+}
+
+impl IndexSnapshot {
+    pub fn tip(&self) -> &Sha256dHash {
+        &self.tip
+    }
+    /// The height of `tip`, the best chain is read up to it (see `query::ReadContext`).
+    pub fn height(&self) -> usize {
+        self.height
+    }
+    pub fn store(&self) -> &store::ReadStore {
+        &self.store
+    }
+    pub fn db(&self) -> &store::DBSnapshot {
+        &self.store
+    }
+}
+
+enum Store {
+    Writer(store::DBStore),
+    Replica(PathBuf), // of the DB indexed by another process (see `--read-only`)
+}
+
 pub struct App {
-    store: store::DBStore,
+    store: Store,
     index: index::Index,
     daemon: daemon::Daemon,
     tip: Mutex<Sha256dHash>,
+    snapshot: RwLock<Arc<IndexSnapshot>>,
 }
 
 impl App {
@@ -18,20 +49,64 @@ impl App {
         index: index::Index,
         daemon: daemon::Daemon,
     ) -> Result<Arc<App>> {
+        let snapshot = IndexSnapshot {
+            tip: index.best_header_hash(),
+            height: index.best_height(),
+            store: store.snapshot(),
+        };
         Ok(Arc::new(App {
-            store,
+            store: Store::Writer(store),
             index,
             daemon: daemon.reconnect()?,
             tip: Mutex::new(Sha256dHash::default()),
+            snapshot: RwLock::new(Arc::new(snapshot)),
         }))
     }
 
-    fn write_store(&self) -> &store::WriteStore {
-        &self.store
+    /// Serves the DB indexed by another process at `path`, from `snapshot` until it is updated.
+    pub fn new_replica(
+        path: &Path,
+        snapshot: store::DBSnapshot,
+        index: index::Index,
+        daemon: daemon::Daemon,
+    ) -> Result<Arc<App>> {
+        let snapshot = IndexSnapshot {
+            tip: index.best_header_hash(),
+            height: index.best_height(),
+            store: snapshot,
+        };
+        Ok(Arc::new(App {
+            store: Store::Replica(path.to_path_buf()),
+            index,
+            daemon: daemon.reconnect()?,
+            tip: Mutex::new(Sha256dHash::default()),
+            snapshot: RwLock::new(Arc::new(snapshot)),
+        }))
     }
-    // TODO: use index for queries.
-    pub fn read_store(&self) -> &store::ReadStore {
-        &self.store
+
+    /// The writable DB (`None` for replicas).
+    pub fn store(&self) -> Option<&store::DBStore> {
+        match self.store {
+            Store::Writer(ref store) => Some(store),
+            Store::Replica(_) => None,
+        }
+    }
+    /// The latest snapshot, to serve a whole request from.
+    pub fn snapshot(&self) -> Arc<IndexSnapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+    /// The DB opened read-only as of the current tip, e.g. to checkpoint it (see
+    /// `DBSnapshot::backup_to`). Taken between block updates, like `snapshot()`.
+    pub fn read_only_snapshot(&self) -> Result<Arc<IndexSnapshot>> {
+        let _tip = self.tip.lock().expect("failed to lock tip");
+        match self.store {
+            Store::Writer(ref store) => Ok(Arc::new(IndexSnapshot {
+                tip: self.index().best_header_hash(),
+                height: self.index().best_height(),
+                store: store::DBSnapshot::open_read_only(store.path())?,
+            })),
+            Store::Replica(_) => Ok(self.snapshot()), // already opened read-only
+        }
     }
     pub fn index(&self) -> &index::Index {
         &self.index
@@ -42,10 +117,46 @@ impl App {
 
     pub fn update(&self, signal: &Waiter) -> Result<bool> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
-        let new_block = *tip != self.daemon().getbestblockhash()?;
-        if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
-        }
+        let mut new_block = *tip != self.daemon().getbestblockhash()?;
+        let snapshot = match self.store {
+            Store::Writer(ref store) => {
+                if new_block {
+                    *tip = self.index().update(store, &signal)?;
+                }
+                // also picks up the rows written in the background (e.g. see `migration`)
+                IndexSnapshot {
+                    tip: *tip,
+                    height: self.index().best_height(),
+                    store: store.snapshot(),
+                }
+            }
+            Store::Replica(ref path) => {
+                if !new_block {
+                    return Ok(false);
+                }
+                // the writer may not have indexed the new block yet, it is then picked up on
+                // a later update (since the tip is only moved to the indexed one)
+                let store = match store::DBSnapshot::open_read_only(path) {
+                    Ok(store) => store,
+                    Err(e) => {
+                        // e.g. if the writer's compactions deleted a table file meanwhile, the
+                        // previous snapshot is served until the next update retries
+                        warn!("failed to reopen DB: {}", e.display_chain());
+                        return Ok(false);
+                    }
+                };
+                self.index().reload(&store);
+                let indexed_tip = self.index().best_header_hash();
+                new_block = *tip != indexed_tip;
+                *tip = indexed_tip;
+                IndexSnapshot {
+                    tip: *tip,
+                    height: self.index().best_height(),
+                    store,
+                }
+            }
+        };
+        *self.snapshot.write().unwrap() = Arc::new(snapshot);
         Ok(new_block)
     }
 }
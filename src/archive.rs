@@ -0,0 +1,388 @@
+use bincode;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::path::Path;
+
+use config::Config;
+use daemon::Daemon;
+use index::read_indexed_headers;
+use migration::{check_schema, needs_upgrade, Schema, DB_VERSION};
+use store::{family_names, full_compaction, is_fully_compacted, DBStore, ReadStore, Row};
+use store::{DBSnapshot, WriteStore};
+use util::HeaderEntry;
+
+use errors::*;
+
+/// An archive is a directory with one gzipped file of sorted rows per column family, and this
+/// manifest describing them. It can be published, and used to bootstrap new deployments
+/// without indexing the whole chain (see `--export-archive` and `--import-archive`).
+const MANIFEST_NAME: &str = "MANIFEST.json";
+const IMPORT_BATCH_SIZE: usize = 100_000;
+const HEADERS_BATCH_SIZE: usize = 2000; // verified against the daemon at once
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub schema: Schema,
+    pub tip: String,
+    pub height: usize,
+    pub files: Vec<ArchiveFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub family: String,
+    pub name: String,
+    pub rows: usize,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Exports a fully compacted DB to a new archive directory.
+pub fn export_archive(store: &DBStore, config: &Config, dir: &Path) -> Result<Manifest> {
+    if !is_fully_compacted(store) {
+        bail!("the index is not fully built yet, let electrs sync before exporting it");
+    }
+    let schema = check_schema(store, config)?;
+    if needs_upgrade(store, &schema) {
+        bail!("the index must be upgraded before exporting it, let electrs run first");
+    }
+    if dir.exists() {
+        bail!("archive {:?} already exists", dir);
+    }
+    let snapshot = store.snapshot();
+    let headers = read_indexed_headers(&snapshot);
+    let tip = *headers.tip();
+    info!("exporting DB at block {} to {:?}", tip, dir);
+    fs::create_dir_all(dir).chain_err(|| format!("failed to create {:?}", dir))?;
+    let mut files = vec![];
+    for family in family_names() {
+        let file = export_family(&snapshot, family, dir)?;
+        debug!("exported {:?}", file);
+        files.push(file);
+    }
+    let manifest = Manifest {
+        schema,
+        tip: tip.be_hex_string(),
+        height: headers.len() - 1,
+        files,
+    };
+    // written last, so that an interrupted export is never mistaken for a complete one
+    let path = dir.join(MANIFEST_NAME);
+    let content = serde_json::to_string_pretty(&manifest).unwrap();
+    fs::write(&path, content).chain_err(|| format!("failed to write {:?}", path))?;
+    info!(
+        "exported {} rows",
+        manifest.files.iter().map(|f| f.rows).sum::<usize>()
+    );
+    Ok(manifest)
+}
+
+fn export_family(snapshot: &DBSnapshot, family: &str, dir: &Path) -> Result<ArchiveFile> {
+    let name = format!("{}.rows.gz", family);
+    let path = dir.join(&name);
+    let file = File::create(&path).chain_err(|| format!("failed to create {:?}", path))?;
+    let mut encoder = GzEncoder::new(BufWriter::new(file), Compression::default());
+    let mut rows = 0;
+    for row in snapshot.family_rows(family) {
+        let row = row.chain_err(|| format!("failed to export `{}` column family", family))?;
+        bincode::serialize_into(&mut encoder, &(row.key, row.value))
+            .chain_err(|| format!("failed to write {:?}", path))?;
+        rows += 1;
+    }
+    encoder
+        .finish()
+        .and_then(|mut writer| writer.flush())
+        .chain_err(|| format!("failed to write {:?}", path))?;
+    let (size, sha256) = file_digest(&path)?;
+    Ok(ArchiveFile {
+        family: family.to_string(),
+        name,
+        rows,
+        size,
+        sha256,
+    })
+}
+
+/// Bootstraps the (missing or empty) DB directory from an archive, after checking it against
+/// the configuration and the daemon's chain. Indexing then resumes from the archive's tip.
+pub fn import_archive(dir: &Path, config: &Config, daemon: &Daemon) -> Result<()> {
+    let db_path = &config.db_path;
+    if db_path.exists()
+        && fs::read_dir(db_path)
+            .chain_err(|| format!("failed to list {:?}", db_path))?
+            .next()
+            .is_some()
+    {
+        warn!("not importing {:?}, a DB exists at {:?}", dir, db_path);
+        return Ok(());
+    }
+    let manifest = read_manifest(dir, config)?;
+    info!(
+        "importing DB at block {} (height {}) from {:?}",
+        manifest.tip, manifest.height, dir
+    );
+    // loaded aside, so that an invalid (or interrupted) import doesn't leave a broken DB behind
+    let staging = db_path.with_extension("importing");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).chain_err(|| format!("failed to remove {:?}", staging))?;
+    }
+    let result = {
+        let store = DBStore::open(&staging, /*low_memory=*/ false);
+        load_archive(&store, dir, &manifest)
+            .and_then(|_| check_loaded_schema(&store, &manifest, config))
+            .and_then(|_| verify_headers(&store, &manifest, daemon))
+            .map(|_| full_compaction(store))
+    };
+    if let Err(e) = result {
+        fs::remove_dir_all(&staging).chain_err(|| format!("failed to remove {:?}", staging))?;
+        return Err(e).chain_err(|| format!("cannot import {:?}", dir));
+    }
+    fs::rename(&staging, db_path).chain_err(|| format!("failed to move DB to {:?}", db_path))?;
+    info!("imported DB at block {}", manifest.tip);
+    Ok(())
+}
+
+// Checks everything that can be checked before loading any row.
+fn read_manifest(dir: &Path, config: &Config) -> Result<Manifest> {
+    let path = dir.join(MANIFEST_NAME);
+    let content = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+    let manifest: Manifest =
+        serde_json::from_slice(&content).chain_err(|| format!("invalid manifest {:?}", path))?;
+    if manifest.schema.version != DB_VERSION {
+        bail!(
+            "archive has database version {}, expected {}",
+            manifest.schema.version,
+            DB_VERSION
+        );
+    }
+    manifest.schema.check(config)?;
+    check_families(&manifest)?;
+    for file in &manifest.files {
+        let (size, sha256) = file_digest(&dir.join(&file.name))?;
+        if size != file.size || sha256 != file.sha256 {
+            bail!(
+                "{:?} doesn't match its checksum, the archive is corrupted",
+                file.name
+            );
+        }
+    }
+    Ok(manifest)
+}
+
+// The DB version which added a column family, archives of older DBs don't have it (and their
+// upgrade builds its rows).
+fn added_in_version(family: &str) -> u32 {
+    match family {
+        "txidsearch" | "addrsearch" => 3,
+        _ => 1,
+    }
+}
+
+// Every column family must be archived, in a single file (a missing one would be left empty).
+fn check_families(manifest: &Manifest) -> Result<()> {
+    let families = family_names();
+    for file in &manifest.files {
+        if !families.contains(&file.family.as_str()) {
+            bail!("unknown column family {:?} in archive", file.family);
+        }
+    }
+    for family in families {
+        match manifest.files.iter().filter(|f| f.family == family).count() {
+            0 if manifest.schema.version < added_in_version(family) => (),
+            0 => bail!("column family {:?} is missing from archive", family),
+            1 => (),
+            n => bail!("column family {:?} is archived {} times", family, n),
+        }
+    }
+    Ok(())
+}
+
+// The manifest was checked instead of the `C` row before loading the rows, so they must agree.
+fn check_loaded_schema(store: &ReadStore, manifest: &Manifest, config: &Config) -> Result<()> {
+    let schema = check_schema(store, config)?;
+    if schema != manifest.schema {
+        bail!(
+            "archive schema {:?} doesn't match its manifest ({:?})",
+            schema,
+            manifest.schema
+        );
+    }
+    Ok(())
+}
+
+// Rows are sorted, so loading them in bulk-import mode is close to ingesting sorted files. The
+// C API could write and ingest SST files (`rocksdb_sstfilewriter_*` and
+// `rocksdb_ingest_external_file_cf`), but the `rocksdb` crate doesn't expose the DB handle
+// needed to ingest them.
+fn load_archive(store: &DBStore, dir: &Path, manifest: &Manifest) -> Result<()> {
+    for file in &manifest.files {
+        let path = dir.join(&file.name);
+        let reader = File::open(&path).chain_err(|| format!("failed to open {:?}", path))?;
+        read_rows(GzDecoder::new(BufReader::new(reader)), file.rows, |rows| {
+            store.write(rows)
+        })
+        .chain_err(|| format!("failed to import {:?}", path))?;
+        debug!("imported {} rows from {:?}", file.rows, file.name);
+    }
+    Ok(())
+}
+
+// Reads exactly `count` rows, passed to `write` in batches. The checksum doesn't tell whether
+// the manifest's row count is right, so the rows must end with the file.
+fn read_rows<R: Read, F: FnMut(Vec<Row>)>(mut reader: R, count: usize, mut write: F) -> Result<()> {
+    let mut rows = vec![];
+    for index in 0..count {
+        let (key, value): (Vec<u8>, Vec<u8>) = bincode::deserialize_from(&mut reader)
+            .chain_err(|| format!("failed to read row {} of {}", index, count))?;
+        rows.push(Row { key, value });
+        if rows.len() == IMPORT_BATCH_SIZE {
+            write(mem::replace(&mut rows, vec![]));
+        }
+    }
+    if reader.read(&mut [0u8]).chain_err(|| "failed to read")? > 0 {
+        bail!("more data follows the {} rows of the manifest", count);
+    }
+    write(rows);
+    Ok(())
+}
+
+// The archive's headers must be the daemon's, up to the archive's tip.
+fn verify_headers(store: &ReadStore, manifest: &Manifest, daemon: &Daemon) -> Result<()> {
+    let headers = read_indexed_headers(store);
+    if headers.tip().be_hex_string() != manifest.tip || headers.len() != manifest.height + 1 {
+        bail!("archive headers don't match its manifest");
+    }
+    let entries: Vec<&HeaderEntry> = headers.iter().collect();
+    for chunk in entries.chunks(HEADERS_BATCH_SIZE) {
+        let heights: Vec<usize> = chunk.iter().map(|entry| entry.height()).collect();
+        let daemon_headers = daemon
+            .getblockheaders(&heights)
+            .chain_err(|| "failed to get headers, the daemon must be synced past the archive")?;
+        for (entry, header) in chunk.iter().zip(daemon_headers.iter()) {
+            if entry.header() != header {
+                bail!(
+                    "archive block {} at height {} is not in the daemon's chain",
+                    entry.hash(),
+                    entry.height()
+                );
+            }
+        }
+        debug!("verified {} archive headers", heights.last().unwrap() + 1);
+    }
+    Ok(())
+}
+
+fn file_digest(path: &Path) -> Result<(u64, String)> {
+    let mut file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    let mut size = 0;
+    loop {
+        let n = file
+            .read(&mut buf)
+            .chain_err(|| format!("failed to read {:?}", path))?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((size, hasher.result_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_families, file_digest, read_rows, ArchiveFile, Manifest};
+    use bincode;
+    use serde_json;
+    use std::fs;
+    use std::path::PathBuf;
+    use store::family_names;
+
+    #[test]
+    fn test_file_digest() {
+        let path = PathBuf::from(format!("/tmp/electrs-digest-{}", ::std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        let (size, sha256) = file_digest(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(
+            sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_read_rows() {
+        let mut data = vec![];
+        for key in &[b"A", b"B"] {
+            bincode::serialize_into(&mut data, &(key.to_vec(), b"".to_vec())).unwrap();
+        }
+        let mut keys = vec![];
+        read_rows(&data[..], 2, |rows| {
+            keys.extend(rows.into_iter().map(|row| row.key))
+        })
+        .unwrap();
+        assert_eq!(keys, vec![b"A".to_vec(), b"B".to_vec()]);
+        assert!(read_rows(&data[..], 1, |_| ()).is_err());
+        assert!(read_rows(&data[..], 3, |_| ()).is_err());
+    }
+
+    #[test]
+    fn test_manifest_format() {
+        let manifest: Manifest = serde_json::from_value(json!({
+            "schema": {
+                "network_type": "regtest",
+                "version": 2,
+                "extended_db_enabled": true,
+                "hash_prefix_len": 8
+            },
+            "tip": "00000000000000000000000000000000000000000000000000000000000000ff",
+            "height": 10,
+            "files": [{ "family": "default", "name": "default.rows.gz", "rows": 1, "size": 20, "sha256": "00" }]
+        }))
+        .unwrap();
+        assert_eq!(manifest.schema.hash_prefix_len, 8);
+        assert_eq!(manifest.files[0].family, "default");
+    }
+
+    #[test]
+    fn test_check_families() {
+        let file = |family: &str| ArchiveFile {
+            family: family.to_string(),
+            name: format!("{}.rows.gz", family),
+            rows: 0,
+            size: 0,
+            sha256: String::new(),
+        };
+        let mut manifest: Manifest = serde_json::from_value(json!({
+            "schema": {
+                "network_type": "regtest",
+                "version": 2,
+                "extended_db_enabled": true,
+                "hash_prefix_len": 8
+            },
+            "tip": "00000000000000000000000000000000000000000000000000000000000000ff",
+            "height": 10,
+            "files": []
+        }))
+        .unwrap();
+        manifest.files = family_names().into_iter().map(file).collect();
+        assert!(check_families(&manifest).is_ok());
+
+        manifest.files.push(file("txin"));
+        assert!(check_families(&manifest).is_err()); // duplicated
+        manifest.files.pop();
+        manifest.files.retain(|f| f.family != "txout");
+        assert!(check_families(&manifest).is_err()); // missing
+        manifest.files.push(file("unknown"));
+        assert!(check_families(&manifest).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::Sha256dHash;
+use error_chain::ChainedError;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use config::Config;
+use index::read_indexed_headers;
+use migration::check_schema;
+use query::Query;
+use store::{DBSnapshot, DBStore, ReadStore};
+use util::spawn_thread;
+
+use errors::*;
+
+/// A complete backup: a RocksDB checkpoint of the DB as of `tip`, which can be used as a DB
+/// directory as is (see `--restore-from`).
+#[derive(Clone, Debug, Serialize)]
+pub struct BackupInfo {
+    pub path: PathBuf,
+    pub tip: String,
+    pub height: usize,
+    pub size: u64, // in bytes, though its table files are hard-linked to the DB's when possible
+    pub duration_secs: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum BackupStatus {
+    Idle,
+    Running {
+        path: PathBuf,
+        tip: String,
+        height: usize,
+    },
+    Done(BackupInfo),
+    Failed {
+        error: String,
+    },
+}
+
+/// Creates backups in the background (one at a time), for the `/admin/backup` endpoint.
+pub struct Backups {
+    dir: PathBuf,
+    status: Arc<Mutex<BackupStatus>>,
+}
+
+impl Backups {
+    pub fn new(dir: &Path) -> Backups {
+        Backups {
+            dir: dir.to_path_buf(),
+            status: Arc::new(Mutex::new(BackupStatus::Idle)),
+        }
+    }
+
+    pub fn status(&self) -> BackupStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Backs up the DB as of the current tip, so the copy is consistent with it even if blocks
+    /// are indexed meanwhile.
+    pub fn start(&self, query: &Query) -> Result<BackupStatus> {
+        let mut status = self.status.lock().unwrap();
+        if let BackupStatus::Running { .. } = *status {
+            bail!(ErrorKind::BackupInProgress);
+        }
+        fs::create_dir_all(&self.dir).chain_err(|| format!("failed to create {:?}", self.dir))?;
+        let snapshot = query.read_only_snapshot()?;
+        let height = snapshot.height();
+        let path = self
+            .dir
+            .join(format!("{}-{}", height, snapshot.tip().be_hex_string()));
+        *status = BackupStatus::Running {
+            path: path.clone(),
+            tip: snapshot.tip().be_hex_string(),
+            height,
+        };
+        let shared_status = self.status.clone();
+        spawn_thread("backup", move || {
+            // a panic must not leave the backup running, which would refuse the next ones
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                create_backup(snapshot.db(), snapshot.tip(), height, &path)
+            }))
+            .unwrap_or_else(|_| Err("backup panicked".into()));
+            *shared_status.lock().unwrap() = match result {
+                Ok(info) => BackupStatus::Done(info),
+                Err(e) => {
+                    error!("backup failed: {}", e.display_chain());
+                    BackupStatus::Failed {
+                        error: e.to_string(),
+                    }
+                }
+            };
+        });
+        Ok(status.clone())
+    }
+}
+
+/// Backs up the DB (while electrs is stopped, see `electrs-admin backup`) into `dir`, named as
+/// by the `/admin/backup` endpoint.
+pub fn backup_db(store: &DBStore, dir: &Path) -> Result<BackupInfo> {
+    let snapshot = DBSnapshot::open_read_only(store.path())?;
+    let headers = read_indexed_headers(&snapshot);
+    if headers.len() == 0 {
+        bail!("no block is indexed yet");
+    }
+    let tip = *headers.tip();
+    let height = headers.len() - 1;
+    fs::create_dir_all(dir).chain_err(|| format!("failed to create {:?}", dir))?;
+    let path = dir.join(format!("{}-{}", height, tip.be_hex_string()));
+    create_backup(&snapshot, &tip, height, &path)
+}
+
+fn create_backup(
+    db: &DBSnapshot,
+    tip: &Sha256dHash,
+    height: usize,
+    path: &Path,
+) -> Result<BackupInfo> {
+    if path.exists() {
+        bail!("backup {:?} already exists", path);
+    }
+    // written aside, so that an interrupted backup is never mistaken for a complete one
+    let partial = path.with_extension("partial");
+    if partial.exists() {
+        fs::remove_dir_all(&partial).chain_err(|| format!("failed to remove {:?}", partial))?;
+    }
+    info!("backing up DB at block {} to {:?}", tip, path);
+    let start = Instant::now();
+    db.backup_to(&partial)
+        .chain_err(|| format!("failed to back up DB to {:?}", partial))?;
+    let size = dir_size(&partial)?;
+    fs::rename(&partial, path).chain_err(|| format!("failed to move backup to {:?}", path))?;
+    let info = BackupInfo {
+        path: path.to_path_buf(),
+        tip: tip.be_hex_string(),
+        height,
+        size,
+        duration_secs: start.elapsed().as_secs(),
+    };
+    info!("backup done: {:?}", info);
+    Ok(info)
+}
+
+/// Restores a backup into the (missing or empty) DB directory. Indexing then resumes from the
+/// backup's tip, as after a restart.
+pub fn restore_backup(backup: &Path, config: &Config) -> Result<()> {
+    let db_path = &config.db_path;
+    if db_path.exists()
+        && fs::read_dir(db_path)
+            .chain_err(|| format!("failed to list {:?}", db_path))?
+            .next()
+            .is_some()
+    {
+        warn!("not restoring {:?}, a DB exists at {:?}", backup, db_path);
+        return Ok(());
+    }
+    // copied aside, so that an invalid (or interrupted) restore doesn't leave a broken DB behind
+    let staging = db_path.with_extension("restoring");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).chain_err(|| format!("failed to remove {:?}", staging))?;
+    }
+    info!("restoring DB from {:?}", backup);
+    copy_files(backup, &staging)?;
+    let tip = {
+        let store = DBStore::open(&staging, /*low_memory=*/ false);
+        check_backup(&store, config)
+    };
+    let tip = match tip {
+        Ok(tip) => tip,
+        Err(e) => {
+            fs::remove_dir_all(&staging).chain_err(|| format!("failed to remove {:?}", staging))?;
+            return Err(e).chain_err(|| format!("cannot restore {:?}", backup));
+        }
+    };
+    fs::rename(&staging, db_path).chain_err(|| format!("failed to move DB to {:?}", db_path))?;
+    info!("restored DB at block {}", tip);
+    Ok(())
+}
+
+fn check_backup(store: &ReadStore, config: &Config) -> Result<Sha256dHash> {
+    check_schema(store, config)?;
+    let tip = store.get(b"L").chain_err(|| "no indexed blocks")?;
+    deserialize(&tip).chain_err(|| "invalid last indexed block row")
+}
+
+// RocksDB directories are flat
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir).chain_err(|| format!("failed to list {:?}", dir))? {
+        let entry = entry.chain_err(|| format!("failed to list {:?}", dir))?;
+        let metadata = entry
+            .metadata()
+            .chain_err(|| format!("failed to read {:?}", entry.path()))?;
+        size += metadata.len();
+    }
+    Ok(size)
+}
+
+fn copy_files(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).chain_err(|| format!("failed to create {:?}", to))?;
+    for entry in fs::read_dir(from).chain_err(|| format!("failed to list {:?}", from))? {
+        let path = entry
+            .chain_err(|| format!("failed to list {:?}", from))?
+            .path();
+        if !path.is_file() {
+            bail!("unexpected {:?} in backup", path);
+        }
+        let target = to.join(path.file_name().unwrap());
+        fs::copy(&path, &target).chain_err(|| format!("failed to copy {:?}", path))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,323 @@
+extern crate bitcoin;
+extern crate clap;
+extern crate electrs;
+#[macro_use]
+extern crate error_chain;
+extern crate hex;
+extern crate stderrlog;
+
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use error_chain::ChainedError;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::process;
+
+use electrs::{
+    backup::backup_db,
+    errors::*,
+    index::{
+        last_indexed_block, parse_header_row, read_indexed_blockhashes, RawTxRow, RowCodec,
+        TxInRow, TxOutRow, TxRow,
+    },
+    migration::read_schema,
+    store::{family_names, full_compaction, DBStore, ReadStore, Row},
+    util::full_hash,
+};
+
+// Offline inspection and maintenance of the index, while electrs isn't running.
+fn main() {
+    let m = App::new("electrs-admin")
+        .about("Inspects and maintains an electrs index database (stop electrs first)")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("verbosity")
+                .short("v")
+                .multiple(true)
+                .help("Increase logging verbosity"),
+        )
+        .arg(
+            Arg::with_name("db_dir")
+                .long("db-dir")
+                .help("Directory of the index database (default: ./db/)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network")
+                .long("network")
+                .help("Select Bitcoin network type ('mainnet', 'testnet' or 'regtest')")
+                .takes_value(true),
+        )
+        .subcommand(SubCommand::with_name("stats").about("Print row counts and sizes per prefix"))
+        .subcommand(
+            SubCommand::with_name("tx")
+                .about("Print the decoded rows of a transaction")
+                .arg(Arg::with_name("txid").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("scripthash")
+                .about("Print the decoded rows of a script hash")
+                .arg(Arg::with_name("scripthash").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-headers")
+                .about("Check that the indexed headers are consistent and form a chain up to the last indexed block"),
+        )
+        .subcommand(SubCommand::with_name("compact").about("Run a full compaction"))
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Back up the database into <dir>/<height>-<tip hash>, as `POST /admin/backup` does")
+                .arg(Arg::with_name("dir").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("reset-markers")
+                .about("Reset the full compaction (`F`) and last indexed block (`L`) markers")
+                .arg(
+                    Arg::with_name("compaction")
+                        .long("compaction")
+                        .help("Remove the full compaction marker, so that it runs again on startup"),
+                )
+                .arg(
+                    Arg::with_name("tip")
+                        .long("tip")
+                        .help("Rewind the last indexed block to this (indexed) block hash, so that later blocks are indexed again")
+                        .takes_value(true),
+                )
+                .group(
+                    clap::ArgGroup::with_name("markers")
+                        .args(&["compaction", "tip"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .get_matches();
+
+    stderrlog::new()
+        .verbosity(m.occurrences_of("verbosity") as usize)
+        .timestamp(stderrlog::Timestamp::Millisecond)
+        .init()
+        .expect("logging initialization failed");
+
+    if let Err(e) = run(&m) {
+        eprintln!("{}", e.display_chain());
+        process::exit(1);
+    }
+}
+
+fn run(m: &ArgMatches) -> Result<()> {
+    let network_name = m.value_of("network").unwrap_or("mainnet");
+    let db_path = Path::new(m.value_of("db_dir").unwrap_or("./db")).join(network_name);
+    if !db_path.exists() {
+        bail!("no database at {:?}", db_path);
+    }
+    let store = DBStore::open(&db_path, /*low_memory=*/ false);
+    let schema = read_schema(&store)?;
+    let codec = schema.row_codec();
+    println!("{:?} at {:?}", schema, db_path);
+
+    match m.subcommand() {
+        ("stats", _) => print_stats(&store),
+        ("tx", Some(args)) => dump_tx(&store, codec, args.value_of("txid").unwrap()),
+        ("scripthash", Some(args)) => {
+            dump_scripthash(&store, codec, args.value_of("scripthash").unwrap())
+        }
+        ("verify-headers", _) => verify_headers(&store),
+        ("compact", _) => {
+            full_compaction(store);
+            Ok(())
+        }
+        ("backup", Some(args)) => {
+            let info = backup_db(&store, Path::new(args.value_of("dir").unwrap()))?;
+            println!(
+                "backed up the DB up to block {} (height {}) to {:?} ({} bytes)",
+                info.tip, info.height, info.path, info.size
+            );
+            Ok(())
+        }
+        ("reset-markers", Some(args)) => reset_markers(&store, args),
+        _ => unreachable!(),
+    }
+}
+
+fn print_stats(store: &DBStore) -> Result<()> {
+    #[derive(Default)]
+    struct PrefixStats {
+        family: &'static str,
+        rows: usize,
+        key_bytes: usize,
+        value_bytes: usize,
+    }
+    let snapshot = store.snapshot();
+    let mut stats = BTreeMap::<u8, PrefixStats>::new();
+    for family in family_names() {
+        for row in snapshot.family_rows(family) {
+            let row = row?;
+            let entry = stats.entry(row.key[0]).or_default();
+            entry.family = family;
+            entry.rows += 1;
+            entry.key_bytes += row.key.len();
+            entry.value_bytes += row.value.len();
+        }
+    }
+    println!(
+        "{:>6} {:>12} {:>12} {:>14} {:>14}",
+        "prefix", "family", "rows", "key bytes", "value bytes"
+    );
+    for (code, s) in stats {
+        println!(
+            "{:>6} {:>12} {:>12} {:>14} {:>14}",
+            format!("{:?}", code as char),
+            s.family,
+            s.rows,
+            s.key_bytes,
+            s.value_bytes
+        );
+    }
+    Ok(())
+}
+
+fn dump_tx(store: &DBStore, codec: RowCodec, txid: &str) -> Result<()> {
+    let txid = Sha256dHash::from_hex(txid).chain_err(|| "invalid txid")?;
+    for row in store.scan(&TxRow::filter_full(&txid)) {
+        let row = TxRow::from_row(&row);
+        println!(
+            "T: confirmed at height {} in block {}",
+            row.height, row.blockhash
+        );
+    }
+    for row in store.scan(&RawTxRow::filter_full(&txid)) {
+        let row = RawTxRow::from_row(&row);
+        println!("t: {} bytes", row.rawtx.len());
+    }
+    // spending rows are only keyed by prefixes, so they may include other transactions' outputs
+    let spent_prefix = [b"I", &codec.hash_prefix(&txid[..])[..]].concat();
+    for row in store.scan(&spent_prefix) {
+        let row = TxInRow::from_row(codec, &row)?;
+        println!(
+            "I: output {} spent by txid prefix {}",
+            row.prev_index,
+            hex::encode(&row.txid_prefix[..])
+        );
+    }
+    Ok(())
+}
+
+fn dump_scripthash(store: &DBStore, codec: RowCodec, script_hash: &str) -> Result<()> {
+    let script_hash = full_hash(&hex::decode(script_hash).chain_err(|| "invalid script hash")?);
+    for row in store.scan(&TxOutRow::filter(codec, &script_hash[..])) {
+        let row = TxOutRow::from_row(codec, &row)?;
+        let txids: Vec<String> = store
+            .scan(&TxRow::filter_prefix(&row.txid_prefix))
+            .iter()
+            .map(|row| {
+                let row = TxRow::from_row(row);
+                format!("{} at height {}", hex_txid(&row.key.txid), row.height)
+            })
+            .collect();
+        println!(
+            "O: funded by txid prefix {} ({})",
+            hex::encode(&row.txid_prefix[..]),
+            txids.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn hex_txid(txid: &[u8]) -> String {
+    let txid: Sha256dHash = deserialize(txid).unwrap();
+    txid.be_hex_string()
+}
+
+// Unlike `read_indexed_headers`, reports every inconsistency instead of panicking on the first.
+fn verify_headers(store: &DBStore) -> Result<()> {
+    let mut errors = 0;
+    let mut headers = HashMap::new();
+    for row in store.iter_scan(b"B") {
+        match parse_header_row(&row) {
+            Ok((hash, header)) => {
+                if header.bitcoin_hash() != hash {
+                    eprintln!(
+                        "header row of block {} holds block {}",
+                        hash,
+                        header.bitcoin_hash()
+                    );
+                    errors += 1;
+                }
+                headers.insert(hash, header);
+            }
+            Err(e) => {
+                eprintln!("header row {}: {}", hex::encode(&row.key), e);
+                errors += 1;
+            }
+        }
+    }
+    // the best chain, from the last indexed block down to the genesis block
+    let null_hash = Sha256dHash::default();
+    let tip: Sha256dHash = match store.get(b"L") {
+        Some(value) => deserialize(&value).chain_err(|| "invalid last indexed block row")?,
+        None => null_hash,
+    };
+    let mut chain = HashSet::new();
+    let mut blockhash = tip;
+    while blockhash != null_hash {
+        let header = match headers.get(&blockhash) {
+            Some(header) => header,
+            None => {
+                eprintln!("block {} of the best chain is missing", blockhash);
+                errors += 1;
+                break;
+            }
+        };
+        if !chain.insert(blockhash) {
+            eprintln!("block {} is its own ancestor", blockhash);
+            errors += 1;
+            break;
+        }
+        blockhash = header.prev_blockhash;
+    }
+    // the other headers were indexed before a reorg, so they must fork from indexed ones
+    let mut stale = 0;
+    for (hash, header) in &headers {
+        if chain.contains(hash) {
+            continue;
+        }
+        if header.prev_blockhash != null_hash && !headers.contains_key(&header.prev_blockhash) {
+            eprintln!(
+                "block {} is an orphan, its parent {} is missing",
+                hash, header.prev_blockhash
+            );
+            errors += 1;
+        } else {
+            stale += 1;
+        }
+    }
+    println!(
+        "{} headers up to {}, {} stale headers",
+        chain.len(),
+        tip,
+        stale
+    );
+    if errors > 0 {
+        bail!("found {} inconsistent headers", errors);
+    }
+    Ok(())
+}
+
+fn reset_markers(store: &DBStore, args: &ArgMatches) -> Result<()> {
+    let mut keys = vec![];
+    let mut rows: Vec<Row> = vec![];
+    if args.is_present("compaction") {
+        keys.push(b"F".to_vec());
+    }
+    if let Some(tip) = args.value_of("tip") {
+        let tip = Sha256dHash::from_hex(tip).chain_err(|| "invalid block hash")?;
+        if !read_indexed_blockhashes(store).contains(&tip) {
+            bail!("block {} isn't indexed", tip);
+        }
+        rows.push(last_indexed_block(&tip));
+    }
+    store.rewrite(keys, rows);
+    println!("markers reset, restart electrs to apply them");
+    Ok(())
+}
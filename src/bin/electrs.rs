@@ -1,31 +1,44 @@
 extern crate electrs;
 
+#[macro_use]
 extern crate error_chain;
 #[macro_use]
 extern crate log;
 
 use electrs::rest;
 use error_chain::ChainedError;
+use std::path::Path;
 use std::process;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
 use std::time::Duration;
 
 use electrs::{
     app::App,
+    archive::{export_archive, import_archive},
+    backup::restore_backup,
     bulk,
     config::Config,
     daemon::Daemon,
     errors::*,
     index::Index,
     metrics::Metrics,
+    migration::{
+        load_schema, needs_upgrade, read_schema, run_schema_upgrade, start_schema_upgrade,
+    },
     query::Query,
     signal::Waiter,
-    store::{full_compaction, is_fully_compacted, verify_index_compatibility, DBStore},
+    status::{SyncPhase, SyncStatus},
+    store::{full_compaction, is_fully_compacted, DBSnapshot, DBStore},
+    verify::{interrupted_reindex, reindex_range, verify_index},
 };
 
 fn run_server(config: Config) -> Result<()> {
     let signal = Waiter::new();
-    let metrics = Metrics::new(config.monitoring_addr);
+    let metrics = Metrics::new(config.monitoring_addr.clone(), config.unix_socket_mode);
     metrics.start();
+    let status = SyncStatus::new();
+    rest::run_server(&config, status.clone(), &signal)?; // queries are served once synced
 
     let daemon = Daemon::new(
         &config.daemon_dir,
@@ -35,42 +48,141 @@ fn run_server(config: Config) -> Result<()> {
         signal.clone(),
         &metrics,
     )?;
+    if config.read_only {
+        return run_replica(&config, daemon, &metrics, status, &signal);
+    }
+    if let Some(ref backup) = config.restore_from {
+        restore_backup(backup, &config)?;
+    }
+    if let Some(ref archive) = config.import_archive {
+        import_archive(archive, &config, &daemon)?;
+    }
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, &metrics, &config)?;
+    let mut store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
+    let mut schema = load_schema(&mut store, &config)?;
+    let interrupted = interrupted_reindex(&store)?;
+    // nothing is served during the initial import, nor while verifying or reindexing blocks
+    if config.verify_from.is_some()
+        || config.reindex_from.is_some()
+        || interrupted.is_some()
+        || !is_fully_compacted(&store)
+    {
+        run_schema_upgrade(&store, &mut schema)?;
+    }
+    let codec = schema.row_codec();
+    let index = Index::load(&store, &daemon, &metrics, &config, codec)?;
+    if let Some(range) = interrupted {
+        warn!(
+            "completing the interrupted reindex of blocks {}..{}",
+            range.0, range.1
+        );
+        let extended_db_enabled = config.extended_db_enabled && schema.extended_db_enabled;
+        reindex_range(&store, &daemon, range, codec, extended_db_enabled)?;
+        index.reload(&store);
+    }
 
-    verify_index_compatibility(&store, &config);
+    if let Some(from) = config.verify_from {
+        let to = config.verify_to.unwrap_or_else(|| index.best_height());
+        let extended_db_enabled = config.extended_db_enabled && schema.extended_db_enabled;
+        let report = verify_index(
+            &store,
+            &daemon,
+            &metrics,
+            (from, to),
+            codec,
+            extended_db_enabled,
+            config.verify_repair,
+        )?;
+        if !config.verify_repair && report.missing + report.mismatched + report.extra > 0 {
+            bail!("the index differs from the daemon's blocks, see --verify-repair");
+        }
+        return Ok(());
+    }
+    if let Some(from) = config.reindex_from {
+        let to = config.reindex_to.unwrap_or_else(|| index.best_height());
+        let extended_db_enabled = config.extended_db_enabled && schema.extended_db_enabled;
+        reindex_range(&store, &daemon, (from, to), codec, extended_db_enabled)?;
+        return Ok(());
+    }
 
     let store = if is_fully_compacted(&store) {
         store // initial import and full compaction are over
     } else {
         if config.jsonrpc_import {
+            status.set_phase(SyncPhase::Catchup);
             index.update(&store, &signal)?; // slower: uses JSONRPC for fetching blocks
+            status.set_phase(SyncPhase::Compaction);
             full_compaction(store)
         } else {
             // faster, but uses more memory
-            let store = bulk::index_blk_files(&daemon, &config, &metrics, store)?;
+            status.set_phase(SyncPhase::BulkImport);
+            let store =
+                bulk::index_blk_files(&daemon, &config, &metrics, status.clone(), store, codec)?;
+            status.set_phase(SyncPhase::Compaction);
             let store = full_compaction(store);
             index.reload(&store); // make sure the block header index is up-to-date
             store
         }
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
+    status.update(|info| info.fully_compacted = true);
+    status.set_phase(SyncPhase::Catchup);
 
     let app = App::new(store, index, daemon)?;
-    let query = Query::new(app.clone(), config.extended_db_enabled, &metrics);
+    let query = Query::new(
+        app.clone(),
+        config.extended_db_enabled && schema.extended_db_enabled,
+        &metrics,
+    );
+    let upgrade_failures = start_schema_upgrade(app.clone(), query.clone(), schema, &config);
+
+    serve(&app, &query, &status, &signal, Some(&upgrade_failures))
+}
 
-    let mut server = None; // HTTP REST server
+/// Serves queries, following the daemon's new blocks and mempool, until stopped (or until the
+/// background schema upgrade fails).
+fn serve(
+    app: &App,
+    query: &Arc<Query>,
+    status: &SyncStatus,
+    signal: &Waiter,
+    upgrade_failures: Option<&Receiver<Error>>,
+) -> Result<()> {
     loop {
-        app.update(&signal)?;
+        if let Some(Ok(e)) = upgrade_failures.map(|failures| failures.try_recv()) {
+            return Err(e);
+        }
+        app.update(signal)?;
         query.update_mempool()?;
 
-        if server.is_none() {
-            let info = app.daemon().getblockchaininfo()?;
-            if info.initialblockdownload == false && info.verificationprogress > 0.9999 {
-                server = Some(rest::run_server(&config, query.clone()));
-            } else {
-                warn!("bitcoind not fully synced waiting");
+        // a failing daemon only makes the server unready, queries keep being served
+        match app.daemon().getblockchaininfo() {
+            Ok(info) => {
+                let daemon_synced =
+                    info.initialblockdownload == false && info.verificationprogress > 0.9999;
+                let ready = daemon_synced
+                    && app.index().best_header_hash().be_hex_string() == info.bestblockhash;
+                status.update(|sync| {
+                    sync.indexed_height = Some(app.index().best_height());
+                    sync.daemon_height = Some(info.blocks);
+                    sync.daemon_verification_progress = Some(info.verificationprogress);
+                    sync.daemon_available = true;
+                    sync.mempool_synced = true;
+                    sync.ready = ready;
+                });
+                if ready && status.query().is_none() {
+                    status.set_phase(SyncPhase::Synced);
+                    status.set_query(query.clone());
+                } else if !daemon_synced {
+                    warn!("bitcoind not fully synced waiting");
+                }
+            }
+            Err(e) => {
+                warn!("failed to get blockchain info: {}", e.display_chain());
+                status.update(|sync| {
+                    sync.daemon_available = false;
+                    sync.ready = false;
+                });
             }
         }
 
@@ -82,9 +194,49 @@ fn run_server(config: Config) -> Result<()> {
     Ok(())
 }
 
+/// Serves the DB indexed by another process, reopening it to follow its updates.
+fn run_replica(
+    config: &Config,
+    daemon: Daemon,
+    metrics: &Metrics,
+    status: Arc<SyncStatus>,
+    signal: &Waiter,
+) -> Result<()> {
+    let snapshot = DBSnapshot::open_read_only(&config.db_path)?;
+    let schema = read_schema(&snapshot)?;
+    schema.check(config)?;
+    if needs_upgrade(&snapshot, &schema) {
+        bail!("the DB needs a schema upgrade, let its writer upgrade it first");
+    }
+    if !is_fully_compacted(&snapshot) {
+        bail!("the DB is still being imported, let its writer finish first");
+    }
+    let index = Index::load(&snapshot, &daemon, metrics, config, schema.row_codec())?;
+    status.update(|info| info.fully_compacted = true);
+    status.set_phase(SyncPhase::Catchup);
+
+    let app = App::new_replica(&config.db_path, snapshot, index, daemon)?;
+    let query = Query::new(
+        app.clone(),
+        config.extended_db_enabled && schema.extended_db_enabled,
+        metrics,
+    );
+    serve(&app, &query, &status, signal, None)
+}
+
+fn run_export(config: Config, dir: &Path) -> Result<()> {
+    let store = DBStore::open(&config.db_path, /*low_memory=*/ false);
+    export_archive(&store, &config, dir)?;
+    Ok(())
+}
+
 fn main() {
     let config = Config::from_args();
-    if let Err(e) = run_server(config) {
+    let result = match config.export_archive.clone() {
+        Some(dir) => run_export(config, &dir),
+        None => run_server(config),
+    };
+    if let Err(e) = result {
         error!("server failed: {}", e.display_chain());
         process::exit(1);
     }
@@ -14,8 +14,9 @@ use std::thread;
 
 use config::Config;
 use daemon::Daemon;
-use index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use index::{index_block, last_indexed_block, read_indexed_blockhashes, RowCodec};
 use metrics::{CounterVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use status::SyncStatus;
 use store::{DBStore, Row, WriteStore};
 use util::{spawn_thread, HeaderList, SyncChannel};
 
@@ -30,6 +31,7 @@ struct Parser {
     block_count: CounterVec,
     bytes_read: Histogram,
     extended_db_enabled: bool,
+    codec: RowCodec,
 }
 
 impl Parser {
@@ -38,12 +40,14 @@ impl Parser {
         metrics: &Metrics,
         indexed_blockhashes: HashSet<Sha256dHash>,
         extended_db_enabled: bool,
+        codec: RowCodec,
     ) -> Result<Arc<Parser>> {
         Ok(Arc::new(Parser {
             magic: daemon.magic(),
             current_headers: load_headers(daemon)?,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
             extended_db_enabled,
+            codec,
             duration: metrics.histogram_vec(
                 HistogramOpts::new("parse_duration", "blk*.dat parsing duration (in seconds)"),
                 &["step"],
@@ -97,6 +101,7 @@ impl Parser {
                     .insert(blockhash.clone())
                 {
                     rows.extend(index_block(
+                        self.codec,
                         &block,
                         header.height() as u32,
                         self.extended_db_enabled,
@@ -219,11 +224,17 @@ pub fn index_blk_files(
     daemon: &Daemon,
     config: &Config,
     metrics: &Metrics,
+    status: Arc<SyncStatus>,
     store: DBStore,
+    codec: RowCodec,
 ) -> Result<DBStore> {
     set_open_files_limit(2048); // twice the default `ulimit -n` value
     let blk_files = daemon.list_blk_files()?;
     info!("indexing {} blk*.dat files", blk_files.len());
+    status.update(|info| {
+        info.bulk_files_indexed = 0;
+        info.bulk_files_total = blk_files.len();
+    });
     let indexed_blockhashes = read_indexed_blockhashes(&store);
     debug!("found {} indexed blocks", indexed_blockhashes.len());
     let parser = Parser::new(
@@ -231,6 +242,7 @@ pub fn index_blk_files(
         metrics,
         indexed_blockhashes,
         config.extended_db_enabled,
+        codec,
     )?;
     let (blobs, reader) = start_reader(blk_files, parser.clone());
     let rows_chan = SyncChannel::new(0);
@@ -241,6 +253,7 @@ pub fn index_blk_files(
         for (rows, path) in rows_chan.into_receiver() {
             trace!("indexed {:?}: {} rows", path, rows.len());
             store.write(rows);
+            status.update(|info| info.bulk_files_indexed += 1);
         }
         reader
             .join()
@@ -2,13 +2,16 @@ use bitcoin::network::constants::Network;
 use clap::{App, Arg};
 use dirs::home_dir;
 use num_cpus;
+use std::fmt;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
 use stderrlog;
 
 use daemon::CookieGetter;
+use util::MIN_HASH_PREFIX_LEN;
 
 use errors::*;
 
@@ -22,14 +25,66 @@ pub struct Config {
     pub daemon_rpc_addr: SocketAddr,
     pub cookie: Option<String>,
     pub electrum_rpc_addr: SocketAddr,
-    pub http_addr: SocketAddr,
-    pub monitoring_addr: SocketAddr,
+    pub http_addr: ListenAddr,
+    pub monitoring_addr: ListenAddr,
+    pub unix_socket_mode: u32,
     pub jsonrpc_import: bool,
     pub index_batch_size: usize,
     pub bulk_index_threads: usize,
     pub tx_cache_size: usize,
     pub extended_db_enabled: bool,
+    pub index_prefix_len: Option<usize>,
     pub prevout_enabled: bool,
+    pub xpub_gap_limit: u32,
+    pub rate_limit: u32,
+    pub rate_limit_burst: u32,
+    pub api_keys_file: Option<PathBuf>,
+    pub api_key_rate_multiplier: u32,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub cors_origins: Vec<String>,
+    pub cors_methods: String,
+    pub http_headers: Vec<(String, String)>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub admin_token_file: Option<PathBuf>,
+    pub backup_dir: Option<PathBuf>,
+    pub restore_from: Option<PathBuf>,
+    pub export_archive: Option<PathBuf>,
+    pub import_archive: Option<PathBuf>,
+    pub verify_from: Option<usize>,
+    pub verify_to: Option<usize>,
+    pub verify_repair: bool,
+    pub reindex_from: Option<usize>,
+    pub reindex_to: Option<usize>,
+    pub read_only: bool,
+}
+
+/// Address of a listening socket, either TCP or a Unix domain socket path prefixed by `unix:`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> ::std::result::Result<ListenAddr, AddrParseError> {
+        if s.starts_with("unix:") {
+            Ok(ListenAddr::Unix(PathBuf::from(&s["unix:".len()..])))
+        } else {
+            s.parse().map(ListenAddr::Tcp)
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ListenAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 impl Config {
@@ -80,7 +135,7 @@ impl Config {
             .arg(
                 Arg::with_name("http_addr")
                     .long("http-addr")
-                    .help("HTTP server 'addr:port' to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
+                    .help("HTTP server 'addr:port' or 'unix:/path' to listen on (default: '127.0.0.1:3000' for mainnet, '127.0.0.1:3001' for testnet and '127.0.0.1:3002' for regtest)")
                     .takes_value(true),
             )
             .arg(
@@ -92,9 +147,15 @@ impl Config {
             .arg(
                 Arg::with_name("monitoring_addr")
                     .long("monitoring-addr")
-                    .help("Prometheus monitoring 'addr:port' to listen on (default: 127.0.0.1:4224 for mainnet, 127.0.0.1:14224 for testnet and 127.0.0.1:24224 for regtest)")
+                    .help("Prometheus monitoring 'addr:port' or 'unix:/path' to listen on (default: 127.0.0.1:4224 for mainnet, 127.0.0.1:14224 for testnet and 127.0.0.1:24224 for regtest)")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("unix_socket_mode")
+                    .long("unix-socket-mode")
+                    .help("Permissions (in octal) of the Unix sockets created for 'unix:/path' addresses")
+                    .default_value("660"),
+            )
             .arg(
                 Arg::with_name("jsonrpc_import")
                     .long("jsonrpc-import")
@@ -123,11 +184,162 @@ impl Config {
                     .long("light")
                     .help("Enable light operation mode")
             )
+            .arg(
+                Arg::with_name("index_prefix_len")
+                    .long("index-prefix-len")
+                    .help("Length (in bytes) of the txid and script hash prefixes stored in the index (default: 8). Only used when creating a new database")
+                    .takes_value(true)
+            )
             .arg(
                 Arg::with_name("disable_prevout")
                     .long("disable-prevout")
                     .help("Don't attach previous output details to inputs")
             )
+            .arg(
+                Arg::with_name("xpub_gap_limit")
+                    .long("xpub-gap-limit")
+                    .help("Number of consecutive unused addresses to derive before an xpub scan stops")
+                    .default_value("20")
+            )
+            .arg(
+                Arg::with_name("rate_limit")
+                    .long("rate-limit")
+                    .help("Number of REST request tokens refilled per second for each client (0 disables rate limiting)")
+                    .default_value("0")
+            )
+            .arg(
+                Arg::with_name("rate_limit_burst")
+                    .long("rate-limit-burst")
+                    .help("Number of REST request tokens a client can spend at once")
+                    .default_value("50")
+            )
+            .arg(
+                Arg::with_name("api_keys_file")
+                    .long("api-keys-file")
+                    .help("File with one API key per line, sent by clients in the 'X-API-Key' header for higher rate limits")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("api_key_rate_multiplier")
+                    .long("api-key-rate-multiplier")
+                    .help("Factor applied to the rate limit and burst of clients with a valid API key")
+                    .default_value("10")
+            )
+            .arg(
+                Arg::with_name("trusted_proxy")
+                    .long("trusted-proxy")
+                    .help("Address of a reverse proxy whose 'X-Forwarded-For' header is trusted (may be repeated)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("cors_origin")
+                    .long("cors-origin")
+                    .help("Origin allowed to make cross-origin REST requests, or '*' for any (may be repeated)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("cors_methods")
+                    .long("cors-methods")
+                    .help("Methods allowed in cross-origin REST requests")
+                    .default_value("GET, POST")
+            )
+            .arg(
+                Arg::with_name("http_header")
+                    .long("http-header")
+                    .help("Custom 'Name: value' header added to every REST response (may be repeated)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("tls_cert")
+                    .long("tls-cert")
+                    .help("PEM certificate (chain) used to serve the REST API over HTTPS, reloaded on SIGHUP")
+                    .takes_value(true)
+                    .requires("tls_key")
+            )
+            .arg(
+                Arg::with_name("tls_key")
+                    .long("tls-key")
+                    .help("PKCS#8 PEM private key of the --tls-cert certificate")
+                    .takes_value(true)
+                    .requires("tls_cert")
+            )
+            .arg(
+                Arg::with_name("admin_token_file")
+                    .long("admin-token-file")
+                    .help("File with the token sent in the 'X-Admin-Token' header to use the /admin REST endpoints (disabled otherwise)")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("backup_dir")
+                    .long("backup-dir")
+                    .help("Directory where index backups are created by 'POST /admin/backup'")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("restore_from")
+                    .long("restore-from")
+                    .help("Index backup to restore on startup, when the DB directory is empty")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("export_archive")
+                    .long("export-archive")
+                    .help("Export the index to a portable archive in this directory, and exit")
+                    .takes_value(true)
+                    .conflicts_with_all(&["import_archive", "restore_from"])
+            )
+            .arg(
+                Arg::with_name("import_archive")
+                    .long("import-archive")
+                    .help("Index archive (see --export-archive) to bootstrap from on startup, when the DB directory is empty")
+                    .takes_value(true)
+                    .conflicts_with("restore_from")
+            )
+            .arg(
+                Arg::with_name("verify_from")
+                    .long("verify-from")
+                    .help("Verify the indexed rows of the blocks from this height against the daemon's blocks, and exit")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("verify_to")
+                    .long("verify-to")
+                    .help("Last height to verify (default: the last indexed block)")
+                    .takes_value(true)
+                    .requires("verify_from")
+            )
+            .arg(
+                Arg::with_name("verify_repair")
+                    .long("verify-repair")
+                    .help("Write the missing rows and delete the extra ones found while verifying")
+                    .requires("verify_from")
+            )
+            .arg(
+                Arg::with_name("reindex_from")
+                    .long("reindex-from")
+                    .help("Delete and rebuild the rows of the blocks from this height using the daemon's blocks, and exit")
+                    .takes_value(true)
+                    .conflicts_with("verify_from")
+            )
+            .arg(
+                Arg::with_name("reindex_to")
+                    .long("reindex-to")
+                    .help("Last height to reindex (default: the last indexed block)")
+                    .takes_value(true)
+                    .requires("reindex_from")
+            )
+            .arg(
+                Arg::with_name("read_only")
+                    .long("read-only")
+                    .help("Serve the REST API from the DB indexed by another electrs process (using the same --db-dir), without writing to it")
+                    .conflicts_with_all(&["export_archive", "import_archive", "restore_from", "verify_from", "reindex_from"])
+            )
             .get_matches();
 
         let network_name = m.value_of("network").unwrap_or("mainnet");
@@ -171,16 +383,29 @@ impl Config {
             .unwrap_or(&format!("127.0.0.1:{}", default_electrum_port))
             .parse()
             .expect("invalid Electrum RPC address");
-        let http_addr: SocketAddr = m
+        let http_addr: ListenAddr = m
             .value_of("http_addr")
             .unwrap_or(&format!("127.0.0.1:{}", default_http_port))
             .parse()
             .expect("invalid HTTP server address");
-        let monitoring_addr: SocketAddr = m
+        let monitoring_addr: ListenAddr = m
             .value_of("monitoring_addr")
             .unwrap_or(&format!("127.0.0.1:{}", default_monitoring_port))
             .parse()
             .expect("invalid Prometheus monitoring address");
+        let unix_socket_mode = u32::from_str_radix(m.value_of("unix_socket_mode").unwrap(), 8)
+            .expect("invalid Unix socket mode");
+
+        let index_prefix_len = m.value_of("index_prefix_len").map(|len| {
+            let len: usize = len.parse().expect("invalid index prefix length");
+            if len < MIN_HASH_PREFIX_LEN || len > 32 {
+                panic!(
+                    "index prefix length must be between {} and 32 bytes",
+                    MIN_HASH_PREFIX_LEN
+                );
+            }
+            len
+        });
 
         let mut daemon_dir = m
             .value_of("daemon_dir")
@@ -196,6 +421,28 @@ impl Config {
             Network::Regtest => daemon_dir.push("regtest"),
         }
         let cookie = m.value_of("cookie").map(|s| s.to_owned());
+        let trusted_proxies: Vec<IpAddr> = if m.is_present("trusted_proxy") {
+            values_t_or_exit!(m, "trusted_proxy", IpAddr)
+        } else {
+            vec![]
+        };
+
+        let cors_origins: Vec<String> = m.values_of("cors_origin").map_or(vec![], |values| {
+            values.map(|value| value.to_string()).collect()
+        });
+        let http_headers: Vec<(String, String)> =
+            m.values_of("http_header").map_or(vec![], |values| {
+                values
+                    .map(|value| {
+                        let mut parts = value.splitn(2, ':');
+                        let name = parts.next().unwrap().trim().to_string();
+                        let value = parts
+                            .next()
+                            .expect("invalid HTTP header, expected 'Name: value'");
+                        (name, value.trim().to_string())
+                    })
+                    .collect()
+            });
 
         let mut log = stderrlog::new();
         log.verbosity(m.occurrences_of("verbosity") as usize);
@@ -219,12 +466,44 @@ impl Config {
             electrum_rpc_addr,
             http_addr,
             monitoring_addr,
+            unix_socket_mode,
             jsonrpc_import: m.is_present("jsonrpc_import"),
             index_batch_size: value_t_or_exit!(m, "index_batch_size", usize),
             bulk_index_threads,
             tx_cache_size: value_t_or_exit!(m, "tx_cache_size", usize),
             extended_db_enabled: !m.is_present("light"),
+            index_prefix_len,
             prevout_enabled: !m.is_present("disable_prevout"),
+            xpub_gap_limit: value_t_or_exit!(m, "xpub_gap_limit", u32),
+            rate_limit: value_t_or_exit!(m, "rate_limit", u32),
+            rate_limit_burst: value_t_or_exit!(m, "rate_limit_burst", u32),
+            api_keys_file: m.value_of("api_keys_file").map(PathBuf::from),
+            api_key_rate_multiplier: value_t_or_exit!(m, "api_key_rate_multiplier", u32),
+            trusted_proxies,
+            cors_origins,
+            cors_methods: m.value_of("cors_methods").unwrap().to_string(),
+            http_headers,
+            tls_cert: m.value_of("tls_cert").map(PathBuf::from),
+            tls_key: m.value_of("tls_key").map(PathBuf::from),
+            admin_token_file: m.value_of("admin_token_file").map(PathBuf::from),
+            backup_dir: m.value_of("backup_dir").map(PathBuf::from),
+            restore_from: m.value_of("restore_from").map(PathBuf::from),
+            export_archive: m.value_of("export_archive").map(PathBuf::from),
+            import_archive: m.value_of("import_archive").map(PathBuf::from),
+            verify_from: m
+                .value_of("verify_from")
+                .map(|_| value_t_or_exit!(m, "verify_from", usize)),
+            verify_to: m
+                .value_of("verify_to")
+                .map(|_| value_t_or_exit!(m, "verify_to", usize)),
+            verify_repair: m.is_present("verify_repair"),
+            reindex_from: m
+                .value_of("reindex_from")
+                .map(|_| value_t_or_exit!(m, "reindex_from", usize)),
+            reindex_to: m
+                .value_of("reindex_to")
+                .map(|_| value_t_or_exit!(m, "reindex_to", usize)),
+            read_only: m.is_present("read_only"),
         };
         eprintln!("{:?}", config);
         config
@@ -266,3 +545,24 @@ impl CookieGetter for CookieFile {
         Ok(contents)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use config::ListenAddr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        assert_eq!(
+            "127.0.0.1:3000".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("127.0.0.1:3000".parse().unwrap())
+        );
+        let unix = "unix:/run/electrs/http.sock".parse::<ListenAddr>().unwrap();
+        assert_eq!(
+            unix,
+            ListenAddr::Unix(PathBuf::from("/run/electrs/http.sock"))
+        );
+        assert_eq!(unix.to_string(), "unix:/run/electrs/http.sock");
+        assert!("localhost".parse::<ListenAddr>().is_err());
+    }
+}
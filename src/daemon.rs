@@ -66,7 +66,10 @@ fn parse_jsonrpc_reply(mut reply: Value, method: &str, expected_id: u64) -> Resu
                     match code {
                         // RPC_IN_WARMUP -> retry by later reconnection
                         -28 => bail!(ErrorKind::Connection(err.to_string())),
-                        _ => (),
+                        _ => {
+                            let message = err["message"].as_str().unwrap_or("").to_string();
+                            bail!(ErrorKind::RpcError(method.to_string(), code, message))
+                        }
                     }
                 }
                 bail!("{} RPC error: {}", method, err);
@@ -109,6 +112,7 @@ struct NetworkInfo {
     subversion: String,
 }
 
+#[derive(Clone)]
 pub struct MempoolEntry {
     fee: u64,   // in satoshis
     vsize: u32, // in virtual bytes (= weight/4)
@@ -1,3 +1,4 @@
+use bitcoin::util::hash::Sha256dHash;
 use chan_signal::Signal;
 
 error_chain! {
@@ -15,5 +16,30 @@ error_chain! {
             description("Interruption by external signal")
             display("Iterrupted by SIG{:?}", signal)
         }
+
+        RpcError(method: String, code: i64, message: String) {
+            description("Daemon RPC error")
+            display("{} RPC error {}: {}", method, code, message)
+        }
+
+        TxNotFound(txid: Sha256dHash) {
+            description("Transaction not found")
+            display("Transaction {} not found", txid)
+        }
+
+        BlockNotFound(blockhash: Sha256dHash) {
+            description("Block not found")
+            display("Block {} not found", blockhash)
+        }
+
+        TooManyTxs(limit: usize) {
+            description("Too many txs")
+            display("Too many txs (limit is {})", limit)
+        }
+
+        BackupInProgress {
+            description("Backup in progress")
+            display("Another backup is still in progress")
+        }
     }
 }
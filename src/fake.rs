@@ -1,4 +1,8 @@
-use store::{ReadStore, Row, WriteStore};
+use std::collections::BTreeMap;
+use std::iter;
+use std::sync::RwLock;
+
+use store::{Direction, ReadStore, Row, ScanRange, WriteStore};
 use util::Bytes;
 
 pub struct FakeStore;
@@ -7,13 +11,71 @@ impl ReadStore for FakeStore {
     fn get(&self, _key: &[u8]) -> Option<Bytes> {
         None
     }
-    fn scan(&self, _prefix: &[u8]) -> Vec<Row> {
-        vec![]
+    fn iter_range<'a>(&'a self, _range: ScanRange) -> Box<Iterator<Item = Row> + 'a> {
+        Box::new(iter::empty())
     }
 }
 
 impl WriteStore for FakeStore {
     fn write(&self, _rows: Vec<Row>) {}
+    fn delete(&self, _keys: Vec<Bytes>) {}
+    fn flush(&self) {}
+}
+
+/// Keeps the rows in memory, e.g. to test code reading and writing the index.
+#[derive(Default)]
+pub struct MemStore {
+    rows: RwLock<BTreeMap<Bytes, Bytes>>,
+}
+
+impl MemStore {
+    pub fn new(rows: BTreeMap<Bytes, Bytes>) -> MemStore {
+        MemStore {
+            rows: RwLock::new(rows),
+        }
+    }
+
+    /// A copy of all the rows.
+    pub fn rows(&self) -> BTreeMap<Bytes, Bytes> {
+        self.rows.read().unwrap().clone()
+    }
+}
+
+impl ReadStore for MemStore {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.rows.read().unwrap().get(key).cloned()
+    }
+    fn iter_range<'a>(&'a self, range: ScanRange) -> Box<Iterator<Item = Row> + 'a> {
+        let to_row = |(key, value): (&Bytes, &Bytes)| Row {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        let map = self.rows.read().unwrap();
+        let rows: Vec<Row> = match (range.direction(), range.upper_bound()) {
+            (Direction::Forward, _) => map
+                .range(range.lower_bound().to_vec()..)
+                .map(to_row)
+                .collect(),
+            (Direction::Reverse, Some(upper)) => {
+                map.range(..upper.to_vec()).rev().map(to_row).collect()
+            }
+            (Direction::Reverse, None) => map.iter().rev().map(to_row).collect(),
+        };
+        range.clip(rows.into_iter())
+    }
+}
+
+impl WriteStore for MemStore {
+    fn write(&self, rows: Vec<Row>) {
+        let mut map = self.rows.write().unwrap();
+        map.extend(rows.into_iter().map(Row::into_pair));
+    }
+    fn delete(&self, keys: Vec<Bytes>) {
+        let mut map = self.rows.write().unwrap();
+        for key in keys {
+            map.remove(&key);
+        }
+    }
     fn flush(&self) {}
 }
 
@@ -33,5 +95,41 @@ mod tests {
         // nothing was actually written
         assert!(store.get(b"").is_none());
         assert!(store.scan(b"").is_empty());
+        assert!(store.iter_scan(b"k").next().is_none());
+    }
+
+    #[test]
+    fn test_memstore() {
+        use fake::MemStore;
+        use store::{ReadStore, Row, ScanRange, WriteStore};
+
+        let store = MemStore::default();
+        let row = |key: &[u8]| Row {
+            key: key.to_vec(),
+            value: vec![],
+        };
+        store.write(vec![
+            row(b"A"),
+            row(b"B1"),
+            row(b"B2"),
+            row(b"B3"),
+            row(b"C"),
+        ]);
+        store.delete(vec![b"B2".to_vec()]);
+        let keys = |range| -> Vec<Vec<u8>> { store.iter_range(range).map(|row| row.key).collect() };
+        assert_eq!(
+            keys(ScanRange::prefix(b"B")),
+            vec![b"B1".to_vec(), b"B3".to_vec()]
+        );
+        assert_eq!(
+            keys(ScanRange::prefix(b"B").reverse()),
+            vec![b"B3".to_vec(), b"B1".to_vec()]
+        );
+        assert_eq!(
+            keys(ScanRange::prefix(b"B").end(b"B3").reverse()),
+            vec![b"B1".to_vec()]
+        );
+        assert!(store.get(b"B2").is_none());
+        assert_eq!(store.rows().len(), 4);
     }
 }
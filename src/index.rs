@@ -2,113 +2,170 @@ use bincode;
 use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
 use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::network::constants::Network;
 use bitcoin::util::hash::BitcoinHash;
 use bitcoin::util::hash::Sha256dHash;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
+use hex;
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::sync::RwLock;
 
 use daemon::Daemon;
 use metrics::{Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
+use migration::STAGING_PREFIX;
 use signal::Waiter;
 use store::{ReadStore, Row, WriteStore};
 use util::{
-    full_hash, hash_prefix, spawn_thread, BlockMeta, Bytes, FullHash, HashPrefix, HeaderEntry,
-    HeaderList, HeaderMap, SyncChannel, HASH_PREFIX_LEN,
+    full_hash, read_varint, script_to_address, spawn_thread, write_varint, BlockMeta, Bytes,
+    FullHash, HashPrefix, HeaderEntry, HeaderList, HeaderMap, SyncChannel, HASH_LEN,
 };
 
 use config::Config;
 
 use errors::*;
 
-#[derive(Serialize, Deserialize)]
-pub struct TxInKey {
-    pub code: u8,
-    pub prev_hash_prefix: HashPrefix,
-    pub prev_index: u16,
+/// How the `I` and `O` rows truncate the hashes they are keyed by, and which network the `A`
+/// rows encode addresses for. Both are properties of the DB (see `migration::Schema`), so the
+/// codec is built from its schema.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RowCodec {
+    hash_prefix_len: usize,
+    network: Network,
 }
 
-#[derive(Serialize, Deserialize)]
+impl RowCodec {
+    pub fn new(hash_prefix_len: usize, network: Network) -> RowCodec {
+        assert!(hash_prefix_len > 0 && hash_prefix_len <= HASH_LEN);
+        RowCodec {
+            hash_prefix_len,
+            network,
+        }
+    }
+
+    pub fn hash_prefix_len(&self) -> usize {
+        self.hash_prefix_len
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn hash_prefix(&self, hash: &[u8]) -> HashPrefix {
+        HashPrefix::new(&hash[..self.hash_prefix_len])
+    }
+}
+
+/// `I` rows map a spent output to the transactions spending it, keyed by
+/// `b'I' || prev_txid prefix || varint(prev_index) || txid prefix`.
 pub struct TxInRow {
-    key: TxInKey,
+    pub prev_hash_prefix: HashPrefix,
+    pub prev_index: u32,
     pub txid_prefix: HashPrefix,
 }
 
 impl TxInRow {
-    pub fn new(txid: &Sha256dHash, input: &TxIn) -> TxInRow {
+    pub fn new(codec: RowCodec, txid: &Sha256dHash, input: &TxIn) -> TxInRow {
         TxInRow {
-            key: TxInKey {
-                code: b'I',
-                prev_hash_prefix: hash_prefix(&input.previous_output.txid.as_bytes()[..]),
-                prev_index: input.previous_output.vout as u16,
-            },
-            txid_prefix: hash_prefix(&txid[..]),
+            prev_hash_prefix: codec.hash_prefix(&input.previous_output.txid.as_bytes()[..]),
+            prev_index: input.previous_output.vout,
+            txid_prefix: codec.hash_prefix(&txid[..]),
         }
     }
 
-    pub fn filter(txid: &Sha256dHash, output_index: usize) -> Bytes {
-        bincode::serialize(&TxInKey {
-            code: b'I',
-            prev_hash_prefix: hash_prefix(&txid[..]),
-            prev_index: output_index as u16,
-        })
-        .unwrap()
+    fn key_prefix(prev_hash_prefix: &[u8], prev_index: u32) -> Bytes {
+        let mut key = Vec::with_capacity(1 + 2 * prev_hash_prefix.len() + 5);
+        key.push(b'I');
+        key.extend_from_slice(prev_hash_prefix);
+        write_varint(&mut key, prev_index);
+        key
+    }
+
+    pub fn filter(codec: RowCodec, txid: &Sha256dHash, output_index: usize) -> Bytes {
+        TxInRow::key_prefix(&codec.hash_prefix(&txid[..]), output_index as u32)
     }
 
     pub fn to_row(&self) -> Row {
-        Row {
-            key: bincode::serialize(&self).unwrap(),
-            value: vec![],
+        let mut key = TxInRow::key_prefix(&self.prev_hash_prefix, self.prev_index);
+        key.extend_from_slice(&self.txid_prefix);
+        Row { key, value: vec![] }
+    }
+
+    pub fn from_row(codec: RowCodec, row: &Row) -> Result<TxInRow> {
+        let len = codec.hash_prefix_len();
+        let key = &row.key;
+        if key.len() < 1 + 2 * len + 1 || key[0] != b'I' {
+            bail!("invalid TxInRow key {}", hex::encode(key));
+        }
+        let (prev_index, varint_len) =
+            read_varint(&key[1 + len..]).chain_err(|| "invalid TxInRow output index")?;
+        if key.len() != 1 + len + varint_len + len {
+            bail!("invalid TxInRow key {}", hex::encode(key));
         }
+        Ok(TxInRow {
+            prev_hash_prefix: HashPrefix::new(&key[1..1 + len]),
+            prev_index,
+            txid_prefix: HashPrefix::new(&key[1 + len + varint_len..]),
+        })
     }
 
-    pub fn from_row(row: &Row) -> TxInRow {
-        bincode::deserialize(&row.key).expect("failed to parse TxInRow")
+    /// The `DB_VERSION` 1 key prefix of the rows spending an output.
+    pub fn filter_v1(txid: &Sha256dHash, output_index: usize) -> Bytes {
+        let index = output_index as u16;
+        [b"I", &txid[..8], &[index as u8, (index >> 8) as u8]].concat()
     }
-}
 
-#[derive(Serialize, Deserialize)]
-pub struct TxOutKey {
-    code: u8,
-    script_hash_prefix: HashPrefix,
+    /// Parses a row written by `DB_VERSION` 1, which bincode-serialized the key with 8 byte
+    /// prefixes and a u16 `prev_index` (truncating larger output indexes).
+    pub fn from_v1_row(row: &Row) -> Option<TxInRow> {
+        if row.key.len() != 19 || row.key[0] != b'I' {
+            return None;
+        }
+        Some(TxInRow {
+            prev_hash_prefix: HashPrefix::new(&row.key[1..9]),
+            prev_index: u32::from(row.key[9]) | u32::from(row.key[10]) << 8,
+            txid_prefix: HashPrefix::new(&row.key[11..19]),
+        })
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// `O` rows map a script hash to the transactions funding it, keyed by
+/// `b'O' || script hash prefix || txid prefix`.
 pub struct TxOutRow {
-    key: TxOutKey,
+    script_hash_prefix: HashPrefix,
     pub txid_prefix: HashPrefix,
 }
 
 impl TxOutRow {
-    pub fn new(txid: &Sha256dHash, output: &TxOut) -> TxOutRow {
+    pub fn new(codec: RowCodec, txid: &Sha256dHash, output: &TxOut) -> TxOutRow {
         TxOutRow {
-            key: TxOutKey {
-                code: b'O',
-                script_hash_prefix: hash_prefix(&compute_script_hash(&output.script_pubkey[..])),
-            },
-            txid_prefix: hash_prefix(&txid[..]),
+            script_hash_prefix: codec.hash_prefix(&compute_script_hash(&output.script_pubkey[..])),
+            txid_prefix: codec.hash_prefix(&txid[..]),
         }
     }
 
-    pub fn filter(script_hash: &[u8]) -> Bytes {
-        bincode::serialize(&TxOutKey {
-            code: b'O',
-            script_hash_prefix: hash_prefix(&script_hash[..HASH_PREFIX_LEN]),
-        })
-        .unwrap()
+    pub fn filter(codec: RowCodec, script_hash: &[u8]) -> Bytes {
+        [b"O", &codec.hash_prefix(script_hash)[..]].concat()
     }
 
     pub fn to_row(&self) -> Row {
         Row {
-            key: bincode::serialize(&self).unwrap(),
+            key: [b"O", &self.script_hash_prefix[..], &self.txid_prefix[..]].concat(),
             value: vec![],
         }
     }
 
-    pub fn from_row(row: &Row) -> TxOutRow {
-        bincode::deserialize(&row.key).expect("failed to parse TxOutRow")
+    pub fn from_row(codec: RowCodec, row: &Row) -> Result<TxOutRow> {
+        let len = codec.hash_prefix_len();
+        if row.key.len() != 1 + 2 * len || row.key[0] != b'O' {
+            bail!("invalid TxOutRow key {}", hex::encode(&row.key));
+        }
+        Ok(TxOutRow {
+            script_hash_prefix: HashPrefix::new(&row.key[1..1 + len]),
+            txid_prefix: HashPrefix::new(&row.key[1 + len..]),
+        })
     }
 }
 
@@ -202,6 +259,37 @@ impl RawTxRow {
     }
 }
 
+/// `S` rows index the confirmed txids for prefix searches, keyed by `b'S' || txid` in display
+/// (reversed) byte order, since the `T` rows' internal byte order doesn't match hex prefixes.
+pub fn txid_search_key(txid: &Sha256dHash) -> Bytes {
+    Some(b'S')
+        .into_iter()
+        .chain(txid[..].iter().rev().cloned())
+        .collect()
+}
+
+/// `A` rows index the addresses paid by confirmed transactions for prefix searches, keyed by
+/// `b'A' || address`.
+pub fn address_search_key(address: &str) -> Bytes {
+    [b"A", address.as_bytes()].concat()
+}
+
+pub fn index_search_rows(codec: RowCodec, txn: &Transaction, rows: &mut Vec<Row>) {
+    let search_row = |key| Row { key, value: vec![] };
+    rows.push(search_row(txid_search_key(&txn.txid())));
+    let network = codec.network();
+    let addresses: HashSet<String> = txn
+        .output
+        .iter()
+        .filter_map(|output| script_to_address(&output.script_pubkey, &network))
+        .collect();
+    rows.extend(
+        addresses
+            .iter()
+            .map(|address| search_row(address_search_key(address))),
+    );
+}
+
 #[derive(Serialize, Deserialize)]
 struct BlockKey {
     code: u8,
@@ -217,6 +305,7 @@ pub fn compute_script_hash(data: &[u8]) -> FullHash {
 }
 
 pub fn index_transaction(
+    codec: RowCodec,
     txn: &Transaction,
     height: u32,
     blockhash: &Sha256dHash,
@@ -228,27 +317,26 @@ pub fn index_transaction(
         if input.previous_output.txid == null_hash {
             continue;
         }
-        rows.push(TxInRow::new(&txid, &input).to_row());
+        rows.push(TxInRow::new(codec, &txid, &input).to_row());
     }
     for output in &txn.output {
-        rows.push(TxOutRow::new(&txid, &output).to_row());
+        rows.push(TxOutRow::new(codec, &txid, &output).to_row());
     }
     // Persist transaction ID and confirmed height/hash
     rows.push(TxRow::new(&txid, height, blockhash).to_row());
 }
 
-pub fn index_block(block: &Block, height: u32, extended_db_enabled: bool) -> Vec<Row> {
+pub fn index_block(
+    codec: RowCodec,
+    block: &Block,
+    height: u32,
+    extended_db_enabled: bool,
+) -> Vec<Row> {
     let blockhash = block.bitcoin_hash();
     let mut rows = vec![];
     for txn in &block.txdata {
-        index_transaction(&txn, height, &blockhash, &mut rows);
-
-        // Persist raw transaction to txstore
-        if extended_db_enabled {
-            rows.push(RawTxRow::new(&txn.txid(), serialize(txn)).to_row()); // @TODO avoid re-serialization
-        }
+        index_transaction(codec, &txn, height, &blockhash, &mut rows);
     }
-    let blockhash = block.bitcoin_hash();
     // Persist block hash and header
     rows.push(Row {
         key: bincode::serialize(&BlockKey {
@@ -259,32 +347,45 @@ pub fn index_block(block: &Block, height: u32, extended_db_enabled: bool) -> Vec
         value: serialize(&block.header),
     });
 
-    // Persist block metadata (size, number of txs and sum of txs weight)
     if extended_db_enabled {
-        let blockmeta = BlockMeta::from(block);
-        rows.push(Row {
-            key: bincode::serialize(&BlockKey {
-                code: b'M',
-                hash: full_hash(&blockhash[..]),
-            })
-            .unwrap(),
-            value: bincode::serialize(&blockmeta).unwrap(),
-        });
+        rows.extend(index_block_extended(codec, block));
     }
+    rows
+}
 
-    // Persist list of txids in block
-    if extended_db_enabled {
-        let txids: Vec<Sha256dHash> = block.txdata.iter().map(|tx| tx.txid()).collect();
-        rows.push(Row {
-            key: bincode::serialize(&BlockKey {
-                code: b'X',
-                hash: full_hash(&blockhash[..]),
-            })
-            .unwrap(),
-            value: bincode::serialize(&txids).unwrap(),
-        });
+/// The rows that are only kept in full (non `--light`) mode.
+pub fn index_block_extended(codec: RowCodec, block: &Block) -> Vec<Row> {
+    let blockhash = block.bitcoin_hash();
+    let mut rows = vec![];
+
+    // Persist raw transactions to txstore, and their txids and addresses for searches
+    for txn in &block.txdata {
+        rows.push(RawTxRow::new(&txn.txid(), serialize(txn)).to_row()); // @TODO avoid re-serialization
+        index_search_rows(codec, txn, &mut rows);
     }
 
+    // Persist block metadata (size, number of txs and sum of txs weight)
+    let blockmeta = BlockMeta::from(block);
+    rows.push(Row {
+        key: bincode::serialize(&BlockKey {
+            code: b'M',
+            hash: full_hash(&blockhash[..]),
+        })
+        .unwrap(),
+        value: bincode::serialize(&blockmeta).unwrap(),
+    });
+
+    // Persist list of txids in block
+    let txids: Vec<Sha256dHash> = block.txdata.iter().map(|tx| tx.txid()).collect();
+    rows.push(Row {
+        key: bincode::serialize(&BlockKey {
+            code: b'X',
+            hash: full_hash(&blockhash[..]),
+        })
+        .unwrap(),
+        value: bincode::serialize(&txids).unwrap(),
+    });
+
     rows
 }
 
@@ -298,21 +399,28 @@ pub fn last_indexed_block(blockhash: &Sha256dHash) -> Row {
 
 pub fn read_indexed_blockhashes(store: &ReadStore) -> HashSet<Sha256dHash> {
     let mut result = HashSet::new();
-    for row in store.scan(b"B") {
+    for row in store.iter_scan(b"B") {
         let key: BlockKey = bincode::deserialize(&row.key).unwrap();
         result.insert(deserialize(&key.hash).unwrap());
     }
     result
 }
 
-fn read_indexed_headers(store: &ReadStore) -> HeaderList {
+/// Parses a `B` row, without checking that its key is the hash of its header.
+pub fn parse_header_row(row: &Row) -> Result<(Sha256dHash, BlockHeader)> {
+    let key: BlockKey = bincode::deserialize(&row.key).chain_err(|| "invalid header key")?;
+    let header: BlockHeader = deserialize(&row.value).chain_err(|| "invalid header")?;
+    Ok((deserialize(&key.hash).unwrap(), header))
+}
+
+pub fn read_indexed_headers(store: &ReadStore) -> HeaderList {
     let latest_blockhash: Sha256dHash = match store.get(b"L") {
         // latest blockheader persisted in the DB.
         Some(row) => deserialize(&row).unwrap(),
         None => Sha256dHash::default(),
     };
     let mut map = HeaderMap::new();
-    for row in store.scan(b"B") {
+    for row in store.iter_scan(b"B") {
         let key: BlockKey = bincode::deserialize(&row.key).unwrap();
         let header: BlockHeader = deserialize(&row.value).unwrap();
         map.insert(deserialize(&key.hash).unwrap(), header);
@@ -394,6 +502,9 @@ pub struct Index {
     stats: Stats,
     batch_size: usize,
     extended_db_enabled: bool,
+    codec: RowCodec,
+    // rows written under `STAGING_PREFIX` while a schema upgrade converts them
+    staged_codes: RwLock<Vec<u8>>,
 }
 
 impl Index {
@@ -402,6 +513,7 @@ impl Index {
         daemon: &Daemon,
         metrics: &Metrics,
         config: &Config,
+        codec: RowCodec,
     ) -> Result<Index> {
         let stats = Stats::new(metrics);
         let headers = read_indexed_headers(store);
@@ -412,9 +524,22 @@ impl Index {
             stats,
             batch_size: config.index_batch_size,
             extended_db_enabled: config.extended_db_enabled,
+            codec,
+            staged_codes: RwLock::new(vec![]),
         })
     }
 
+    pub fn codec(&self) -> RowCodec {
+        self.codec
+    }
+
+    /// Makes the new rows starting with one of `codes` be written under `STAGING_PREFIX`, so
+    /// that a schema upgrade running in the background doesn't mistake them for old rows.
+    /// Returns once no batch is being written with the previous codes.
+    pub fn set_staged_codes(&self, codes: Vec<u8>) {
+        *self.staged_codes.write().unwrap() = codes;
+    }
+
     pub fn reload(&self, store: &ReadStore) {
         let mut headers = self.headers.write().unwrap();
         *headers = read_indexed_headers(store);
@@ -441,6 +566,14 @@ impl Index {
             .cloned()
     }
 
+    /// Returns up to `limit` best chain headers, starting at `height`.
+    pub fn get_headers(&self, height: usize, limit: usize) -> Vec<HeaderEntry> {
+        let headers = self.headers.read().unwrap();
+        (height..cmp::min(height + limit, headers.len()))
+            .filter_map(|height| headers.header_by_height(height).cloned())
+            .collect()
+    }
+
     pub fn get_header_by_hash(&self, hash: &Sha256dHash) -> Option<HeaderEntry> {
         self.headers
             .read()
@@ -449,6 +582,17 @@ impl Index {
             .cloned()
     }
 
+    /// Returns up to `limit` best chain headers whose hex blockhash starts with `prefix`.
+    pub fn find_headers_by_prefix(&self, prefix: &str, limit: usize) -> Vec<HeaderEntry> {
+        self.headers
+            .read()
+            .unwrap()
+            .headers_by_hex_prefix(prefix, limit)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
     pub fn update(&self, store: &WriteStore, waiter: &Waiter) -> Result<Sha256dHash> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
@@ -489,6 +633,8 @@ impl Index {
                 break;
             }
 
+            // held until the batch is written, see `set_staged_codes()`
+            let staged_codes = self.staged_codes.read().unwrap();
             let mut rows = vec![];
             for block in &batch {
                 let blockhash = block.bitcoin_hash();
@@ -497,12 +643,18 @@ impl Index {
                     .expect(&format!("missing header for block {}", blockhash));
 
                 let timer = self.stats.start_timer("index");
-                let mut block_rows = index_block(block, height as u32, self.extended_db_enabled);
+                let mut block_rows =
+                    index_block(self.codec, block, height as u32, self.extended_db_enabled);
                 block_rows.push(last_indexed_block(&blockhash));
                 rows.extend(block_rows);
                 timer.observe_duration();
                 self.stats.update(block, height);
             }
+            for row in rows.iter_mut() {
+                if staged_codes.contains(&row.key[0]) {
+                    row.key = [STAGING_PREFIX, &row.key[..]].concat();
+                }
+            }
             let timer = self.stats.start_timer("write");
             store.write(rows);
             timer.observe_duration();
@@ -517,3 +669,83 @@ impl Index {
         Ok(tip)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, TxIn};
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::Sha256dHash;
+
+    use index::{RowCodec, TxInRow, TxOutRow};
+    use store::Row;
+
+    #[test]
+    fn test_txin_row() {
+        let txid = Sha256dHash::from_data(b"spending");
+        let prev_txid = Sha256dHash::from_data(b"funding");
+        let input = TxIn {
+            previous_output: OutPoint {
+                txid: prev_txid,
+                vout: 70000, // used to be truncated to a u16
+            },
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        };
+        let codec = RowCodec::new(8, Network::Regtest);
+        let row = TxInRow::new(codec, &txid, &input).to_row();
+        assert!(row
+            .key
+            .starts_with(&TxInRow::filter(codec, &prev_txid, 70000)));
+        assert!(!row
+            .key
+            .starts_with(&TxInRow::filter(codec, &prev_txid, 70000 & 0xffff)));
+
+        let parsed = TxInRow::from_row(codec, &row).unwrap();
+        assert_eq!(parsed.prev_index, 70000);
+        assert_eq!(&parsed.prev_hash_prefix[..], &prev_txid[..8]);
+        assert_eq!(&parsed.txid_prefix[..], &txid[..8]);
+
+        // rows of another prefix length (or truncated ones) are rejected
+        assert!(TxInRow::from_row(RowCodec::new(4, Network::Regtest), &row).is_err());
+        let truncated = Row {
+            key: row.key[..row.key.len() - 1].to_vec(),
+            value: vec![],
+        };
+        assert!(TxInRow::from_row(codec, &truncated).is_err());
+    }
+
+    #[test]
+    fn test_txout_row() {
+        let codec = RowCodec::new(4, Network::Regtest);
+        let row = Row {
+            key: [&b"O"[..], &[1; 4], &[2; 4]].concat(),
+            value: vec![],
+        };
+        let parsed = TxOutRow::from_row(codec, &row).unwrap();
+        assert_eq!(&parsed.txid_prefix[..], &[2; 4]);
+        assert_eq!(parsed.to_row().key, row.key);
+        assert!(TxOutRow::from_row(RowCodec::new(8, Network::Regtest), &row).is_err());
+    }
+
+    #[test]
+    fn test_txin_row_from_v1() {
+        // bincode-serialized TxInKey { code, prev_hash_prefix, prev_index: u16 } + txid_prefix
+        let key = [&b"I"[..], &[1; 8], &[0x80, 0x01], &[2; 8]].concat();
+        let parsed = TxInRow::from_v1_row(&Row { key, value: vec![] }).unwrap();
+        assert_eq!(&parsed.prev_hash_prefix[..], &[1; 8]);
+        assert_eq!(parsed.prev_index, 384);
+        assert_eq!(&parsed.txid_prefix[..], &[2; 8]);
+        // converted keys use a varint for the index
+        assert_eq!(
+            parsed.to_row().key,
+            [&b"I"[..], &[1; 8], &[0x80, 0x03], &[2; 8]].concat()
+        );
+        assert!(TxInRow::from_v1_row(&Row {
+            key: b"O".to_vec(),
+            value: vec![]
+        })
+        .is_none());
+    }
+}
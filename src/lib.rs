@@ -3,26 +3,36 @@
 extern crate base64;
 extern crate bincode;
 extern crate bitcoin;
+extern crate brotli;
 extern crate chan_signal;
 extern crate crypto;
 extern crate dirs;
+extern crate flate2;
+extern crate futures;
 extern crate glob;
 extern crate hex;
 extern crate hyper;
 extern crate libc;
+extern crate librocksdb_sys;
 extern crate lru;
 extern crate lru_cache;
+extern crate native_tls;
 extern crate num_cpus;
 extern crate page_size;
 extern crate prometheus;
+extern crate rmp_serde;
 extern crate rocksdb;
 extern crate secp256k1;
 extern crate serde;
+extern crate serde_cbor;
 extern crate stderrlog;
 extern crate syscoin_bech32;
 extern crate sysconf;
 extern crate time;
 extern crate tiny_http;
+extern crate tokio;
+extern crate tokio_tls;
+extern crate tokio_uds;
 extern crate url;
 
 #[macro_use]
@@ -41,6 +51,8 @@ extern crate serde_derive;
 extern crate serde_json;
 
 pub mod app;
+pub mod archive;
+pub mod backup;
 pub mod bulk;
 pub mod config;
 pub mod daemon;
@@ -49,8 +61,14 @@ pub mod fake;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod migration;
 pub mod query;
+pub mod ratelimit;
+pub mod replica;
 pub mod rest;
 pub mod signal;
+pub mod status;
 pub mod store;
 pub mod util;
+pub mod verify;
+pub mod xpub;
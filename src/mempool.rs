@@ -4,33 +4,42 @@ use hex;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::FromIterator;
 use std::ops::Bound;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use daemon::{Daemon, MempoolEntry};
-use index::index_transaction;
+use index::{index_transaction, RowCodec};
 use metrics::{Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
-use store::{ReadStore, Row};
-use util::Bytes;
+use store::{Direction, ReadStore, Row, ScanRange};
+use util::{hash_has_hex_prefix, Bytes};
 
 use errors::*;
 
 const VSIZE_BIN_WIDTH: u32 = 100_000; // in vbytes
 pub const MEMPOOL_HEIGHT: u32 = u32::max_value(); // special "marker" for mempool transactions
 
+#[derive(Clone)]
 struct MempoolStore {
     map: BTreeMap<Bytes, Vec<Bytes>>,
+    codec: RowCodec,
 }
 
 impl MempoolStore {
-    fn new() -> MempoolStore {
+    fn new(codec: RowCodec) -> MempoolStore {
         MempoolStore {
             map: BTreeMap::new(),
+            codec,
         }
     }
 
     fn add(&mut self, tx: &Transaction) {
         let mut rows = vec![];
-        index_transaction(tx, MEMPOOL_HEIGHT, &Sha256dHash::default(), &mut rows);
+        index_transaction(
+            self.codec,
+            tx,
+            MEMPOOL_HEIGHT,
+            &Sha256dHash::default(),
+            &mut rows,
+        );
         for row in rows {
             let (key, value) = row.into_pair();
             self.map.entry(key).or_insert(vec![]).push(value);
@@ -39,7 +48,13 @@ impl MempoolStore {
 
     fn remove(&mut self, tx: &Transaction) {
         let mut rows = vec![];
-        index_transaction(tx, MEMPOOL_HEIGHT, &Sha256dHash::default(), &mut rows);
+        index_transaction(
+            self.codec,
+            tx,
+            MEMPOOL_HEIGHT,
+            &Sha256dHash::default(),
+            &mut rows,
+        );
         for row in rows {
             let (key, value) = row.into_pair();
             let no_values_left = {
@@ -71,26 +86,30 @@ impl ReadStore for MempoolStore {
     fn get(&self, key: &[u8]) -> Option<Bytes> {
         Some(self.map.get(key)?.last()?.to_vec())
     }
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let range = self
-            .map
-            .range((Bound::Included(prefix.to_vec()), Bound::Unbounded));
-        let mut rows = vec![];
-        for (key, values) in range {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            if let Some(value) = values.last() {
-                rows.push(Row {
-                    key: key.to_vec(),
-                    value: value.to_vec(),
-                });
-            }
-        }
-        rows
+    fn iter_range<'a>(&'a self, range: ScanRange) -> Box<Iterator<Item = Row> + 'a> {
+        let entries: Box<Iterator<Item = (&Bytes, &Vec<Bytes>)>> =
+            match (range.direction(), range.upper_bound()) {
+                (Direction::Forward, _) => Box::new(self.map.range((
+                    Bound::Included(range.lower_bound().to_vec()),
+                    Bound::Unbounded,
+                ))),
+                (Direction::Reverse, Some(upper)) => Box::new(
+                    self.map
+                        .range((Bound::Unbounded, Bound::Excluded(upper.to_vec())))
+                        .rev(),
+                ),
+                (Direction::Reverse, None) => Box::new(self.map.iter().rev()),
+            };
+        range.clip(entries.filter_map(|(key, values)| {
+            Some(Row {
+                key: key.to_vec(),
+                value: values.last()?.to_vec(),
+            })
+        }))
     }
 }
 
+#[derive(Clone)]
 struct Item {
     tx: Transaction,     // stored for faster retrieval and index removal
     entry: MempoolEntry, // caches mempool fee rates
@@ -138,20 +157,23 @@ impl Stats {
     }
 }
 
+/// The mempool as of its last update. Requests keep it while it is updated (see
+/// `Query::update_mempool`), so it is cloned then.
+#[derive(Clone)]
 pub struct Tracker {
     items: HashMap<Sha256dHash, Item>,
     index: MempoolStore,
     histogram: Vec<(f32, u32)>,
-    stats: Stats,
+    stats: Arc<Stats>,
 }
 
 impl Tracker {
-    pub fn new(metrics: &Metrics) -> Tracker {
+    pub fn new(metrics: &Metrics, codec: RowCodec) -> Tracker {
         Tracker {
             items: HashMap::new(),
-            index: MempoolStore::new(),
+            index: MempoolStore::new(codec),
             histogram: vec![],
-            stats: Stats {
+            stats: Arc::new(Stats {
                 count: metrics.gauge(MetricOpts::new(
                     "mempool_count",
                     "# of mempool transactions",
@@ -168,7 +190,7 @@ impl Tracker {
                     &["fee_rate"],
                 ),
                 max_fee_rate: Mutex::new(1.0),
-            },
+            }),
         }
     }
 
@@ -176,6 +198,20 @@ impl Tracker {
         self.items.get(txid).map(|stats| stats.tx.clone())
     }
 
+    pub fn has_txn(&self, txid: &Sha256dHash) -> bool {
+        self.items.contains_key(txid)
+    }
+
+    /// Returns up to `limit` mempool txids whose hex representation starts with `prefix`.
+    pub fn find_txids_by_prefix(&self, prefix: &str, limit: usize) -> Vec<Sha256dHash> {
+        self.items
+            .keys()
+            .filter(|txid| hash_has_hex_prefix(txid, prefix))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
     /// Returns vector of (fee_rate, vsize) pairs, where fee_{n-1} > fee_n and vsize_n is the
     /// total virtual size of mempool transactions with fee in the bin [fee_{n-1}, fee_n].
     /// Note: fee_{-1} is implied to be infinite.
@@ -1,8 +1,8 @@
 use page_size;
 use prometheus::{self, Encoder};
 use std::fs;
-use std::io;
-use std::net::SocketAddr;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
 use std::thread;
 use std::time::Duration;
 use sysconf;
@@ -13,20 +13,25 @@ pub use prometheus::{
     IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
 };
 
-use util::spawn_thread;
+use config::ListenAddr;
+use util::{bind_unix_socket, spawn_thread};
+
+const MAX_REQUEST_HEAD: usize = 8192;
 
 use errors::*;
 
 pub struct Metrics {
     reg: prometheus::Registry,
-    addr: SocketAddr,
+    addr: ListenAddr,
+    unix_socket_mode: u32,
 }
 
 impl Metrics {
-    pub fn new(addr: SocketAddr) -> Metrics {
+    pub fn new(addr: ListenAddr, unix_socket_mode: u32) -> Metrics {
         Metrics {
             reg: prometheus::Registry::new(),
             addr,
+            unix_socket_mode,
         }
     }
 
@@ -67,17 +72,37 @@ impl Metrics {
     }
 
     pub fn start(&self) {
-        let server = tiny_http::Server::http(self.addr).expect(&format!(
-            "failed to start monitoring HTTP server at {}",
-            self.addr
-        ));
-        start_process_exporter(&self);
-        let reg = self.reg.clone();
-        spawn_thread("metrics", move || loop {
-            if let Err(e) = handle_request(&reg, server.recv()) {
-                error!("http error: {}", e);
+        match self.addr {
+            ListenAddr::Tcp(addr) => {
+                let server = tiny_http::Server::http(addr).expect(&format!(
+                    "failed to start monitoring HTTP server at {}",
+                    addr
+                ));
+                start_process_exporter(&self);
+                let reg = self.reg.clone();
+                spawn_thread("metrics", move || loop {
+                    if let Err(e) = handle_request(&reg, server.recv()) {
+                        error!("http error: {}", e);
+                    }
+                });
             }
-        });
+            ListenAddr::Unix(ref path) => {
+                let listener = bind_unix_socket(path, self.unix_socket_mode).expect(&format!(
+                    "failed to start monitoring HTTP server at {}",
+                    self.addr
+                ));
+                start_process_exporter(&self);
+                let reg = self.reg.clone();
+                spawn_thread("metrics", move || {
+                    for stream in listener.incoming() {
+                        if let Err(e) = stream.and_then(|stream| handle_unix_request(&reg, stream))
+                        {
+                            error!("http error: {}", e);
+                        }
+                    }
+                });
+            }
+        }
     }
 }
 
@@ -94,6 +119,31 @@ fn handle_request(
     request.respond(response)
 }
 
+// tiny_http can't listen on Unix sockets, and the scrapers only ever send a plain GET, so the
+// request head is skipped and the metrics are written back on a non-persistent connection.
+fn handle_unix_request(reg: &prometheus::Registry, mut stream: UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut head = vec![];
+    let mut buf = [0u8; 1024];
+    while !head.ends_with(b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let mut body = vec![];
+    prometheus::TextEncoder::new()
+        .encode(&reg.gather(), &mut body)
+        .unwrap();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
 struct Stats {
     utime: f64,
     rss: u64,
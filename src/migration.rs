@@ -0,0 +1,450 @@
+use bincode;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::network::constants::Network;
+use bitcoin::util::hash::Sha256dHash;
+use error_chain::ChainedError;
+use hex;
+use std::cmp;
+use std::mem;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+
+use app::App;
+use config::Config;
+use index::{index_block_extended, index_search_rows, RawTxRow, RowCodec, TxInRow};
+use query::Query;
+use store::{family_by_name, DBStore, ReadStore, Row, WriteStore};
+use util::{spawn_thread, DEFAULT_HASH_PREFIX_LEN, HASH_LEN, MIN_HASH_PREFIX_LEN};
+
+use errors::*;
+
+// 1: bincode-serialized `I` and `O` keys, with 8 byte prefixes
+// 2: configurable prefix length (stored in the `C` row) and varint-encoded output indexes
+// 3: `S` and `A` rows, for searching txids and addresses by prefix (in full mode)
+pub const DB_VERSION: u32 = 3;
+
+/// Upgrades the DB from `version` to `version + 1`.
+/// Steps may be interrupted, and must be safe to run again from the start.
+struct SchemaStep {
+    version: u32,
+    description: &'static str,
+    /// The codes of the rows it converts, which the index stages meanwhile.
+    codes: &'static [u8],
+    run: fn(&DBStore) -> Result<()>,
+}
+
+const SCHEMA_STEPS: &[SchemaStep] = &[
+    SchemaStep {
+        version: 1,
+        description: "varint-encoded output indexes in `I` rows",
+        codes: b"I",
+        run: migrate_txin_rows_v1,
+    },
+    SchemaStep {
+        version: 2,
+        description: "`S` and `A` rows for prefix searches",
+        codes: b"", // only new rows are written
+        run: build_search_rows_v2,
+    },
+];
+
+/// Converted (and newly indexed) rows are staged under this prefix during an upgrade, so that
+/// they never clash with rows that are still waiting to be converted.
+pub const STAGING_PREFIX: &[u8] = b"_";
+const REWRITE_BATCH_SIZE: usize = 100_000;
+
+// The column families only kept in full mode, with the `t`, `X`, `M`, `S` and `A` rows
+const EXTENDED_FAMILIES: [&str; 5] = [
+    "rawtx",
+    "blocktxids",
+    "blockmeta",
+    "txidsearch",
+    "addrsearch",
+];
+// Next height to build the extended rows for, while switching to full mode
+const EXTENDED_PROGRESS_KEY: &[u8] = b"P";
+const EXTENDED_BATCH_SIZE: usize = 10; // blocks fetched from the daemon at once
+
+/// The layout of the DB, kept in the `C` row.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Schema {
+    pub network_type: Network,
+    pub version: u32,
+    /// Whether the `t`, `X`, `M`, `S` and `A` rows are complete and can be queried.
+    pub extended_db_enabled: bool,
+    pub hash_prefix_len: u32,
+}
+
+impl Schema {
+    fn read(store: &ReadStore) -> Result<Option<Schema>> {
+        let value = match store.get(b"C") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let (network_type, version, extended_db_enabled): (Network, u32, bool) =
+            bincode::deserialize(&value).chain_err(|| "invalid schema row")?;
+        let schema: Schema = match version {
+            // the prefix length wasn't recorded, and was always 8 bytes
+            1 => Schema {
+                network_type,
+                version,
+                extended_db_enabled,
+                hash_prefix_len: 8,
+            },
+            2..=DB_VERSION => bincode::deserialize(&value).chain_err(|| "invalid schema row")?,
+            _ => bail!(
+                "database version {} is not supported (expected up to {}), upgrade electrs",
+                version,
+                DB_VERSION
+            ),
+        };
+        let len = schema.hash_prefix_len as usize;
+        if len < MIN_HASH_PREFIX_LEN || len > HASH_LEN {
+            bail!("invalid prefix length {} in schema row", len);
+        }
+        Ok(Some(schema))
+    }
+
+    pub fn row_codec(&self) -> RowCodec {
+        RowCodec::new(self.hash_prefix_len as usize, self.network_type)
+    }
+
+    /// Checks the settings that can't be changed without a reindex.
+    pub fn check(&self, config: &Config) -> Result<()> {
+        if self.network_type != config.network_type {
+            bail!(
+                "database was created for {}, not for {}",
+                self.network_type,
+                config.network_type
+            );
+        }
+        if let Some(len) = config.index_prefix_len {
+            if len != self.hash_prefix_len as usize {
+                bail!(
+                    "database uses {} byte prefixes, changing --index-prefix-len requires a reindex",
+                    self.hash_prefix_len
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn to_row(&self) -> Row {
+        Row {
+            key: b"C".to_vec(),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+}
+
+/// Reads the layout of an existing DB.
+pub fn read_schema(store: &ReadStore) -> Result<Schema> {
+    Schema::read(store)?.chain_err(|| "missing schema row, not an electrs database")
+}
+
+/// Checks that an existing DB (e.g. a backup) can be used with this configuration, possibly
+/// after upgrading it.
+pub fn check_schema(store: &ReadStore, config: &Config) -> Result<Schema> {
+    let schema = read_schema(store)?;
+    schema.check(config)?;
+    Ok(schema)
+}
+
+/// Reads the schema of the DB (creating it for a new one), and checks it against the
+/// configuration. Toggling `--light` doesn't require a reindex: the extended rows are dropped
+/// right away, or built in the background once the server runs (see `start_schema_upgrade`).
+/// Upgrading the DB to `DB_VERSION` is left to `run_schema_upgrade` or `start_schema_upgrade`.
+pub fn load_schema(store: &mut DBStore, config: &Config) -> Result<Schema> {
+    let mut schema = match Schema::read(store)? {
+        Some(schema) => schema,
+        None => {
+            let schema = Schema {
+                network_type: config.network_type,
+                version: DB_VERSION,
+                extended_db_enabled: config.extended_db_enabled,
+                hash_prefix_len: config.index_prefix_len.unwrap_or(DEFAULT_HASH_PREFIX_LEN) as u32,
+            };
+            store.rewrite(vec![], vec![schema.to_row()]);
+            schema
+        }
+    };
+    schema.check(config)?;
+    if !config.extended_db_enabled {
+        schema.extended_db_enabled = false;
+        store.rewrite(vec![EXTENDED_PROGRESS_KEY.to_vec()], vec![schema.to_row()]);
+        for name in EXTENDED_FAMILIES.iter() {
+            let family = family_by_name(name).expect("missing extended family");
+            if store.iter_scan(&[family.code]).next().is_some() {
+                store.drop_family(family)?;
+            }
+        }
+    }
+    Ok(schema)
+}
+
+/// Whether the DB isn't fully upgraded to `DB_VERSION` yet (possibly by an interrupted run).
+pub fn needs_upgrade(store: &ReadStore, schema: &Schema) -> bool {
+    schema.version < DB_VERSION || store.iter_scan(STAGING_PREFIX).next().is_some()
+}
+
+/// Upgrades the DB to `DB_VERSION` right away, when nothing else uses it yet.
+pub fn run_schema_upgrade(store: &DBStore, schema: &mut Schema) -> Result<()> {
+    run_schema_steps(store, schema)?;
+    move_staged_rows(store)
+}
+
+fn run_schema_steps(store: &DBStore, schema: &mut Schema) -> Result<()> {
+    while schema.version < DB_VERSION {
+        let step = schema_step(schema.version)?;
+        info!(
+            "upgrading database to version {}: {}",
+            step.version + 1,
+            step.description
+        );
+        (step.run)(store)
+            .chain_err(|| format!("failed to upgrade from version {}", step.version))?;
+        schema.version = step.version + 1;
+        store.rewrite(vec![], vec![schema.to_row()]);
+    }
+    Ok(())
+}
+
+fn schema_step(version: u32) -> Result<&'static SchemaStep> {
+    SCHEMA_STEPS
+        .iter()
+        .find(|step| step.version == version)
+        .chain_err(|| format!("no upgrade from database version {}", version))
+}
+
+// Moves the rows converted by the steps (or by an interrupted run) into place.
+fn move_staged_rows(store: &DBStore) -> Result<()> {
+    let moved = rewrite_rows(store, STAGING_PREFIX, |row| {
+        Ok(Row {
+            key: row.key[STAGING_PREFIX.len()..].to_vec(),
+            value: row.value,
+        })
+    })?;
+    if moved > 0 {
+        info!("upgraded {} rows", moved);
+    }
+    Ok(())
+}
+
+// Version 1 bincode-serialized the `I` keys with a u16 output index, while `O` keys were laid
+// out the same way. Indexes above 65535 were truncated, and stay so until a reindex.
+fn migrate_txin_rows_v1(store: &DBStore) -> Result<()> {
+    let count = rewrite_rows(store, b"I", |row| {
+        let row = TxInRow::from_v1_row(&row)
+            .chain_err(|| format!("invalid version 1 `I` row {}", hex::encode(&row.key)))?
+            .to_row();
+        Ok(Row {
+            key: [STAGING_PREFIX, &row.key[..]].concat(),
+            value: row.value,
+        })
+    })?;
+    info!("converted {} `I` rows", count);
+    Ok(())
+}
+
+// Version 2 didn't index the txids and addresses for searches, they are built from the `t` rows
+// (none in light mode, where they are built along with the other extended rows when switching).
+fn build_search_rows_v2(store: &DBStore) -> Result<()> {
+    let codec = read_schema(store)?.row_codec();
+    let mut count = 0;
+    let mut rows = vec![];
+    for row in store.iter_scan(b"t") {
+        let rawtx = RawTxRow::from_row(&row);
+        let txn: Transaction = deserialize(&rawtx.rawtx)
+            .chain_err(|| format!("invalid raw transaction row {}", hex::encode(&row.key)))?;
+        index_search_rows(codec, &txn, &mut rows);
+        count += 1;
+        if rows.len() >= REWRITE_BATCH_SIZE {
+            store.write(mem::replace(&mut rows, vec![]));
+        }
+    }
+    store.write(rows);
+    info!("indexed {} transactions for searches", count);
+    Ok(())
+}
+
+// Replaces every row under `prefix` by its converted one, in atomic batches. Stops at the first
+// row that can't be converted, leaving it (and the following ones) in place.
+fn rewrite_rows<F>(store: &DBStore, prefix: &[u8], convert: F) -> Result<usize>
+where
+    F: Fn(Row) -> Result<Row>,
+{
+    let mut count = 0;
+    let mut keys = vec![];
+    let mut rows = vec![];
+    for row in store.iter_scan(prefix) {
+        keys.push(row.key.clone());
+        rows.push(convert(row)?);
+        if keys.len() == REWRITE_BATCH_SIZE {
+            count += keys.len();
+            store.rewrite(
+                mem::replace(&mut keys, vec![]),
+                mem::replace(&mut rows, vec![]),
+            );
+        }
+    }
+    count += keys.len();
+    store.rewrite(keys, rows);
+    Ok(count)
+}
+
+/// Upgrades the DB to `DB_VERSION`, then builds the extended rows when switching to full mode,
+/// in the background. Meanwhile, the server keeps indexing blocks (staging the rows being
+/// converted) and serving queries: they look up the rows being converted in every format (see
+/// `Query::set_upgrading`), and are served as in light mode (i.e. using the daemon) until the
+/// extended rows are complete. Both run in the same thread, since they update the `C` row.
+///
+/// A failed upgrade is sent to the returned receiver: blocks can't be indexed until the DB is
+/// upgraded, so the server stops (and resumes the upgrade once restarted). A failure to build
+/// the extended rows is only logged, queries keep being served as in light mode.
+pub fn start_schema_upgrade(
+    app: Arc<App>,
+    query: Arc<Query>,
+    schema: Schema,
+    config: &Config,
+) -> Receiver<Error> {
+    let (failure, failures) = mpsc::channel();
+    let upgrade = match app.store() {
+        Some(store) => needs_upgrade(store, &schema),
+        None => return failures, // replicas are upgraded by their writer
+    };
+    let mode_switch = config.extended_db_enabled && !schema.extended_db_enabled;
+    if !upgrade && !mode_switch {
+        return failures;
+    }
+    // blocks indexed after startup get their extended rows right away
+    let tip = app.index().best_height();
+    if upgrade {
+        let codes = SCHEMA_STEPS
+            .iter()
+            .filter(|step| step.version >= schema.version)
+            .flat_map(|step| step.codes.iter().cloned())
+            .collect();
+        app.index().set_staged_codes(codes);
+        query.set_upgrading(true);
+    }
+    spawn_thread("schema_upgrade", move || {
+        let mut schema = schema;
+        if upgrade {
+            let store = app.store().unwrap();
+            let result = run_schema_steps(store, &mut schema).and_then(|()| {
+                // no converted row is left under the staged codes, new rows can be written
+                // in place before moving the staged ones there
+                app.index().set_staged_codes(vec![]);
+                move_staged_rows(store)
+            });
+            if let Err(e) = result {
+                let _ = failure.send(e.chain_err(|| "failed to upgrade database"));
+                return;
+            }
+            query.set_upgrading(false);
+            info!("database upgraded to version {}", DB_VERSION);
+        }
+        if mode_switch {
+            match build_extended_rows(&app, schema, tip) {
+                Ok(()) => query.set_extended_db_enabled(true),
+                Err(e) => error!("failed to build extended rows: {}", e.display_chain()),
+            }
+        }
+    });
+    failures
+}
+
+// Builds the extended rows of the blocks up to `tip`.
+fn build_extended_rows(app: &App, mut schema: Schema, tip: usize) -> Result<()> {
+    let store = app
+        .store()
+        .chain_err(|| "replicas can't build extended rows")?;
+    let daemon = app.daemon().reconnect()?;
+    let codec = schema.row_codec();
+    let mut height = match store.get(EXTENDED_PROGRESS_KEY) {
+        Some(value) => bincode::deserialize::<u32>(&value).chain_err(|| "invalid progress row")?,
+        None => 0,
+    } as usize;
+    info!("building extended rows from height {} to {}", height, tip);
+    while height <= tip {
+        // read at once, so that a reorg can't remove some of them halfway
+        let limit = cmp::min(EXTENDED_BATCH_SIZE, tip + 1 - height);
+        let hashes: Vec<Sha256dHash> = app
+            .index()
+            .get_headers(height, limit)
+            .iter()
+            .map(|entry| *entry.hash())
+            .collect();
+        if hashes.is_empty() {
+            break;
+        }
+        let mut rows = vec![];
+        for block in daemon.getblocks(&hashes)? {
+            rows.extend(index_block_extended(codec, &block));
+        }
+        height += hashes.len();
+        rows.push(Row {
+            key: EXTENDED_PROGRESS_KEY.to_vec(),
+            value: bincode::serialize(&(height as u32)).unwrap(),
+        });
+        store.write(rows);
+        if height % 10_000 < EXTENDED_BATCH_SIZE {
+            info!("built extended rows up to height {}", height);
+        }
+    }
+    // a leftover progress row would make a later switch skip blocks, so it is deleted first
+    store.delete(vec![EXTENDED_PROGRESS_KEY.to_vec()]);
+    schema.extended_db_enabled = true;
+    store.write(vec![schema.to_row()]);
+    info!("extended rows are complete, switched to full mode");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode;
+    use bitcoin::network::constants::Network;
+
+    use super::{Schema, DB_VERSION};
+    use std::iter;
+
+    use store::{ReadStore, Row, ScanRange};
+    use util::Bytes;
+
+    struct SchemaRow(Bytes);
+
+    impl ReadStore for SchemaRow {
+        fn get(&self, key: &[u8]) -> Option<Bytes> {
+            assert_eq!(key, b"C");
+            Some(self.0.clone())
+        }
+        fn iter_range<'a>(&'a self, _range: ScanRange) -> Box<Iterator<Item = Row> + 'a> {
+            Box::new(iter::empty())
+        }
+    }
+
+    #[test]
+    fn test_read_schema() {
+        let v1 = bincode::serialize(&(Network::Testnet, 1u32, true)).unwrap();
+        let schema = Schema::read(&SchemaRow(v1)).unwrap().unwrap();
+        assert_eq!(schema.version, 1);
+        assert_eq!(schema.hash_prefix_len, 8);
+        assert!(schema.extended_db_enabled);
+
+        let v2 = Schema {
+            network_type: Network::Regtest,
+            version: DB_VERSION,
+            extended_db_enabled: false,
+            hash_prefix_len: 12,
+        };
+        let schema = Schema::read(&SchemaRow(v2.to_row().value))
+            .unwrap()
+            .unwrap();
+        assert_eq!(schema.network_type, Network::Regtest);
+        assert_eq!(schema.hash_prefix_len, 12);
+
+        let future = bincode::serialize(&(Network::Testnet, DB_VERSION + 1, true)).unwrap();
+        assert!(Schema::read(&SchemaRow(future)).is_err());
+    }
+}
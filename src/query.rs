@@ -3,17 +3,23 @@ use bitcoin::blockdata::block::Block;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::util::hash::Sha256dHash;
+use error_chain::ChainedError;
 use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 
-use app::App;
-use index::{compute_script_hash, RawTxRow, TxInRow, TxOutRow, TxRow};
+use app::{App, IndexSnapshot};
+use index::{
+    address_search_key, compute_script_hash, RawTxRow, RowCodec, TxInRow, TxOutRow, TxRow,
+};
 use mempool::Tracker;
-use metrics::{HistogramOpts, HistogramVec, Metrics};
+use metrics::{CounterVec, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use migration::STAGING_PREFIX;
 use serde_json::Value;
 use store::{ReadStore, Row};
 use util::{
-    BlockHeaderMeta, BlockMeta, BlockStatus, Bytes, HashPrefix, HeaderEntry, TransactionStatus,
+    hash_has_hex_prefix, hex_prefix_range, BlockHeaderMeta, BlockMeta, BlockStatus, Bytes,
+    HashPrefix, HeaderEntry, TransactionStatus,
 };
 
 use errors::*;
@@ -100,10 +106,14 @@ impl Status {
     pub fn history_txs(&self) -> Vec<&TxnHeight> {
         let mut txns_map = BTreeMap::<Sha256dHash, &TxnHeight>::new();
         for f in self.funding() {
-            txns_map.insert(f.txn_id, &f.txn.as_ref().unwrap());
+            if let Some(ref txn) = f.txn {
+                txns_map.insert(f.txn_id, txn);
+            }
         }
         for s in self.spending() {
-            txns_map.insert(s.txn_id, &s.txn.as_ref().unwrap());
+            if let Some(ref txn) = s.txn {
+                txns_map.insert(s.txn_id, txn);
+            }
         }
         let mut txns: Vec<&TxnHeight> = txns_map.into_iter().map(|item| item.1).collect();
         // Sort in reverse confirmation height order (unconfirmed txns use u32::max_value as their height):
@@ -157,54 +167,191 @@ fn rawtxrow_by_txid(store: &ReadStore, txid: &Sha256dHash) -> Option<RawTxRow> {
 
 fn txrows_by_prefix(store: &ReadStore, txid_prefix: &HashPrefix) -> Vec<TxRow> {
     store
-        .scan(&TxRow::filter_prefix(&txid_prefix))
+        .iter_scan(&TxRow::filter_prefix(&txid_prefix))
+        .map(|row| TxRow::from_row(&row))
+        .collect()
+}
+
+// The `I`, `O` and `T` rows only keep a prefix (see `RowCodec`) of the txids and script
+// hashes they point to, so the transactions found through them may be unrelated ones sharing
+// the prefix. Each prefix is looked up once, and callers must check the full hashes after loading.
+fn txrows_by_prefixes(store: &ReadStore, mut prefixes: Vec<HashPrefix>) -> Vec<TxRow> {
+    prefixes.sort_unstable();
+    prefixes.dedup();
+    prefixes
         .iter()
-        .map(|row| TxRow::from_row(row))
+        .flat_map(|txid_prefix| txrows_by_prefix(store, txid_prefix))
         .collect()
 }
 
-fn txids_by_script_hash(store: &ReadStore, script_hash: &[u8]) -> Vec<HashPrefix> {
-    store
-        .scan(&TxOutRow::filter(script_hash))
+fn funding_outputs(t: &TxnHeight, script_hash: &[u8]) -> Vec<FundingOutput> {
+    let txn_id = t.txn.txid();
+    t.txn
+        .output
+        .iter()
+        .enumerate()
+        .filter(|&(_, output)| compute_script_hash(&output.script_pubkey[..]) == script_hash)
+        .map(|(index, output)| FundingOutput {
+            txn: Some(t.clone()),
+            txn_id: txn_id,
+            height: t.height,
+            output_index: index,
+            value: output.value,
+        })
+        .collect()
+}
+
+fn spending_inputs(t: &TxnHeight, funding: &FundingOutput) -> Vec<SpendingInput> {
+    t.txn
+        .input
         .iter()
-        .take(FUNDING_TXN_LIMIT + 1)
-        .map(|row| TxOutRow::from_row(row).txid_prefix)
+        .enumerate()
+        .filter(|&(_, input)| {
+            input.previous_output.txid == funding.txn_id
+                && input.previous_output.vout == funding.output_index as u32
+        })
+        .map(|(input_index, _)| SpendingInput {
+            txn: Some(t.clone()),
+            txn_id: t.txn.txid(),
+            height: t.height,
+            input_index: input_index,
+            funding_output: (funding.txn_id, funding.output_index),
+            value: funding.value,
+        })
+        .collect()
+}
+
+fn txids_by_script_hash(
+    store: &ReadStore,
+    codec: RowCodec,
+    script_hash: &[u8],
+) -> Vec<Result<HashPrefix>> {
+    store
+        .iter_scan(&TxOutRow::filter(codec, script_hash))
+        .take(FUNDING_TXN_LIMIT + 1) // stops reading the index right after the limit
+        .map(|row| TxOutRow::from_row(codec, &row).map(|row| row.txid_prefix))
         .collect()
 }
 
 fn txids_by_funding_output(
     store: &ReadStore,
+    codec: RowCodec,
     txn_id: &Sha256dHash,
     output_index: usize,
-) -> Vec<HashPrefix> {
+) -> Vec<Result<HashPrefix>> {
     store
-        .scan(&TxInRow::filter(&txn_id, output_index))
-        .iter()
-        .map(|row| TxInRow::from_row(row).txid_prefix)
+        .iter_scan(&TxInRow::filter(codec, &txn_id, output_index))
+        .map(|row| TxInRow::from_row(codec, &row).map(|row| row.txid_prefix))
         .collect()
 }
 
-pub fn get_block_meta(store: &ReadStore, blockhash: &Sha256dHash) -> Option<BlockMeta> {
+// While the `I` rows are converted (see `migration::start_schema_upgrade`), the spending rows
+// may be found under their version 1 key, their staged key or their current one. The rows of
+// another format that match a lookup are skipped, or only lead to colliding transactions.
+fn txids_by_funding_output_upgrading(
+    store: &ReadStore,
+    codec: RowCodec,
+    txn_id: &Sha256dHash,
+    output_index: usize,
+) -> Vec<HashPrefix> {
+    let current = TxInRow::filter(codec, &txn_id, output_index);
+    let staged = [STAGING_PREFIX, &current[..]].concat();
+    let mut prefixes: Vec<HashPrefix> = store
+        .iter_scan(&current)
+        .chain(store.iter_scan(&staged).map(|row| Row {
+            key: row.key[STAGING_PREFIX.len()..].to_vec(),
+            value: row.value,
+        }))
+        .filter_map(|row| TxInRow::from_row(codec, &row).ok())
+        .map(|row| row.txid_prefix)
+        .collect();
+    prefixes.extend(
+        store
+            .iter_scan(&TxInRow::filter_v1(&txn_id, output_index))
+            .filter_map(|row| TxInRow::from_v1_row(&row))
+            .map(|row| row.txid_prefix),
+    );
+    prefixes
+}
+
+pub fn get_block_meta(store: &ReadStore, blockhash: &Sha256dHash) -> Result<Option<BlockMeta>> {
     let key = [b"M", &blockhash[..]].concat();
-    let value = store.get(&key)?;
-    let meta: BlockMeta = bincode::deserialize(&value).unwrap();
-    Some(meta)
+    match store.get(&key) {
+        Some(value) => Ok(Some(
+            bincode::deserialize(&value).chain_err(|| "invalid block meta row")?,
+        )),
+        None => Ok(None),
+    }
 }
 
-pub fn get_block_txids(store: &ReadStore, blockhash: &Sha256dHash) -> Option<Vec<Sha256dHash>> {
+pub fn get_block_txids(
+    store: &ReadStore,
+    blockhash: &Sha256dHash,
+) -> Result<Option<Vec<Sha256dHash>>> {
     let key = [b"X", &blockhash[..]].concat();
-    let value = store.get(&key)?;
-    let txids: Vec<Sha256dHash> = bincode::deserialize(&value).unwrap();
-    Some(txids)
+    match store.get(&key) {
+        Some(value) => Ok(Some(
+            bincode::deserialize(&value).chain_err(|| "invalid block txids row")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Returns up to `limit` confirmed txids starting with the hex `prefix`, using the `S` rows.
+pub fn txids_by_hex_prefix(store: &ReadStore, prefix: &str, limit: usize) -> Vec<Sha256dHash> {
+    let (first, _) = match hex_prefix_range(prefix) {
+        Some(range) => range,
+        None => return vec![],
+    };
+    // an odd prefix only scans by its whole bytes
+    store
+        .iter_scan(&[b"S", &first[..prefix.len() / 2]].concat())
+        .filter_map(|row| {
+            let txid: Vec<u8> = row.key[1..].iter().rev().cloned().collect();
+            deserialize::<Sha256dHash>(&txid).ok()
+        })
+        .filter(|txid| hash_has_hex_prefix(txid, prefix))
+        .take(limit)
+        .collect()
+}
+
+/// Returns up to `limit` addresses paid by confirmed transactions starting with `prefix`, using
+/// the `A` rows.
+pub fn addresses_by_prefix(store: &ReadStore, prefix: &str, limit: usize) -> Vec<String> {
+    store
+        .iter_scan(&address_search_key(prefix))
+        .filter_map(|row| String::from_utf8(row.key[1..].to_vec()).ok())
+        .take(limit)
+        .collect()
+}
+
+/// What a request is served from: an index snapshot, the best chain up to its tip, and the
+/// mempool synced right after it. Requests take a single one (see `Query::read_context`), so
+/// that all their lookups agree with each other.
+#[derive(Clone)]
+pub struct ReadContext {
+    index: Arc<IndexSnapshot>,
+    tracker: Arc<Tracker>,
+}
+
+impl ReadContext {
+    pub fn snapshot(&self) -> &Arc<IndexSnapshot> {
+        &self.index
+    }
 }
 
 pub struct Query {
     app: Arc<App>,
-    tracker: RwLock<Tracker>,
-    extended_db_enabled: bool,
+    tracker: RwLock<Arc<Tracker>>,
+    // only replaced while `tracker` is write-locked, see `read_context()`
+    snapshot: RwLock<Arc<IndexSnapshot>>,
+    extended_db_enabled: AtomicBool,
+    upgrading: AtomicBool,
 
     // monitoring
     latency: HistogramVec,
+    inconsistencies: CounterVec,
+    collisions: CounterVec,
 }
 
 impl Query {
@@ -213,25 +360,114 @@ impl Query {
             1e-4, 2e-4, 5e-4, 1e-3, 2e-3, 5e-3, 1e-2, 2e-2, 5e-2, 0.1, 0.2, 0.5, 1., 2., 5., 10.,
             20., 50., 100.,
         ];
+        let codec = app.index().codec();
         Arc::new(Query {
+            snapshot: RwLock::new(app.snapshot()),
             app,
-            extended_db_enabled,
-            tracker: RwLock::new(Tracker::new(metrics)),
+            extended_db_enabled: AtomicBool::new(extended_db_enabled),
+            upgrading: AtomicBool::new(false),
+            tracker: RwLock::new(Arc::new(Tracker::new(metrics, codec))),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("query_latency", "Query latency (in seconds)")
                     .buckets(latency_buckets),
                 &["type"],
             ),
+            inconsistencies: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_inconsistencies",
+                    "# of missing, corrupted or ambiguous index entries found while serving queries",
+                ),
+                &["type"],
+            ),
+            collisions: metrics.counter_vec(
+                MetricOpts::new(
+                    "query_prefix_collisions",
+                    "# of transactions loaded through a colliding hash prefix",
+                ),
+                &["type"],
+            ),
         })
     }
 
+    /// Starts using the `t`, `X` and `M` rows instead of the daemon, once they are complete.
+    pub fn set_extended_db_enabled(&self, enabled: bool) {
+        self.extended_db_enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Set while the `I` rows are converted in the background (see `migration`), to look them
+    /// up in every format.
+    pub fn set_upgrading(&self, upgrading: bool) {
+        self.upgrading.store(upgrading, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Arc<IndexSnapshot> {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// See `App::read_only_snapshot`.
+    pub fn read_only_snapshot(&self) -> Result<Arc<IndexSnapshot>> {
+        self.app.read_only_snapshot()
+    }
+
+    /// The state to serve a request from. The mempool is only replaced along with the snapshot
+    /// it was synced after, so a request can't see a block being connected halfway (or its
+    /// txns twice).
+    pub fn read_context(&self) -> ReadContext {
+        let tracker = self.tracker.read().unwrap();
+        ReadContext {
+            index: self.snapshot(),
+            tracker: tracker.clone(),
+        }
+    }
+
+    // The best chain up to the request's snapshot tip: the headers connected after it are
+    // skipped (its rows don't have them), and none are returned while a reorg of the snapshot
+    // tip isn't in a snapshot yet.
+    fn header_at(&self, ctx: &ReadContext, height: usize) -> Option<HeaderEntry> {
+        if height > ctx.index.height() {
+            return None;
+        }
+        let index = self.app.index();
+        match index.get_header(ctx.index.height()) {
+            Some(ref tip) if tip.hash() == ctx.index.tip() => index.get_header(height),
+            _ => None,
+        }
+    }
+
+    fn header_by_hash(&self, ctx: &ReadContext, hash: &Sha256dHash) -> Option<HeaderEntry> {
+        let header = self.app.index().get_header_by_hash(hash)?;
+        self.header_at(ctx, header.height())
+            .filter(|best| best.hash() == header.hash())
+    }
+
+    /// Records index data that a query couldn't make sense of, instead of panicking on it.
+    pub fn count_inconsistency(&self, kind: &str) {
+        self.inconsistencies.with_label_values(&[kind]).inc();
+    }
+
+    // Invalid rows are skipped, so that a corrupted row doesn't fail the whole query.
+    fn valid_prefixes(&self, prefixes: Vec<Result<HashPrefix>>) -> Vec<HashPrefix> {
+        prefixes
+            .into_iter()
+            .filter_map(|prefix| match prefix {
+                Ok(prefix) => Some(prefix),
+                Err(e) => {
+                    warn!("skipping index row: {}", e.display_chain());
+                    self.count_inconsistency("invalid_index_row");
+                    None
+                }
+            })
+            .collect()
+    }
+
     fn load_txns_by_prefix(
         &self,
+        ctx: &ReadContext,
         store: &ReadStore,
         prefixes: Vec<HashPrefix>,
     ) -> Result<Vec<TxnHeight>> {
         if prefixes.len() > FUNDING_TXN_LIMIT {
-            bail!("Too many txs");
+            bail!(ErrorKind::TooManyTxs(FUNDING_TXN_LIMIT));
         }
 
         let mut txns = vec![];
@@ -239,24 +475,24 @@ impl Query {
             .latency
             .with_label_values(&["load_txns_by_prefix"])
             .start_timer();
-        for txid_prefix in prefixes {
-            for tx_row in txrows_by_prefix(store, &txid_prefix) {
-                let txid: Sha256dHash = deserialize(&tx_row.key.txid).unwrap();
-                let txn = self
-                    .load_txn(&txid, Some(&tx_row.blockhash))
-                    .chain_err(|| "cannot locate tx")?;
-                txns.push(TxnHeight {
-                    txn,
-                    height: tx_row.height,
-                    blockhash: tx_row.blockhash,
-                })
-            }
+        for tx_row in txrows_by_prefixes(store, prefixes) {
+            let txid: Sha256dHash =
+                deserialize(&tx_row.key.txid).chain_err(|| "invalid txid in tx row")?;
+            let txn = self
+                .load_txn(ctx, &txid, Some(&tx_row.blockhash))
+                .chain_err(|| "cannot locate tx")?;
+            txns.push(TxnHeight {
+                txn,
+                height: tx_row.height,
+                blockhash: tx_row.blockhash,
+            })
         }
         Ok(txns)
     }
 
     fn find_spending_input(
         &self,
+        ctx: &ReadContext,
         store: &ReadStore,
         funding: &FundingOutput,
     ) -> Result<Option<SpendingInput>> {
@@ -264,32 +500,40 @@ impl Query {
             .latency
             .with_label_values(&["find_spending_input"])
             .start_timer();
-        let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(
-            store,
-            txids_by_funding_output(store, &funding.txn_id, funding.output_index),
-        )?;
-        let mut spending_inputs = vec![];
+        let codec = self.app.index().codec();
+        let prefixes = if self.upgrading.load(Ordering::Relaxed) {
+            txids_by_funding_output_upgrading(store, codec, &funding.txn_id, funding.output_index)
+        } else {
+            self.valid_prefixes(txids_by_funding_output(
+                store,
+                codec,
+                &funding.txn_id,
+                funding.output_index,
+            ))
+        };
+        let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(ctx, store, prefixes)?;
+        let mut spenders = vec![];
         for t in &spending_txns {
-            for (input_index, input) in t.txn.input.iter().enumerate() {
-                if input.previous_output.txid == funding.txn_id
-                    && input.previous_output.vout == funding.output_index as u32
-                {
-                    spending_inputs.push(SpendingInput {
-                        txn: Some(t.clone()),
-                        txn_id: t.txn.txid(),
-                        height: t.height,
-                        input_index: input_index,
-                        funding_output: (funding.txn_id, funding.output_index),
-                        value: funding.value,
-                    })
-                }
+            let inputs = spending_inputs(t, funding);
+            if inputs.is_empty() {
+                self.collisions.with_label_values(&["spending"]).inc();
             }
+            spenders.extend(inputs);
         }
-        assert!(spending_inputs.len() <= 1);
-        Ok(if spending_inputs.len() == 1 {
-            Some(spending_inputs.remove(0))
-        } else {
+        if spenders.len() > 1 {
+            // only possible with leftover rows (e.g. of a reorged block), report the first one
+            warn!(
+                "{} inputs spend {}:{}",
+                spenders.len(),
+                funding.txn_id,
+                funding.output_index
+            );
+            self.count_inconsistency("multiple_spenders");
+        }
+        Ok(if spenders.is_empty() {
             None
+        } else {
+            Some(spenders.remove(0))
         })
     }
 
@@ -298,24 +542,16 @@ impl Query {
             .latency
             .with_label_values(&["find_funding_outputs"])
             .start_timer();
-        let mut result = vec![];
-        let txn_id = t.txn.txid();
-        for (index, output) in t.txn.output.iter().enumerate() {
-            if compute_script_hash(&output.script_pubkey[..]) == script_hash {
-                result.push(FundingOutput {
-                    txn: Some(t.clone()),
-                    txn_id: txn_id,
-                    height: t.height,
-                    output_index: index,
-                    value: output.value,
-                })
-            }
+        let result = funding_outputs(t, script_hash);
+        if result.is_empty() {
+            self.collisions.with_label_values(&["funding"]).inc();
         }
         result
     }
 
     fn confirmed_status(
         &self,
+        ctx: &ReadContext,
         script_hash: &[u8],
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
         let _timer = self
@@ -324,13 +560,17 @@ impl Query {
             .start_timer();
         let mut funding = vec![];
         let mut spending = vec![];
-        let read_store = self.app.read_store();
-        let txid_prefixes = txids_by_script_hash(read_store, script_hash);
-        for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
+        let store = ctx.index.store();
+        let txid_prefixes = self.valid_prefixes(txids_by_script_hash(
+            store,
+            self.app.index().codec(),
+            script_hash,
+        ));
+        for t in self.load_txns_by_prefix(ctx, store, txid_prefixes)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         for funding_output in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
+            if let Some(spent) = self.find_spending_input(ctx, store, &funding_output)? {
                 spending.push(spent);
             }
         }
@@ -339,6 +579,7 @@ impl Query {
 
     fn mempool_status(
         &self,
+        ctx: &ReadContext,
         script_hash: &[u8],
         confirmed_funding: &[FundingOutput],
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
@@ -348,42 +589,50 @@ impl Query {
             .start_timer();
         let mut funding = vec![];
         let mut spending = vec![];
-        let tracker = self.tracker.read().unwrap();
-        let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash);
-        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes)? {
+        let mempool = ctx.tracker.index();
+        let txid_prefixes = self.valid_prefixes(txids_by_script_hash(
+            mempool,
+            self.app.index().codec(),
+            script_hash,
+        ));
+        for t in self.load_txns_by_prefix(ctx, mempool, txid_prefixes)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         // // TODO: dedup outputs (somehow) both confirmed and in mempool (e.g. reorg?)
         for funding_output in funding.iter().chain(confirmed_funding.iter()) {
-            if let Some(spent) = self.find_spending_input(tracker.index(), &funding_output)? {
+            if let Some(spent) = self.find_spending_input(ctx, mempool, &funding_output)? {
                 spending.push(spent);
             }
         }
         Ok((funding, spending))
     }
 
-    pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
+    pub fn status(&self, ctx: &ReadContext, script_hash: &[u8]) -> Result<Status> {
         let _timer = self.latency.with_label_values(&["status"]).start_timer();
-        let confirmed = self.confirmed_status(script_hash)?;
+        let confirmed = self.confirmed_status(ctx, script_hash)?;
         //.chain_err(|| "failed to get confirmed status")?;
-        let mempool = self.mempool_status(script_hash, &confirmed.0)?;
+        let mempool = self.mempool_status(ctx, script_hash, &confirmed.0)?;
         //.chain_err(|| "failed to get mempool status")?;
         Ok(Status { confirmed, mempool })
     }
 
-    pub fn find_spending_by_outpoint(&self, outpoint: OutPoint) -> Result<Option<SpendingInput>> {
+    pub fn find_spending_by_outpoint(
+        &self,
+        ctx: &ReadContext,
+        outpoint: OutPoint,
+    ) -> Result<Option<SpendingInput>> {
         let _timer = self
             .latency
             .with_label_values(&["find_spending_by_outpoint"])
             .start_timer();
         let funding_output = FundingOutput::from(outpoint);
-        let read_store = self.app.read_store();
-        let tracker = self.tracker.read().unwrap();
         Ok(
-            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
+            if let Some(spent) =
+                self.find_spending_input(ctx, ctx.index.store(), &funding_output)?
+            {
                 Some(spent)
             } else if let Some(spent) =
-                self.find_spending_input(tracker.index(), &funding_output)?
+                self.find_spending_input(ctx, ctx.tracker.index(), &funding_output)?
             {
                 Some(spent)
             } else {
@@ -394,6 +643,7 @@ impl Query {
 
     pub fn find_spending_for_funding_tx(
         &self,
+        ctx: &ReadContext,
         tx: Transaction,
     ) -> Result<Vec<Option<SpendingInput>>> {
         let _timer = self
@@ -404,7 +654,7 @@ impl Query {
         let mut spends = vec![];
         for (output_index, output) in tx.output.iter().enumerate() {
             let spend = if !output.script_pubkey.is_provably_unspendable() {
-                self.find_spending_by_outpoint((txid, output_index))?
+                self.find_spending_by_outpoint(ctx, (txid, output_index))?
             } else {
                 None
             };
@@ -415,6 +665,7 @@ impl Query {
 
     fn lookup_confirmed_blockhash(
         &self,
+        ctx: &ReadContext,
         tx_hash: &Sha256dHash,
         block_height: Option<u32>,
     ) -> Result<Option<Sha256dHash>> {
@@ -422,22 +673,20 @@ impl Query {
             .latency
             .with_label_values(&["lookup_confirmed_blockhash"])
             .start_timer();
-        let blockhash = if self.tracker.read().unwrap().get_txn(&tx_hash).is_some() {
+        let blockhash = if ctx.tracker.get_txn(&tx_hash).is_some() {
             None // found in mempool (as unconfirmed transaction)
         } else {
             // Lookup in confirmed transactions' index
             let height = match block_height {
                 Some(height) => height,
                 None => {
-                    txrow_by_txid(self.app.read_store(), &tx_hash)
-                        .chain_err(|| format!("not indexed tx {}", tx_hash))?
+                    txrow_by_txid(ctx.index.store(), &tx_hash)
+                        .ok_or_else(|| Error::from(ErrorKind::TxNotFound(*tx_hash)))?
                         .height
                 }
             };
             let header = self
-                .app
-                .index()
-                .get_header(height as usize)
+                .header_at(ctx, height as usize)
                 .chain_err(|| format!("missing header at height {}", height))?;
             Some(*header.hash())
         };
@@ -447,21 +696,28 @@ impl Query {
     // Load transaction by txid
     pub fn load_txn(
         &self,
+        ctx: &ReadContext,
         txid: &Sha256dHash,
         blockhash: Option<&Sha256dHash>,
     ) -> Result<Transaction> {
         let _timer = self.latency.with_label_values(&["load_txn"]).start_timer();
-        if self.extended_db_enabled {
+        if self.extended_db_enabled.load(Ordering::Relaxed) {
             // fetch from our txstore or mempool tracker
-            rawtxrow_by_txid(self.app.read_store(), txid)
-                .map(|row| deserialize(&row.rawtx).expect("cannot parse tx from txstore"))
-                .or_else(|| self.tracker.read().unwrap().get_txn(&txid))
-                .chain_err(|| format!("cannot find tx {}", txid))
+            match rawtxrow_by_txid(ctx.index.store(), txid) {
+                Some(row) => deserialize(&row.rawtx).or_else(|_| {
+                    self.count_inconsistency("invalid_rawtx");
+                    bail!("cannot parse tx {} from txstore", txid)
+                }),
+                None => ctx
+                    .tracker
+                    .get_txn(&txid)
+                    .ok_or_else(|| ErrorKind::TxNotFound(*txid).into()),
+            }
         } else {
             // fetch from bitcoind
             let blockhash_from_index: Option<Sha256dHash> = match blockhash {
                 Some(_) => None,
-                None => self.lookup_confirmed_blockhash(txid, None)?,
+                None => self.lookup_confirmed_blockhash(ctx, txid, None)?,
             };
             let blockhash: Option<&Sha256dHash> = blockhash.or(blockhash_from_index.as_ref());
             self.app.daemon().gettransaction(txid, blockhash)
@@ -471,6 +727,7 @@ impl Query {
     // Load raw transaction by txid
     pub fn load_raw_txn(
         &self,
+        ctx: &ReadContext,
         txid: &Sha256dHash,
         blockhash: Option<&Sha256dHash>,
     ) -> Result<Bytes> {
@@ -478,23 +735,17 @@ impl Query {
             .latency
             .with_label_values(&["load_raw_txn"])
             .start_timer();
-        if self.extended_db_enabled {
+        if self.extended_db_enabled.load(Ordering::Relaxed) {
             // fetch from our txstore or mempool tracker
-            Ok(rawtxrow_by_txid(self.app.read_store(), txid)
+            Ok(rawtxrow_by_txid(ctx.index.store(), txid)
                 .map(|row| row.rawtx)
-                .or_else(|| {
-                    self.tracker
-                        .read()
-                        .unwrap()
-                        .get_txn(&txid)
-                        .map(|tx| serialize(&tx))
-                })
-                .chain_err(|| format!("cannot find tx {}", txid))?)
+                .or_else(|| ctx.tracker.get_txn(&txid).map(|tx| serialize(&tx)))
+                .ok_or_else(|| Error::from(ErrorKind::TxNotFound(*txid)))?)
         } else {
             // fetch from bitcoind
             let blockhash_from_index: Option<Sha256dHash> = match blockhash {
                 Some(_) => None,
-                None => self.lookup_confirmed_blockhash(txid, None)?,
+                None => self.lookup_confirmed_blockhash(ctx, txid, None)?,
             };
             let blockhash: Option<&Sha256dHash> = blockhash.or(blockhash_from_index.as_ref());
             let tx_val = self
@@ -510,12 +761,18 @@ impl Query {
 
     // Public API for transaction retrieval (for Electrum RPC)
     // Fetched from bitcoind, includes tx confirmation information (number of confirmations and block hash)
-    pub fn get_transaction(&self, tx_hash: &Sha256dHash, verbose: bool) -> Result<Value> {
+    pub fn get_transaction(
+        &self,
+        ctx: &ReadContext,
+        tx_hash: &Sha256dHash,
+        verbose: bool,
+    ) -> Result<Value> {
         let _timer = self
             .latency
             .with_label_values(&["get_transaction"])
             .start_timer();
-        let blockhash = self.lookup_confirmed_blockhash(tx_hash, /*block_height*/ None)?;
+        let blockhash =
+            self.lookup_confirmed_blockhash(ctx, tx_hash, /*block_height*/ None)?;
         self.app
             .daemon()
             .gettransaction_raw(tx_hash, blockhash.as_ref(), verbose)
@@ -526,26 +783,47 @@ impl Query {
         self.app.daemon().getblock(blockhash)
     }
 
-    pub fn get_block_header_with_meta(&self, blockhash: &Sha256dHash) -> Result<BlockHeaderMeta> {
+    // Serialized block as returned by bitcoind, kept byte-for-byte so it can be relayed as-is
+    pub fn get_block_raw(&self, blockhash: &Sha256dHash) -> Result<Bytes> {
+        let _timer = self
+            .latency
+            .with_label_values(&["get_block_raw"])
+            .start_timer();
+        let block_hex = self.app.daemon().getblock_raw(blockhash, 0)?;
+        Ok(
+            ::hex::decode(block_hex.as_str().chain_err(|| "non-string block hex")?)
+                .chain_err(|| "invalid hex")?,
+        )
+    }
+
+    pub fn get_block_header_with_meta(
+        &self,
+        ctx: &ReadContext,
+        blockhash: &Sha256dHash,
+    ) -> Result<BlockHeaderMeta> {
         let _timer = self
             .latency
             .with_label_values(&["get_block_header_with_meta"])
             .start_timer();
         Ok(BlockHeaderMeta {
-            header_entry: self.get_header_by_hash(blockhash)?,
-            meta: self.get_block_meta(blockhash)?,
+            header_entry: self.get_header_by_hash(ctx, blockhash)?,
+            meta: self.get_block_meta(ctx, blockhash)?,
         })
     }
 
-    pub fn get_block_txids(&self, blockhash: &Sha256dHash) -> Result<Vec<Sha256dHash>> {
+    pub fn get_block_txids(
+        &self,
+        ctx: &ReadContext,
+        blockhash: &Sha256dHash,
+    ) -> Result<Vec<Sha256dHash>> {
         let _timer = self
             .latency
             .with_label_values(&["get_block_txids"])
             .start_timer();
-        if self.extended_db_enabled {
+        if self.extended_db_enabled.load(Ordering::Relaxed) {
             // fetch from our blockhash=>txids index
-            get_block_txids(self.app.read_store(), blockhash)
-                .chain_err(|| "cannot load block txids")
+            get_block_txids(ctx.index.store(), blockhash)?
+                .ok_or_else(|| ErrorKind::BlockNotFound(*blockhash).into())
         } else {
             // fetch from bitcoind
             let block = self
@@ -568,61 +846,59 @@ impl Query {
         }
     }
 
-    pub fn get_block_meta(&self, blockhash: &Sha256dHash) -> Result<BlockMeta> {
+    pub fn get_block_meta(&self, ctx: &ReadContext, blockhash: &Sha256dHash) -> Result<BlockMeta> {
         let _timer = self
             .latency
             .with_label_values(&["get_block_meta"])
             .start_timer();
-        if self.extended_db_enabled {
+        if self.extended_db_enabled.load(Ordering::Relaxed) {
             // fetch from our blockhash=>txids index
-            get_block_meta(self.app.read_store(), blockhash).chain_err(|| "cannot load block meta")
+            get_block_meta(ctx.index.store(), blockhash)?
+                .ok_or_else(|| ErrorKind::BlockNotFound(*blockhash).into())
         } else {
             // fetch from bitcoind
             BlockMeta::parse_getblock(self.app.daemon().getblock_raw(blockhash, 1)?)
         }
     }
 
-    pub fn get_headers(&self, heights: &[usize]) -> Vec<HeaderEntry> {
-        let index = self.app.index();
+    pub fn get_headers(&self, ctx: &ReadContext, heights: &[usize]) -> Vec<HeaderEntry> {
         heights
             .iter()
-            .filter_map(|height| index.get_header(*height))
+            .filter_map(|height| self.header_at(ctx, *height))
             .collect()
     }
 
-    pub fn get_header_by_hash(&self, hash: &Sha256dHash) -> Result<HeaderEntry> {
-        let header = self.app.index().get_header_by_hash(hash);
-        Ok(header.chain_err(|| "no header found")?.clone())
+    pub fn get_header_by_hash(&self, ctx: &ReadContext, hash: &Sha256dHash) -> Result<HeaderEntry> {
+        self.header_by_hash(ctx, hash)
+            .ok_or_else(|| ErrorKind::BlockNotFound(*hash).into())
     }
 
-    pub fn get_best_header(&self) -> Result<HeaderEntry> {
-        let last_header = self.app.index().best_header();
-        Ok(last_header.chain_err(|| "no headers indexed")?.clone())
+    pub fn get_best_header(&self, ctx: &ReadContext) -> Result<HeaderEntry> {
+        self.header_by_hash(ctx, ctx.index.tip())
+            .chain_err(|| "no headers indexed")
     }
 
-    pub fn get_best_header_hash(&self) -> Sha256dHash {
-        self.app.index().best_header_hash()
+    pub fn get_best_header_hash(&self, ctx: &ReadContext) -> Sha256dHash {
+        *ctx.index.tip()
     }
 
-    pub fn get_best_height(&self) -> usize {
-        self.app.index().best_height()
+    pub fn get_best_height(&self, ctx: &ReadContext) -> usize {
+        ctx.index.height()
     }
 
-    pub fn get_block_status(&self, hash: &Sha256dHash) -> BlockStatus {
+    pub fn get_block_status(&self, ctx: &ReadContext, hash: &Sha256dHash) -> BlockStatus {
         let _timer = self
             .latency
             .with_label_values(&["get_block_status"])
             .start_timer();
         // get_header_by_hash looks up the height first, then fetches the header by that.
         // if the block is no longer the best block at this height, it'll return None.
-        match self.app.index().get_header_by_hash(hash) {
+        match self.header_by_hash(ctx, hash) {
             Some(header) => BlockStatus {
                 in_best_chain: true,
                 height: Some(header.height()),
                 next_best: self
-                    .app
-                    .index()
-                    .get_header(header.height() + 1)
+                    .header_at(ctx, header.height() + 1)
                     .map(|h| h.hash().clone()),
             },
             None => BlockStatus {
@@ -633,22 +909,24 @@ impl Query {
         }
     }
 
-    pub fn get_tx_status(&self, tx_hash: &Sha256dHash) -> Result<TransactionStatus> {
+    pub fn get_tx_status(
+        &self,
+        ctx: &ReadContext,
+        tx_hash: &Sha256dHash,
+    ) -> Result<TransactionStatus> {
         let _timer = self
             .latency
             .with_label_values(&["get_tx_status"])
             .start_timer();
         // try fetching the height/hash of the block seen to confirm the tx
-        let (height, blockhash) = match txrow_by_txid(self.app.read_store(), &tx_hash) {
+        let (height, blockhash) = match txrow_by_txid(ctx.index.store(), &tx_hash) {
             None => return Ok(TransactionStatus::unconfirmed()),
             Some(txrow) => (txrow.height, txrow.blockhash),
         };
 
         // fetch the block header at the recorded confirmation height
         let header = self
-            .app
-            .index()
-            .get_header(height as usize)
+            .header_at(ctx, height as usize)
             .chain_err(|| "invalid block height for tx")?;
 
         // the block at confirmation height is not the one containing the tx, must've reorged!
@@ -659,8 +937,64 @@ impl Query {
         }
     }
 
+    /// Checks whether the transaction is confirmed (according to the `T` rows) or in the mempool.
+    pub fn has_txn(&self, ctx: &ReadContext, txid: &Sha256dHash) -> bool {
+        txrow_by_txid(ctx.index.store(), txid).is_some() || ctx.tracker.has_txn(txid)
+    }
+
+    /// Finds best chain blocks and transactions by a partial hex hash. Confirmed transactions
+    /// are only found in full mode (see `index::txid_search_key`).
+    pub fn search_by_prefix(
+        &self,
+        ctx: &ReadContext,
+        prefix: &str,
+        limit: usize,
+    ) -> (Vec<HeaderEntry>, Vec<Sha256dHash>) {
+        let _timer = self
+            .latency
+            .with_label_values(&["search_by_prefix"])
+            .start_timer();
+        let headers = self
+            .app
+            .index()
+            .find_headers_by_prefix(prefix, limit)
+            .into_iter()
+            .filter(|header| header.height() <= ctx.index.height())
+            .collect();
+        let mut txids = ctx.tracker.find_txids_by_prefix(prefix, limit);
+        if self.extended_db_enabled.load(Ordering::Relaxed) {
+            for txid in txids_by_hex_prefix(ctx.index.store(), prefix, limit) {
+                if txids.len() == limit {
+                    break;
+                }
+                if !txids.contains(&txid) {
+                    txids.push(txid);
+                }
+            }
+        }
+        (headers, txids)
+    }
+
+    /// Finds addresses paid by confirmed transactions by their prefix, in full mode only.
+    pub fn search_addresses_by_prefix(
+        &self,
+        ctx: &ReadContext,
+        prefix: &str,
+        limit: usize,
+    ) -> Vec<String> {
+        let _timer = self
+            .latency
+            .with_label_values(&["search_addresses_by_prefix"])
+            .start_timer();
+        if !self.extended_db_enabled.load(Ordering::Relaxed) {
+            return vec![];
+        }
+        addresses_by_prefix(ctx.index.store(), prefix, limit)
+    }
+
     pub fn get_merkle_proof(
         &self,
+        ctx: &ReadContext,
         tx_hash: &Sha256dHash,
         block_hash: &Sha256dHash,
     ) -> Result<(Vec<Sha256dHash>, usize)> {
@@ -669,7 +1003,7 @@ impl Query {
             .with_label_values(&["get_merkle_proof"])
             .start_timer();
         let mut txids = self
-            .get_block_txids(&block_hash)
+            .get_block_txids(ctx, &block_hash)
             .chain_err(|| format!("missing txids for block #{}", block_hash))?;
         let pos = txids
             .iter()
@@ -697,8 +1031,13 @@ impl Query {
         self.app.daemon().broadcast(txn)
     }
 
+    /// Syncs the mempool, and starts serving requests from the latest index snapshot.
     pub fn update_mempool(&self) -> Result<()> {
-        self.tracker.write().unwrap().update(self.app.daemon())
+        let mut tracker = self.tracker.write().unwrap();
+        // the requests still being served from the previous mempool keep it
+        Arc::make_mut(&mut tracker).update(self.app.daemon())?;
+        *self.snapshot.write().unwrap() = self.app.snapshot();
+        Ok(())
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
@@ -721,3 +1060,212 @@ impl Query {
         last_fee_rate * 1e-5 // [BTC/kB] = 10^5 [sat/B]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::consensus::encode::deserialize;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::Sha256dHash;
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::{
+        addresses_by_prefix, funding_outputs, spending_inputs, txids_by_funding_output,
+        txids_by_funding_output_upgrading, txids_by_hex_prefix, txids_by_script_hash,
+        txrows_by_prefixes, FundingOutput, TxnHeight,
+    };
+    use errors::*;
+    use fake::MemStore;
+    use index::{compute_script_hash, index_search_rows, index_transaction, RowCodec};
+    use store::{ReadStore, Row};
+    use util::{script_to_address, Bytes, HashPrefix};
+
+    // single byte prefixes, so that colliding txids and scripts are found quickly
+    fn codec() -> RowCodec {
+        RowCodec::new(1, Network::Regtest)
+    }
+
+    fn index(txns: &[&Transaction]) -> MemStore {
+        let mut rows = vec![];
+        for txn in txns {
+            index_transaction(codec(), txn, 1, &Sha256dHash::default(), &mut rows);
+        }
+        MemStore::new(rows.into_iter().map(Row::into_pair).collect())
+    }
+
+    // finds a script whose hash starts with `first_byte`
+    fn script(first_byte: u8, skip: usize) -> Script {
+        (0u32..)
+            .map(|i| Script::from(i.to_le_bytes().to_vec()))
+            .filter(|script| compute_script_hash(&script[..])[0] == first_byte)
+            .nth(skip)
+            .unwrap()
+    }
+
+    // finds a transaction (by tweaking its locktime) whose txid starts with `first_byte`
+    fn txn(first_byte: u8, prevout: Option<(Sha256dHash, u32)>, script: &Script) -> Transaction {
+        (0u32..)
+            .map(|lock_time| Transaction {
+                version: 1,
+                lock_time,
+                input: prevout
+                    .iter()
+                    .map(|&(txid, vout)| TxIn {
+                        previous_output: OutPoint { txid, vout },
+                        script_sig: Script::new(),
+                        sequence: 0xffffffff,
+                        witness: vec![],
+                    })
+                    .collect(),
+                output: vec![TxOut {
+                    value: 1000,
+                    script_pubkey: script.clone(),
+                }],
+            })
+            .find(|txn| txn.txid()[0] == first_byte)
+            .unwrap()
+    }
+
+    fn load(
+        store: &ReadStore,
+        prefixes: Vec<Result<HashPrefix>>,
+        txns: &[&Transaction],
+    ) -> Vec<TxnHeight> {
+        let by_txid: HashMap<Sha256dHash, &Transaction> =
+            txns.iter().map(|txn| (txn.txid(), *txn)).collect();
+        let prefixes = prefixes.into_iter().map(Result::unwrap).collect();
+        txrows_by_prefixes(store, prefixes)
+            .into_iter()
+            .map(|row| TxnHeight {
+                txn: by_txid[&deserialize(&row.key.txid).unwrap()].clone(),
+                height: row.height,
+                blockhash: row.blockhash,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_funding_collisions() {
+        let (ours, theirs) = (script(7, 0), script(7, 1));
+        let funding = txn(1, None, &ours);
+        let colliding_script = txn(2, None, &theirs);
+        let colliding_txid = txn(1, Some((funding.txid(), 0)), &theirs);
+        let txns = [&funding, &colliding_script, &colliding_txid];
+        let store = index(&txns);
+
+        let script_hash = compute_script_hash(&ours[..]);
+        let prefixes = txids_by_script_hash(&store, codec(), &script_hash);
+        let loaded = load(&store, prefixes, &txns);
+        // every transaction shares a prefix with the script hash or with the funding txid
+        assert_eq!(loaded.len(), 3);
+        let outputs: Vec<FundingOutput> = loaded
+            .iter()
+            .flat_map(|t| funding_outputs(t, &script_hash))
+            .collect();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].txn_id, funding.txid());
+    }
+
+    #[test]
+    fn test_spending_collisions() {
+        let script = script(0, 0);
+        let funding = txn(1, None, &script);
+        // same txid prefix as the funding transaction
+        let other = txn(1, Some((Sha256dHash::default(), 1)), &script);
+        assert_ne!(funding.txid(), other.txid());
+        let spender = txn(9, Some((funding.txid(), 0)), &script);
+        // spends an unrelated outpoint, but shares the spender's txid prefix
+        let other_spender = txn(9, Some((other.txid(), 0)), &script);
+        let txns = [&funding, &other, &spender, &other_spender];
+        let store = index(&txns);
+
+        let funding_output = FundingOutput::from((funding.txid(), 0));
+        let prefixes = txids_by_funding_output(&store, codec(), &funding.txid(), 0);
+        // both spenders share the same `I` row
+        assert_eq!(prefixes.len(), 1);
+        let loaded = load(&store, prefixes, &txns);
+        assert_eq!(loaded.len(), 2);
+        let inputs: Vec<_> = loaded
+            .iter()
+            .flat_map(|t| spending_inputs(t, &funding_output))
+            .collect();
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].txn_id, spender.txid());
+    }
+
+    #[test]
+    fn test_spending_rows_while_upgrading() {
+        let codec = RowCodec::new(8, Network::Regtest);
+        let funding = Sha256dHash::from_data(b"funding");
+        let spender = |data: &[u8]| Sha256dHash::from_data(data)[..8].to_vec();
+        let v1_key = |index: u16, txid: &[u8]| {
+            [
+                &b"I"[..],
+                &funding[..8],
+                &[index as u8, (index >> 8) as u8],
+                txid,
+            ]
+            .concat()
+        };
+        let key = |index: u8, txid: &[u8]| [&b"I"[..], &funding[..8], &[index], txid].concat();
+        let mut rows = BTreeMap::new();
+        rows.insert(v1_key(1, &spender(b"not converted")), vec![]);
+        rows.insert(
+            [&b"_"[..], &key(1, &spender(b"converted"))].concat(),
+            vec![],
+        );
+        rows.insert(key(1, &spender(b"moved")), vec![]);
+        // other outputs' rows, in every format
+        rows.insert(v1_key(2, &spender(b"other")), vec![]);
+        rows.insert([&b"_"[..], &key(2, &spender(b"other"))].concat(), vec![]);
+        rows.insert(key(2, &spender(b"other")), vec![]);
+        let store = MemStore::new(rows);
+
+        let mut prefixes: Vec<Bytes> =
+            txids_by_funding_output_upgrading(&store, codec, &funding, 1)
+                .iter()
+                .map(|prefix| prefix.to_vec())
+                .collect();
+        prefixes.sort();
+        let mut expected = vec![
+            spender(b"not converted"),
+            spender(b"converted"),
+            spender(b"moved"),
+        ];
+        expected.sort();
+        assert_eq!(prefixes, expected);
+        // otherwise, only the current rows are read (and the version 1 one is invalid)
+        let prefixes = txids_by_funding_output(&store, codec, &funding, 1);
+        assert_eq!(prefixes.len(), 2);
+        assert_eq!(prefixes.iter().filter(|prefix| prefix.is_ok()).count(), 1);
+    }
+
+    #[test]
+    fn test_search_by_prefix() {
+        let p2pkh = Script::from([&[0x76, 0xa9, 0x14][..], &[7; 20], &[0x88, 0xac]].concat());
+        let txns = [txn(0x12, None, &p2pkh), txn(0x34, None, &script(0, 0))];
+        let mut rows = vec![];
+        for txn in &txns {
+            index_search_rows(codec(), txn, &mut rows);
+        }
+        let store = MemStore::new(rows.into_iter().map(Row::into_pair).collect());
+
+        let txid = txns[0].txid();
+        let hex = txid.be_hex_string();
+        for len in 6..9 {
+            assert_eq!(txids_by_hex_prefix(&store, &hex[..len], 10), vec![txid]);
+        }
+        let mismatched = format!("{}{}", &hex[..6], if &hex[6..7] == "0" { "1" } else { "0" });
+        assert!(txids_by_hex_prefix(&store, &mismatched, 10).is_empty());
+        assert!(txids_by_hex_prefix(&store, "xyz", 10).is_empty());
+
+        // only the standard output has an address
+        let address = script_to_address(&p2pkh, &Network::Regtest).unwrap();
+        assert_eq!(
+            addresses_by_prefix(&store, &address[..6], 10),
+            vec![address]
+        );
+        assert_eq!(store.iter_scan(b"A").count(), 1);
+    }
+}
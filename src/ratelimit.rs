@@ -0,0 +1,256 @@
+use lru::LruCache;
+use std::collections::HashSet;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use config::Config;
+
+use errors::*;
+
+const MAX_TRACKED_CLIENTS: usize = 100_000; // least recently seen buckets are evicted beyond this
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ClientId {
+    Ip(IpAddr),
+    ApiKey(String),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9
+}
+
+impl TokenBucket {
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) {
+        let elapsed = as_secs_f64(now.duration_since(self.updated));
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.updated = now;
+    }
+}
+
+/// Per-client token buckets: each client may spend up to `burst` tokens at once, refilled at
+/// `rate` tokens per second. Clients presenting a known API key get both scaled up.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    api_keys: HashSet<String>,
+    api_key_multiplier: f64,
+    trusted_proxies: Vec<IpAddr>,
+    buckets: Mutex<LruCache<ClientId, TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Returns `None` when rate limiting is disabled (`--rate-limit 0`).
+    pub fn new(config: &Config) -> Result<Option<RateLimiter>> {
+        if config.rate_limit == 0 {
+            return Ok(None);
+        }
+        let api_keys = match config.api_keys_file {
+            Some(ref path) => fs::read_to_string(path)
+                .chain_err(|| format!("failed to read API keys from {:?}", path))?
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string())
+                .collect(),
+            None => HashSet::new(),
+        };
+        info!(
+            "rate limiting REST clients to {} req/s (burst {}), {} API keys",
+            config.rate_limit,
+            config.rate_limit_burst,
+            api_keys.len()
+        );
+        Ok(Some(RateLimiter {
+            rate: f64::from(config.rate_limit),
+            burst: f64::from(config.rate_limit_burst.max(1)),
+            api_keys,
+            api_key_multiplier: f64::from(config.api_key_rate_multiplier.max(1)),
+            trusted_proxies: config.trusted_proxies.clone(),
+            buckets: Mutex::new(LruCache::new(MAX_TRACKED_CLIENTS)),
+        }))
+    }
+
+    /// Identifies the client by its API key if it is a known one, or by its address otherwise.
+    pub fn client_id(
+        &self,
+        peer: IpAddr,
+        forwarded_for: Option<&str>,
+        api_key: Option<&str>,
+    ) -> ClientId {
+        match api_key {
+            Some(key) if self.api_keys.contains(key) => ClientId::ApiKey(key.to_string()),
+            _ => ClientId::Ip(self.client_ip(peer, forwarded_for)),
+        }
+    }
+
+    // X-Forwarded-For is walked from the right, as long as the hop that appended the entry
+    // is one of our trusted proxies. Anything further left may have been forged by the client.
+    fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let mut client = peer;
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.split(',').rev() {
+                if !self.trusted_proxies.contains(&client) {
+                    break;
+                }
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(ip) => client = ip,
+                    Err(_) => break,
+                }
+            }
+        }
+        client
+    }
+
+    fn limits(&self, client: &ClientId) -> (f64, f64) {
+        match *client {
+            ClientId::Ip(_) => (self.rate, self.burst),
+            ClientId::ApiKey(_) => (
+                self.rate * self.api_key_multiplier,
+                self.burst * self.api_key_multiplier,
+            ),
+        }
+    }
+
+    /// Takes `cost` tokens from the client's bucket, or returns how long it should wait before
+    /// retrying. Costs larger than the bucket are capped, so that every request can eventually pass.
+    pub fn acquire(&self, client: &ClientId, cost: u32) -> ::std::result::Result<(), Duration> {
+        self.acquire_at(client, cost, Instant::now())
+    }
+
+    fn acquire_at(
+        &self,
+        client: &ClientId,
+        cost: u32,
+        now: Instant,
+    ) -> ::std::result::Result<(), Duration> {
+        let (rate, burst) = self.limits(client);
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains(client) {
+            // evicts the least recently seen client's bucket when full, which is most likely
+            // full again by now (so forgetting it changes nothing)
+            buckets.put(
+                client.clone(),
+                TokenBucket {
+                    tokens: burst,
+                    updated: now,
+                },
+            );
+        }
+        let bucket = buckets.get_mut(client).unwrap();
+        bucket.refill(now, rate, burst);
+        let cost = f64::from(cost).min(burst);
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else {
+            let wait_ms = ((cost - bucket.tokens) / rate * 1000.0).ceil();
+            Err(Duration::from_millis(wait_ms as u64))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientId, RateLimiter};
+    use lru::LruCache;
+    use std::collections::HashSet;
+    use std::net::IpAddr;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    fn limiter(trusted_proxies: Vec<IpAddr>) -> RateLimiter {
+        limiter_with_capacity(trusted_proxies, 100)
+    }
+
+    fn limiter_with_capacity(trusted_proxies: Vec<IpAddr>, capacity: usize) -> RateLimiter {
+        let mut api_keys = HashSet::new();
+        api_keys.insert("secret".to_string());
+        RateLimiter {
+            rate: 2.0,
+            burst: 4.0,
+            api_keys,
+            api_key_multiplier: 10.0,
+            trusted_proxies,
+            buckets: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = limiter(vec![]);
+        let client = ClientId::Ip(ip("10.0.0.1"));
+        let now = Instant::now();
+        assert!(limiter.acquire_at(&client, 3, now).is_ok());
+        assert_eq!(
+            limiter.acquire_at(&client, 2, now),
+            Err(Duration::from_millis(500))
+        );
+        // other clients have their own bucket
+        assert!(limiter
+            .acquire_at(&ClientId::Ip(ip("10.0.0.2")), 4, now)
+            .is_ok());
+        // refilled at 2 tokens per second
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.acquire_at(&client, 2, later).is_ok());
+        // costs above the burst size are capped
+        let much_later = now + Duration::from_secs(60);
+        assert!(limiter.acquire_at(&client, 100, much_later).is_ok());
+    }
+
+    #[test]
+    fn test_tracked_clients_cap() {
+        let limiter = limiter_with_capacity(vec![], 2);
+        let clients: Vec<ClientId> = (1..4)
+            .map(|i| ClientId::Ip(ip(&format!("10.0.0.{}", i))))
+            .collect();
+        let now = Instant::now();
+        assert!(limiter.acquire_at(&clients[0], 4, now).is_ok());
+        assert!(limiter.acquire_at(&clients[1], 4, now).is_ok());
+        assert!(limiter.acquire_at(&clients[0], 1, now).is_err()); // most recently seen
+        assert!(limiter.acquire_at(&clients[2], 4, now).is_ok());
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        // the least recently seen client was forgotten, the others are still tracked
+        assert!(limiter.acquire_at(&clients[0], 1, now).is_err());
+        assert!(limiter.acquire_at(&clients[1], 4, now).is_ok());
+    }
+
+    #[test]
+    fn test_client_id() {
+        let limiter = limiter(vec![ip("127.0.0.1"), ip("10.0.0.1")]);
+        let xff = Some("1.2.3.4, 5.6.7.8, 10.0.0.1");
+
+        // untrusted peers can't spoof their address
+        assert_eq!(
+            limiter.client_id(ip("9.9.9.9"), xff, None),
+            ClientId::Ip(ip("9.9.9.9"))
+        );
+        // trusted proxies are skipped, stopping at the first untrusted hop
+        assert_eq!(
+            limiter.client_id(ip("127.0.0.1"), xff, None),
+            ClientId::Ip(ip("5.6.7.8"))
+        );
+        assert_eq!(
+            limiter.client_id(ip("127.0.0.1"), Some("garbage"), None),
+            ClientId::Ip(ip("127.0.0.1"))
+        );
+        assert_eq!(
+            limiter.client_id(ip("9.9.9.9"), None, Some("secret")),
+            ClientId::ApiKey("secret".to_string())
+        );
+        assert_eq!(
+            limiter.client_id(ip("9.9.9.9"), None, Some("wrong")),
+            ClientId::Ip(ip("9.9.9.9"))
+        );
+    }
+}
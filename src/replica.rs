@@ -0,0 +1,234 @@
+use libc::{c_char, c_int};
+use librocksdb_sys as ffi;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use store::{Direction, Row, ScanRange};
+use util::Bytes;
+
+use errors::*;
+
+/// A DB indexed by another electrs process, opened read-only (see `--read-only`).
+///
+/// It only sees the rows written before it was opened, so replicas catch up with the writer by
+/// opening it again (see `App::update`). All its table files are opened right away (RocksDB's
+/// default `max_open_files`), so that the writer's compactions can't delete them meanwhile.
+/// The `rocksdb` crate doesn't expose read-only opens, hence the C API.
+pub struct ReadOnlyDB {
+    db: *mut ffi::rocksdb_t,
+    opts: *mut ffi::rocksdb_options_t,
+    families: Vec<(&'static str, *mut ffi::rocksdb_column_family_handle_t)>,
+}
+
+// A RocksDB handle can be used from any thread, and this one is never written to.
+unsafe impl Send for ReadOnlyDB {}
+unsafe impl Sync for ReadOnlyDB {}
+
+unsafe fn take_error(err: *mut c_char) -> Option<String> {
+    if err.is_null() {
+        return None;
+    }
+    let message = CStr::from_ptr(err).to_string_lossy().into_owned();
+    ffi::rocksdb_free(err as *mut _);
+    Some(message)
+}
+
+impl ReadOnlyDB {
+    /// Opens the DB at `path` with the column families `names`, which must include "default".
+    pub fn open(path: &Path, names: &[&'static str]) -> Result<ReadOnlyDB> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .chain_err(|| format!("invalid DB path {:?}", path))?;
+        let c_names: Vec<CString> = names
+            .iter()
+            .map(|name| CString::new(*name).unwrap())
+            .collect();
+        let mut name_ptrs: Vec<*const c_char> = c_names.iter().map(|name| name.as_ptr()).collect();
+        let mut handles = vec![ptr::null_mut(); names.len()];
+        unsafe {
+            let opts = ffi::rocksdb_options_create();
+            let mut family_opts = vec![opts as *const _; names.len()];
+            let mut err = ptr::null_mut();
+            let db = ffi::rocksdb_open_for_read_only_column_families(
+                opts,
+                c_path.as_ptr(),
+                names.len() as c_int,
+                name_ptrs.as_mut_ptr(),
+                family_opts.as_mut_ptr(),
+                handles.as_mut_ptr(),
+                /*error_if_log_file_exist=*/ 0,
+                &mut err,
+            );
+            if let Some(message) = take_error(err) {
+                ffi::rocksdb_options_destroy(opts);
+                bail!("failed to open DB at {:?} read-only: {}", path, message);
+            }
+            debug!("opened DB at {:?} read-only", path);
+            Ok(ReadOnlyDB {
+                db,
+                opts,
+                families: names.iter().cloned().zip(handles).collect(),
+            })
+        }
+    }
+
+    fn family(&self, name: &str) -> *mut ffi::rocksdb_column_family_handle_t {
+        self.families
+            .iter()
+            .find(|family| family.0 == name)
+            .unwrap_or_else(|| panic!("missing column family {}", name))
+            .1
+    }
+
+    pub fn get(&self, family: &str, key: &[u8]) -> Result<Option<Bytes>> {
+        let family = self.family(family);
+        unsafe {
+            let read_opts = ffi::rocksdb_readoptions_create();
+            let mut len = 0;
+            let mut err = ptr::null_mut();
+            let value = ffi::rocksdb_get_cf(
+                self.db,
+                read_opts,
+                family,
+                key.as_ptr() as *const c_char,
+                key.len(),
+                &mut len,
+                &mut err,
+            );
+            ffi::rocksdb_readoptions_destroy(read_opts);
+            if let Some(message) = take_error(err) {
+                bail!("failed to read DB: {}", message);
+            }
+            if value.is_null() {
+                return Ok(None);
+            }
+            let bytes = slice::from_raw_parts(value as *const u8, len).to_vec();
+            ffi::rocksdb_free(value as *mut _);
+            Ok(Some(bytes))
+        }
+    }
+
+    /// Creates a checkpoint of the DB at `path`: its table files are hard-linked (or copied
+    /// across filesystems), along with the WAL files it was opened with, so that `path` can be
+    /// opened as the DB was then. Fails if the writer's compactions deleted a table file
+    /// meanwhile.
+    pub fn checkpoint(&self, path: &Path) -> Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .chain_err(|| format!("invalid checkpoint path {:?}", path))?;
+        unsafe {
+            let mut err = ptr::null_mut();
+            let checkpoint = ffi::rocksdb_checkpoint_object_create(self.db, &mut err);
+            if let Some(message) = take_error(err) {
+                bail!("failed to create checkpoint: {}", message);
+            }
+            // read-only DBs can't flush their memtables, the WAL files are copied instead
+            ffi::rocksdb_checkpoint_create(checkpoint, c_path.as_ptr(), u64::max_value(), &mut err);
+            ffi::rocksdb_checkpoint_object_destroy(checkpoint);
+            if let Some(message) = take_error(err) {
+                bail!("failed to create checkpoint at {:?}: {}", path, message);
+            }
+        }
+        Ok(())
+    }
+
+    /// The rows of `family` starting from `range`'s bound in its direction (to be clipped by
+    /// the caller), or all of them in key order. A read error ends them.
+    pub fn rows<'a>(&'a self, family: &str, range: Option<&ScanRange>) -> Rows<'a> {
+        unsafe {
+            let read_opts = ffi::rocksdb_readoptions_create();
+            let iter = ffi::rocksdb_create_iterator_cf(self.db, read_opts, self.family(family));
+            let direction = range.map_or(Direction::Forward, |range| range.direction());
+            match range.map(|range| (range.direction(), range)) {
+                None => ffi::rocksdb_iter_seek_to_first(iter),
+                Some((Direction::Forward, range)) => {
+                    let key = range.lower_bound();
+                    ffi::rocksdb_iter_seek(iter, key.as_ptr() as *const c_char, key.len())
+                }
+                Some((Direction::Reverse, range)) => match range.upper_bound() {
+                    Some(key) => ffi::rocksdb_iter_seek_for_prev(
+                        iter,
+                        key.as_ptr() as *const c_char,
+                        key.len(),
+                    ),
+                    None => ffi::rocksdb_iter_seek_to_last(iter),
+                },
+            }
+            Rows {
+                iter,
+                read_opts,
+                direction,
+                seeked: true,
+                failed: false,
+                db: PhantomData,
+            }
+        }
+    }
+}
+
+impl Drop for ReadOnlyDB {
+    fn drop(&mut self) {
+        unsafe {
+            for &(_, handle) in &self.families {
+                ffi::rocksdb_column_family_handle_destroy(handle);
+            }
+            ffi::rocksdb_close(self.db);
+            ffi::rocksdb_options_destroy(self.opts);
+        }
+    }
+}
+
+pub struct Rows<'a> {
+    iter: *mut ffi::rocksdb_iterator_t,
+    read_opts: *mut ffi::rocksdb_readoptions_t,
+    direction: Direction,
+    seeked: bool, // the first row is the one seeked to
+    failed: bool,
+    db: PhantomData<&'a ReadOnlyDB>,
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Result<Row>> {
+        if self.failed {
+            return None;
+        }
+        unsafe {
+            if self.seeked {
+                self.seeked = false;
+            } else {
+                match self.direction {
+                    Direction::Forward => ffi::rocksdb_iter_next(self.iter),
+                    Direction::Reverse => ffi::rocksdb_iter_prev(self.iter),
+                }
+            }
+            if ffi::rocksdb_iter_valid(self.iter) == 0 {
+                let mut err = ptr::null_mut();
+                ffi::rocksdb_iter_get_error(self.iter, &mut err);
+                if let Some(message) = take_error(err) {
+                    self.failed = true;
+                    return Some(Err(format!("failed to read DB: {}", message).into()));
+                }
+                return None;
+            }
+            let mut len = 0;
+            let key = ffi::rocksdb_iter_key(self.iter, &mut len);
+            let key = slice::from_raw_parts(key as *const u8, len).to_vec();
+            let value = ffi::rocksdb_iter_value(self.iter, &mut len);
+            let value = slice::from_raw_parts(value as *const u8, len).to_vec();
+            Some(Ok(Row { key, value }))
+        }
+    }
+}
+
+impl<'a> Drop for Rows<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::rocksdb_iter_destroy(self.iter);
+            ffi::rocksdb_readoptions_destroy(self.read_opts);
+        }
+    }
+}
@@ -1,31 +1,82 @@
-use bitcoin::consensus::encode::{self, serialize};
+use backup::Backups;
+use bitcoin::consensus::encode::{self, deserialize, serialize};
 use bitcoin::network::constants::Network;
 use bitcoin::util::address::Address;
 use bitcoin::util::hash::{HexError, Sha256dHash};
 use bitcoin::{BitcoinHash, Script};
 use bitcoin::{Transaction, TxIn, TxOut};
-use config::Config;
-use errors;
+use brotli::{self, enc::BrotliEncoderParams};
+use config::{Config, ListenAddr};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use error_chain::ChainedError;
+use errors::{self, ResultExt};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future;
 use hex::{self, FromHexError};
-use hyper::rt::{self, Future};
-use hyper::service::service_fn_ok;
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, CONTENT_ENCODING, CONTENT_LENGTH, ETAG, IF_NONE_MATCH, ORIGIN, VARY,
+};
+use hyper::rt::{self, Future, Stream};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Uri};
 use index::compute_script_hash;
 use mempool::MEMPOOL_HEIGHT;
-use query::{FundingOutput, Query, SpendingInput, TxnHeight};
+use native_tls;
+use query::{FundingOutput, Query, ReadContext, SpendingInput, TxnHeight};
+use ratelimit::RateLimiter;
+use rmp_serde;
 use serde::Serialize;
-use serde_json;
+use serde_cbor;
+use serde_json::{self, Value};
+use signal::Waiter;
+use status::SyncStatus;
 use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::num::ParseIntError;
+use std::path::Path;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::Duration;
+use tokio::reactor::Handle;
+use tokio::timer::Timeout;
+use tokio_tls::{TlsAcceptor, TlsStream};
+use tokio_uds::{UnixListener, UnixStream};
+use url::percent_encoding::percent_decode;
 use util::{
-    full_hash, get_script_asm, script_to_address, BlockHeaderMeta, FullHash, TransactionStatus,
+    bind_unix_socket, full_hash, get_script_asm, script_to_address, BlockHeaderMeta, FullHash,
+    HeaderEntry, TransactionStatus,
 };
+use xpub::{self, Descriptor, XpubStatus};
 
 const TX_LIMIT: usize = 25;
 const BLOCK_LIMIT: usize = 10;
+const HEADERS_LIMIT: usize = 2000; // max number of headers returned by a single range request
+const BATCH_LIMIT: usize = 50; // max number of items in a single batch lookup request
+const MAX_BODY_SIZE: usize = 64 * 1024; // batch requests are much smaller
+const MAX_TX_BODY_SIZE: usize = 256 * 1024; // hex of the largest standard transactions (100kB)
+const SEARCH_LIMIT: usize = 10; // max number of partial matches returned per result type
+const SEARCH_MIN_PREFIX_LEN: usize = 6; // shorter prefixes match too many hashes and addresses
+
+const MIN_COMPRESS_SIZE: usize = 860; // smaller bodies barely shrink and fit a single packet anyway
+const BROTLI_QUALITY: i32 = 5; // favours speed, higher levels cost much more cpu for little gain
+
+const CORS_ALLOW_HEADERS: &str = "Content-Type, If-None-Match, X-API-Key";
+const CORS_EXPOSE_HEADERS: &str = "ETag, Retry-After";
+const CORS_MAX_AGE: &str = "86400"; // seconds browsers may cache preflight results
+
+const TLS_CONCURRENT_HANDSHAKES: usize = 100; // pending handshakes before accepting new connections stalls
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10); // so that idle clients can't stall it
+
+const PROBE_PATHS: [&str; 3] = ["/health", "/ready", "/sync"];
 
 const TTL_LONG: u32 = 157784630; // ttl for static resources (5 years)
 const TTL_SHORT: u32 = 10; // ttl for volatie resources
@@ -217,8 +268,10 @@ struct UtxoValue {
     value: u64,
     status: TransactionStatus,
 }
-impl From<FundingOutput> for UtxoValue {
-    fn from(out: FundingOutput) -> Self {
+impl TryFrom<FundingOutput> for UtxoValue {
+    type Error = HttpError;
+
+    fn try_from(out: FundingOutput) -> Result<Self, HttpError> {
         let FundingOutput {
             txn,
             txn_id,
@@ -228,9 +281,9 @@ impl From<FundingOutput> for UtxoValue {
         } = out;
         let TxnHeight {
             height, blockhash, ..
-        } = txn.unwrap(); // we should never get a FundingOutput without a txn here
+        } = txn.ok_or_else(|| missing_txn(&txn_id))?;
 
-        UtxoValue {
+        Ok(UtxoValue {
             txid: txn_id,
             vout: output_index as u32,
             value: value,
@@ -243,7 +296,7 @@ impl From<FundingOutput> for UtxoValue {
             } else {
                 TransactionStatus::unconfirmed()
             },
-        }
+        })
     }
 }
 
@@ -254,8 +307,10 @@ struct SpendingValue {
     vin: Option<u32>,
     status: Option<TransactionStatus>,
 }
-impl From<SpendingInput> for SpendingValue {
-    fn from(spend: SpendingInput) -> Self {
+impl TryFrom<SpendingInput> for SpendingValue {
+    type Error = HttpError;
+
+    fn try_from(spend: SpendingInput) -> Result<Self, HttpError> {
         let SpendingInput {
             txn,
             txn_id,
@@ -264,9 +319,9 @@ impl From<SpendingInput> for SpendingValue {
         } = spend;
         let TxnHeight {
             height, blockhash, ..
-        } = txn.unwrap(); // we should never get a SpendingInput without a txn here
+        } = txn.ok_or_else(|| missing_txn(&txn_id))?;
 
-        SpendingValue {
+        Ok(SpendingValue {
             spent: true,
             txid: Some(txn_id),
             vin: Some(input_index as u32),
@@ -279,9 +334,15 @@ impl From<SpendingInput> for SpendingValue {
             } else {
                 TransactionStatus::unconfirmed()
             }),
-        }
+        })
     }
 }
+
+// funding outputs and spending inputs returned by the queries always come with their txn
+fn missing_txn(txid: &Sha256dHash) -> HttpError {
+    warn!("missing txn data for {}", txid);
+    HttpError::generic()
+}
 impl Default for SpendingValue {
     fn default() -> Self {
         SpendingValue {
@@ -293,9 +354,229 @@ impl Default for SpendingValue {
     }
 }
 
-fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
+/// Serialization format of structured responses, negotiated through the `Accept` header.
+/// All formats share the same serde types, JSON remains the default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentType {
+    Json,
+    Cbor,
+    MsgPack,
+}
+
+impl ContentType {
+    // q-values are not honoured, the first supported media type listed wins
+    fn from_accept(accept: Option<&str>) -> ContentType {
+        accept
+            .and_then(|accept| {
+                accept
+                    .split(',')
+                    .filter_map(|media| match media.split(';').next().unwrap_or("").trim() {
+                        "application/json" => Some(ContentType::Json),
+                        "application/cbor" => Some(ContentType::Cbor),
+                        "application/msgpack" | "application/x-msgpack" => {
+                            Some(ContentType::MsgPack)
+                        }
+                        _ => None,
+                    })
+                    .next()
+            })
+            .unwrap_or(ContentType::Json)
+    }
+
+    fn mime(&self) -> &'static str {
+        match *self {
+            ContentType::Json => "application/json",
+            ContentType::Cbor => "application/cbor",
+            ContentType::MsgPack => "application/msgpack",
+        }
+    }
+}
+
+/// Compression applied to response bodies, negotiated through the `Accept-Encoding` header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentEncoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl ContentEncoding {
+    // brotli is preferred over gzip whenever both are acceptable
+    fn from_accept_encoding(accept_encoding: Option<&str>) -> ContentEncoding {
+        let accepted: Vec<&str> = accept_encoding.map_or(vec![], |accept_encoding| {
+            accept_encoding
+                .split(',')
+                .filter_map(|item| {
+                    let mut params = item.split(';');
+                    let coding = params.next()?.trim();
+                    let rejected = params.any(|param| {
+                        let param = param.trim();
+                        param.starts_with("q=") && param[2..].parse::<f32>().ok() == Some(0.0)
+                    });
+                    if rejected {
+                        None
+                    } else {
+                        Some(coding)
+                    }
+                })
+                .collect()
+        });
+        if accepted.contains(&"br") {
+            ContentEncoding::Brotli
+        } else if accepted.contains(&"gzip") {
+            ContentEncoding::Gzip
+        } else {
+            ContentEncoding::Identity
+        }
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        match *self {
+            ContentEncoding::Identity => None,
+            ContentEncoding::Gzip => Some("gzip"),
+            ContentEncoding::Brotli => Some("br"),
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            ContentEncoding::Identity => Ok(data.to_vec()),
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            ContentEncoding::Brotli => {
+                let mut params = BrotliEncoderParams::default();
+                params.quality = BROTLI_QUALITY;
+                let mut encoded = Vec::new();
+                brotli::BrotliCompress(&mut &data[..], &mut encoded, &params)?;
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+/// Digest of an uncompressed response body, the validator of responses which depend on more
+/// than the best chain (e.g. on the mempool).
+fn body_digest(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result_str()[..32].to_string()
+}
+
+/// Endpoints whose responses only change with the best chain get a validator derived from the
+/// request and the tip of the snapshot serving it, so that conditional requests are answered
+/// before any lookup. The other transaction endpoints also depend on the mempool (e.g. a txn
+/// being evicted), their validator is a digest of the response instead.
+fn tip_digest(
+    method: &Method,
+    path: &str,
+    content_type: ContentType,
+    ctx: &ReadContext,
+) -> Option<String> {
+    let segments: Vec<&str> = path.split('/').skip(1).collect();
+    let cacheable = match (method, segments.first(), segments.get(2)) {
+        (&Method::GET, Some(&"block"), _)
+        | (&Method::GET, Some(&"block-height"), _)
+        | (&Method::GET, Some(&"blocks"), _) => true,
+        (&Method::GET, Some(&"tx"), Some(&"merkle-proof")) => true,
+        _ => false,
+    };
+    if !cacheable {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    hasher.input_str(&format!(
+        "{}\n{:?}\n{}",
+        path,
+        content_type,
+        ctx.snapshot().tip()
+    ));
+    Some(hasher.result_str()[..32].to_string())
+}
+
+/// Strong validator for a representation: a digest identifying its content, tagged with the
+/// content coding since each encoding is a distinct sequence of bytes.
+fn make_etag(digest: &str, encoding: ContentEncoding) -> String {
+    match encoding.name() {
+        Some(name) => format!("\"{}-{}\"", digest, name),
+        None => format!("\"{}\"", digest),
+    }
+}
+
+// If-None-Match uses the weak comparison function (RFC 7232, section 3.2)
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Returns the requested ETag with this digest, whatever its content coding.
+fn matching_etag(if_none_match: &str, digest: &str) -> Option<String> {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .find(|tag| {
+            let tag = tag.trim_matches('"');
+            tag == digest || tag.starts_with(&format!("{}-", digest))
+        })
+        .map(|tag| tag.to_string())
+}
+
+fn not_modified(etag: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(VARY, "Accept-Encoding")
+        .header(ETAG, etag)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Attaches an ETag to successful responses, answers matching conditional requests with
+/// 304 Not Modified and compresses the remaining bodies.
+fn encode_response(
+    mut response: Response<Vec<u8>>,
+    accept_encoding: Option<&str>,
+    if_none_match: Option<&str>,
+    digest: Option<String>,
+) -> Response<Body> {
+    if response.status() != StatusCode::OK {
+        return response.map(Body::from);
+    }
+    let mut encoding = if response.body().len() >= MIN_COMPRESS_SIZE {
+        ContentEncoding::from_accept_encoding(accept_encoding)
+    } else {
+        ContentEncoding::Identity
+    };
+    let encoded = encoding.encode(response.body()).unwrap_or_else(|e| {
+        warn!("failed to encode response with {:?}: {}", encoding, e);
+        encoding = ContentEncoding::Identity;
+        response.body().clone()
+    });
+    let digest = digest.unwrap_or_else(|| body_digest(response.body()));
+    let etag = make_etag(&digest, encoding);
+
+    {
+        let headers = response.headers_mut();
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+        headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+        if let Some(name) = encoding.name() {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(name));
+        }
+    }
+    if if_none_match.map_or(false, |tags| etag_matches(tags, &etag)) {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response.headers_mut().remove(CONTENT_ENCODING);
+        return response.map(|_| Body::empty());
+    }
+    response.map(|_| Body::from(encoded))
+}
+
+fn ttl_by_depth(height: Option<usize>, ctx: &ReadContext) -> u32 {
     height.map_or(TTL_SHORT, |height| {
-        if query.get_best_height() - height >= CONF_FINAL {
+        if ctx.snapshot().height().saturating_sub(height) >= CONF_FINAL {
             TTL_LONG
         } else {
             TTL_SHORT
@@ -303,13 +584,23 @@ fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     })
 }
 
-fn attach_tx_data(tx: TransactionValue, config: &Config, query: &Arc<Query>) -> TransactionValue {
+fn attach_tx_data(
+    tx: TransactionValue,
+    config: &Config,
+    query: &Arc<Query>,
+    ctx: &ReadContext,
+) -> Result<TransactionValue, HttpError> {
     let mut txs = vec![tx];
-    attach_txs_data(&mut txs, config, query);
-    txs.remove(0)
+    attach_txs_data(&mut txs, config, query, ctx)?;
+    Ok(txs.remove(0))
 }
 
-fn attach_txs_data(txs: &mut Vec<TransactionValue>, config: &Config, query: &Arc<Query>) {
+fn attach_txs_data(
+    txs: &mut Vec<TransactionValue>,
+    config: &Config,
+    query: &Arc<Query>,
+    ctx: &ReadContext,
+) -> Result<(), HttpError> {
     {
         // a map of prev txids/vouts to lookup, with a reference to the "next in" that spends them
         let mut lookups: BTreeMap<Sha256dHash, Vec<(u32, &mut TxInValue)>> = BTreeMap::new();
@@ -339,10 +630,22 @@ fn attach_txs_data(txs: &mut Vec<TransactionValue>, config: &Config, query: &Arc
         // fetch prevtxs and attach prevouts to nextins
         if config.prevout_enabled {
             for (prev_txid, prev_vouts) in lookups {
-                let prevtx = query.load_txn(&prev_txid, None).unwrap();
+                let prevtx = query.load_txn(ctx, &prev_txid, None).map_err(|e| {
+                    let (error, missing) = prevtx_error(e);
+                    if missing {
+                        warn!("missing prevtx {}", prev_txid);
+                        query.count_inconsistency("missing_prevtx");
+                    }
+                    error
+                })?;
                 for (prev_out_idx, ref mut nextin) in prev_vouts {
-                    let mut prevout =
-                        TxOutValue::from(prevtx.output[prev_out_idx as usize].clone());
+                    let prev_output =
+                        prevtx.output.get(prev_out_idx as usize).ok_or_else(|| {
+                            warn!("missing prevout {}:{}", prev_txid, prev_out_idx);
+                            query.count_inconsistency("missing_prevout");
+                            HttpError::generic()
+                        })?;
+                    let mut prevout = TxOutValue::from(prev_output.clone());
                     prevout.scriptpubkey_address =
                         script_to_address(&prevout.scriptpubkey, &config.network_type);
                     nextin.prevout = Some(prevout);
@@ -361,113 +664,666 @@ fn attach_txs_data(txs: &mut Vec<TransactionValue>, config: &Config, query: &Arc
             let total_in: u64 = tx
                 .vin
                 .iter()
-                .map(|vin| vin.clone().prevout.unwrap().value)
+                .filter_map(|vin| vin.prevout.as_ref().map(|prevout| prevout.value))
                 .sum();
             let total_out: u64 = tx.vout.iter().map(|vout| vout.value).sum();
-            tx.fee = Some(total_in - total_out);
+            tx.fee = total_in.checked_sub(total_out);
+            if tx.fee.is_none() {
+                warn!("tx {} spends more than its inputs", tx.txid);
+                query.count_inconsistency("negative_fee");
+            }
+        }
+    }
+    Ok(())
+}
+
+// Only a previous transaction missing from the index (or the daemon) is an inconsistency, other
+// daemon errors make the daemon unavailable for this request. Returns whether it was missing.
+fn prevtx_error(e: errors::Error) -> (HttpError, bool) {
+    match *e.kind() {
+        errors::ErrorKind::TxNotFound(_) | errors::ErrorKind::RpcError(_, -5, _) => {
+            return (HttpError::generic(), true)
+        }
+        _ => (),
+    }
+    let error = HttpError::from(e);
+    match error.code {
+        ErrorCode::DaemonError => (
+            HttpError::new(ErrorCode::DaemonUnavailable, error.message),
+            false,
+        ),
+        _ => (error, false),
+    }
+}
+
+/// State shared by all the connections of the REST server.
+struct ServerState {
+    status: Arc<SyncStatus>,
+    config: Config,
+    rate_limiter: Option<RateLimiter>,
+    response_headers: ResponseHeaders,
+    admin_token: Option<String>,
+    backups: Option<Backups>,
+}
+
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Starts listening right away: health checks are answered during the initial sync, and the
+/// query API once `status` has a `Query` to serve it with.
+pub fn run_server(
+    config: &Config,
+    status: Arc<SyncStatus>,
+    signal: &Waiter,
+) -> Result<(), errors::Error> {
+    let state = Arc::new(ServerState {
+        status,
+        config: config.clone(),
+        rate_limiter: RateLimiter::new(config)?,
+        response_headers: ResponseHeaders::new(config)?,
+        admin_token: match config.admin_token_file {
+            Some(ref path) => Some(read_admin_token(path)?),
+            None => None,
+        },
+        backups: config.backup_dir.as_ref().map(|dir| Backups::new(dir)),
+    });
+
+    match (&config.http_addr, &config.tls_cert, &config.tls_key) {
+        (&ListenAddr::Tcp(addr), &Some(ref cert_path), &Some(ref key_path)) => {
+            serve_tls(state, addr, cert_path, key_path, signal)
         }
+        (&ListenAddr::Tcp(addr), &None, &None) => serve_tcp(state, addr),
+        (&ListenAddr::Unix(ref path), &None, &None) => {
+            serve_unix(state, path, config.unix_socket_mode)
+        }
+        (&ListenAddr::Unix(_), _, _) => {
+            bail!("TLS is not supported on Unix sockets, terminate it in the proxy instead")
+        }
+        _ => bail!("both --tls-cert and --tls-key are required to enable TLS"),
     }
 }
 
-pub fn run_server(config: &Config, query: Arc<Query>) {
-    let addr = &config.http_addr;
+fn serve_tcp(state: Arc<ServerState>, addr: SocketAddr) -> Result<(), errors::Error> {
+    let server = Server::try_bind(&addr)
+        .chain_err(|| format!("failed to bind REST server on {}", addr))?
+        .serve(make_service_fn(move |socket: &AddrStream| {
+            let state = state.clone();
+            let remote_ip = socket.remote_addr().ip();
+            service_fn(move |req| serve_request(&state, remote_ip, req))
+        }))
+        .map_err(|e| eprintln!("server error: {}", e));
     info!("REST server running on {}", addr);
+    thread::spawn(move || rt::run(server));
+    Ok(())
+}
 
-    let config = Arc::new(config.clone());
-
-    let new_service = move || {
-        let query = query.clone();
-        let config = config.clone();
-
-        service_fn_ok(
-            move |req: Request<Body>| match handle_request(req, &query, &config) {
-                Ok(response) => response,
-                Err(e) => {
-                    warn!("{:?}", e);
-                    Response::builder()
-                        .status(e.0)
-                        .header("Content-Type", "text/plain")
-                        .body(Body::from(e.1))
-                        .unwrap()
+fn serve_tls(
+    state: Arc<ServerState>,
+    addr: SocketAddr,
+    cert_path: &Path,
+    key_path: &Path,
+    signal: &Waiter,
+) -> Result<(), errors::Error> {
+    let acceptor = Arc::new(RwLock::new(load_tls_acceptor(cert_path, key_path)?));
+    {
+        // certificates are re-read on SIGHUP, existing connections keep the old ones
+        let acceptor = acceptor.clone();
+        let (cert_path, key_path) = (cert_path.to_path_buf(), key_path.to_path_buf());
+        signal.on_reload(Box::new(move || {
+            match load_tls_acceptor(&cert_path, &key_path) {
+                Ok(reloaded) => {
+                    *acceptor.write().unwrap() = reloaded;
+                    info!("reloaded TLS certificate from {:?}", cert_path);
                 }
-            },
-        )
-    };
+                Err(e) => warn!("failed to reload TLS certificate: {}", e.display_chain()),
+            }
+        }));
+    }
+    let incoming = AddrIncoming::bind(&addr)
+        .chain_err(|| format!("failed to bind REST server on {}", addr))?
+        .map(move |socket| {
+            let acceptor = acceptor.read().unwrap().clone();
+            // a failed (or stalled) handshake only drops that connection, not the whole listener
+            Timeout::new(acceptor.accept(socket), TLS_HANDSHAKE_TIMEOUT).then(|result| {
+                match result {
+                    Err(ref e) if e.is_elapsed() => debug!("TLS handshake timed out"),
+                    Err(ref e) => debug!("TLS handshake failed: {}", e),
+                    Ok(_) => (),
+                }
+                Ok::<_, io::Error>(result.ok())
+            })
+        })
+        .buffer_unordered(TLS_CONCURRENT_HANDSHAKES)
+        .filter_map(|stream| stream);
+    let server = Server::builder(incoming)
+        .serve(make_service_fn(move |stream: &TlsStream<AddrStream>| {
+            let state = state.clone();
+            let remote_ip = stream.get_ref().get_ref().remote_addr().ip();
+            service_fn(move |req| serve_request(&state, remote_ip, req))
+        }))
+        .map_err(|e| eprintln!("server error: {}", e));
+    info!("REST server running on https://{}", addr);
+    thread::spawn(move || rt::run(server));
+    Ok(())
+}
 
-    let server = Server::bind(&addr)
-        .serve(new_service)
+fn serve_unix(state: Arc<ServerState>, path: &Path, mode: u32) -> Result<(), errors::Error> {
+    let listener = bind_unix_socket(path, mode)?;
+    let listener = UnixListener::from_std(listener, &Handle::default())
+        .chain_err(|| format!("failed to register Unix socket {:?}", path))?;
+    let incoming = listener
+        .incoming()
+        .then(|result| {
+            if let Err(ref e) = result {
+                warn!("failed to accept Unix socket connection: {}", e);
+            }
+            Ok::<_, io::Error>(result.ok())
+        })
+        .filter_map(|stream| stream);
+    let server = Server::builder(incoming)
+        .serve(make_service_fn(move |_: &UnixStream| {
+            let state = state.clone();
+            // only a local reverse proxy can connect, the client address comes from
+            // X-Forwarded-For if 127.0.0.1 is listed as a trusted proxy.
+            let remote_ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+            service_fn(move |req| serve_request(&state, remote_ip, req))
+        }))
         .map_err(|e| eprintln!("server error: {}", e));
+    info!("REST server running on unix:{}", path.display());
+    thread::spawn(move || rt::run(server));
+    Ok(())
+}
 
-    thread::spawn(move || {
-        rt::run(server);
-    });
+fn read_admin_token(path: &Path) -> Result<String, errors::Error> {
+    let token = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read admin token from {:?}", path))?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        bail!("empty admin token in {:?}", path);
+    }
+    Ok(token)
 }
 
-fn handle_request(
+/// Loads a PEM certificate (chain) and its PKCS#8 PEM private key.
+fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, errors::Error> {
+    let cert = fs::read(cert_path).chain_err(|| format!("failed to read {:?}", cert_path))?;
+    let key = fs::read(key_path).chain_err(|| format!("failed to read {:?}", key_path))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+        .chain_err(|| "invalid TLS certificate or key")?;
+    let acceptor =
+        native_tls::TlsAcceptor::new(identity).chain_err(|| "failed to create TLS acceptor")?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+fn serve_request(
+    state: &Arc<ServerState>,
+    remote_ip: IpAddr,
     req: Request<Body>,
+) -> ResponseFuture {
+    let (parts, body) = req.into_parts();
+    let origin = parts
+        .headers
+        .get(ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let preflight = parts.method == Method::OPTIONS;
+    let probe = parts.method == Method::GET && PROBE_PATHS.contains(&parts.uri.path());
+    let admin = parts.uri.path().starts_with("/admin/");
+    let admin_token = parts
+        .headers
+        .get("X-Admin-Token")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    // health checks are never rate limited, so that orchestrators don't mistake a busy server
+    // for a dead one.
+    if let (false, &Some(ref rate_limiter)) = (probe, &state.rate_limiter) {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        let client =
+            rate_limiter.client_id(remote_ip, header("X-Forwarded-For"), header("X-API-Key"));
+        let path: Vec<&str> = parts.uri.path().split('/').skip(1).collect();
+        if let Err(retry_after) = rate_limiter.acquire(&client, request_cost(&parts.method, &path))
+        {
+            debug!("rate limited {:?} for {:?}", client, retry_after);
+            let mut response = too_many_requests(retry_after);
+            state.response_headers.apply(
+                response.headers_mut(),
+                origin.as_ref().map(String::as_str),
+                false,
+            );
+            return Box::new(future::ok(response));
+        }
+    }
+
+    let content_type = ContentType::from_accept(
+        parts
+            .headers
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok()),
+    );
+
+    let accept_encoding = parts
+        .headers
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+    let if_none_match = parts
+        .headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    // the request body is only needed by the POST endpoints, and is collected beforehand so
+    // that routing can remain synchronous.
+    let max_body_size = match parts.uri.path() {
+        "/tx" => MAX_TX_BODY_SIZE,
+        _ => MAX_BODY_SIZE,
+    };
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    let body: BodyFuture = if parts.method != Method::POST {
+        Box::new(future::ok(Ok(vec![])))
+    } else if content_length.map_or(false, |len| len > max_body_size) {
+        Box::new(future::ok(Err(payload_too_large(max_body_size))))
+    } else {
+        read_body(body, max_body_size)
+    };
+    let state = state.clone();
+    Box::new(body.and_then(move |body| {
+        let mut digest = None;
+        let result = if let Err(e) = body {
+            Err(e)
+        } else if preflight {
+            // answered before the index is ready too, so that browsers can poll /sync
+            Ok(preflight_response())
+        } else if probe {
+            probe_request(parts.uri.path(), content_type, &state.status)
+        } else if admin {
+            admin_request(
+                &parts.method,
+                parts.uri.path(),
+                admin_token.as_ref().map(String::as_str),
+                content_type,
+                &state,
+            )
+        } else {
+            match state.status.query() {
+                Some(query) => {
+                    let ctx = query.read_context();
+                    digest = tip_digest(&parts.method, parts.uri.path(), content_type, &ctx);
+                    let etag = digest.as_ref().and_then(|digest| {
+                        if_none_match
+                            .as_ref()
+                            .and_then(|tags| matching_etag(tags, digest))
+                    });
+                    match etag {
+                        Some(etag) => Ok(not_modified(&etag)),
+                        None => handle_request(
+                            parts.method,
+                            parts.uri,
+                            &body.unwrap(),
+                            content_type,
+                            &query,
+                            &ctx,
+                            &state.config,
+                        ),
+                    }
+                }
+                None => Err(HttpError::new(
+                    ErrorCode::NotReady,
+                    "Index is not ready yet, see /sync for progress".to_string(),
+                )),
+            }
+        };
+        let mut response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("{:?}", e);
+                e.into_response()
+            }
+        };
+        state.response_headers.apply(
+            response.headers_mut(),
+            origin.as_ref().map(String::as_str),
+            preflight,
+        );
+        let (parts, body) = response.into_parts();
+        body.concat2().map(move |body| {
+            encode_response(
+                Response::from_parts(parts, body.to_vec()),
+                accept_encoding.as_ref().map(String::as_str),
+                if_none_match.as_ref().map(String::as_str),
+                digest,
+            )
+        })
+    }))
+}
+
+// CORS preflight, the Access-Control-* headers are attached by ResponseHeaders
+fn preflight_response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Headers added to every response: CORS headers for the configured origins, and any
+/// custom headers set with `--http-header`.
+struct ResponseHeaders {
+    cors_origins: Vec<String>,
+    cors_methods: HeaderValue,
+    custom: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ResponseHeaders {
+    fn new(config: &Config) -> Result<ResponseHeaders, errors::Error> {
+        let cors_methods = HeaderValue::from_str(&config.cors_methods)
+            .chain_err(|| format!("invalid CORS methods: {:?}", config.cors_methods))?;
+        let custom = config
+            .http_headers
+            .iter()
+            .map(|&(ref name, ref value)| {
+                Ok((
+                    HeaderName::from_bytes(name.as_bytes())
+                        .chain_err(|| format!("invalid header name: {:?}", name))?,
+                    HeaderValue::from_str(value)
+                        .chain_err(|| format!("invalid value for header {}: {:?}", name, value))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, errors::Error>>()?;
+        Ok(ResponseHeaders {
+            cors_origins: config.cors_origins.clone(),
+            cors_methods,
+            custom,
+        })
+    }
+
+    // a wildcard allows every origin, otherwise the request origin is echoed back if listed
+    fn allowed_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.cors_origins.iter().any(|allowed| allowed == "*") {
+            Some("*".to_string())
+        } else {
+            origin
+                .filter(|origin| self.cors_origins.iter().any(|allowed| allowed == origin))
+                .map(|origin| origin.to_string())
+        }
+    }
+
+    fn apply(&self, headers: &mut HeaderMap, origin: Option<&str>, preflight: bool) {
+        for &(ref name, ref value) in &self.custom {
+            headers.insert(name.clone(), value.clone());
+        }
+        if self.cors_origins.is_empty() {
+            return;
+        }
+        if !self.cors_origins.iter().any(|allowed| allowed == "*") {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        let allowed_origin = match self
+            .allowed_origin(origin)
+            .and_then(|origin| HeaderValue::from_str(&origin).ok())
+        {
+            Some(allowed_origin) => allowed_origin,
+            None => return,
+        };
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allowed_origin);
+        if preflight {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, self.cors_methods.clone());
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static(CORS_ALLOW_HEADERS),
+            );
+            headers.insert(
+                ACCESS_CONTROL_MAX_AGE,
+                HeaderValue::from_static(CORS_MAX_AGE),
+            );
+        } else {
+            headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(CORS_EXPOSE_HEADERS),
+            );
+        }
+    }
+}
+
+/// Number of rate limiting tokens spent by a request, roughly following the amount of
+/// index lookups (and daemon calls in light mode) needed to serve it.
+fn request_cost(method: &Method, path: &[&str]) -> u32 {
+    match (method, path.get(0), path.get(2)) {
+        (&Method::POST, _, _) => 10, // batch lookups
+        (_, Some(&"xpub"), _) => 20,
+        (_, Some(&"address"), Some(&"txs"))
+        | (_, Some(&"scripthash"), Some(&"txs"))
+        | (_, Some(&"address"), Some(&"utxo"))
+        | (_, Some(&"scripthash"), Some(&"utxo")) => 5,
+        (_, Some(&"block"), Some(&"txs")) | (_, Some(&"block"), Some(&"raw")) => 5,
+        (_, Some(&"address"), None) | (_, Some(&"scripthash"), None) => 2,
+        (_, Some(&"search"), _) => 2,
+        _ => 1,
+    }
+}
+
+type BodyFuture = Box<Future<Item = Result<Vec<u8>, HttpError>, Error = hyper::Error> + Send>;
+
+enum BodyError {
+    TooLarge,
+    Hyper(hyper::Error),
+}
+
+// Stops reading as soon as the body exceeds `max_size`, which a chunked body doesn't announce.
+fn read_body(body: Body, max_size: usize) -> BodyFuture {
+    Box::new(
+        body.map_err(BodyError::Hyper)
+            .fold(vec![], move |mut body, chunk| {
+                if body.len() + chunk.len() > max_size {
+                    return Err(BodyError::TooLarge);
+                }
+                body.extend_from_slice(&chunk);
+                Ok(body)
+            })
+            .then(move |result| match result {
+                Ok(body) => Ok(Ok(body)),
+                Err(BodyError::TooLarge) => Ok(Err(payload_too_large(max_size))),
+                Err(BodyError::Hyper(e)) => Err(e),
+            }),
+    )
+}
+
+fn payload_too_large(max_size: usize) -> HttpError {
+    HttpError::new(
+        ErrorCode::PayloadTooLarge,
+        format!("Request body is larger than {} bytes", max_size),
+    )
+}
+
+fn too_many_requests(retry_after: Duration) -> Response<Body> {
+    // Retry-After only has a resolution of seconds, round up so clients don't retry too early
+    let secs = retry_after.as_secs() + if retry_after.subsec_nanos() > 0 { 1 } else { 0 };
+    let mut response =
+        HttpError::new(ErrorCode::RateLimited, "Too many requests".to_string()).into_response();
+    response
+        .headers_mut()
+        .insert("Retry-After", HeaderValue::from(secs));
+    response
+}
+
+// Liveness, readiness and sync progress, available before the index is ready
+fn probe_request(
+    path: &str,
+    content_type: ContentType,
+    status: &SyncStatus,
+) -> Result<Response<Body>, HttpError> {
+    match path {
+        "/health" => http_message(StatusCode::OK, "OK".to_string(), 0),
+        "/ready" => {
+            if status.info().ready {
+                http_message(StatusCode::OK, "ready".to_string(), 0)
+            } else {
+                http_message(StatusCode::SERVICE_UNAVAILABLE, "not ready".to_string(), 0)
+            }
+        }
+        "/sync" => data_response(content_type, status.info(), 0),
+        _ => Err(HttpError::not_found(format!(
+            "endpoint does not exist {:?}",
+            path
+        ))),
+    }
+}
+
+// Operator endpoints, only available with --admin-token-file
+fn admin_request(
+    method: &Method,
+    path: &str,
+    token: Option<&str>,
+    content_type: ContentType,
+    state: &ServerState,
+) -> Result<Response<Body>, HttpError> {
+    check_admin_token(state.admin_token.as_ref().map(String::as_str), token)?;
+    match (method, path) {
+        (&Method::GET, "/admin/backup") | (&Method::POST, "/admin/backup") => {
+            let backups = state.backups.as_ref().ok_or_else(|| {
+                HttpError::not_found("backups are disabled, see --backup-dir".to_string())
+            })?;
+            if method == Method::GET {
+                return data_response(content_type, backups.status(), 0);
+            }
+            let query = state.status.query().ok_or_else(|| {
+                HttpError::new(ErrorCode::NotReady, "Index is not ready yet".to_string())
+            })?;
+            let mut response = data_response(content_type, backups.start(&query)?, 0)?;
+            *response.status_mut() = StatusCode::ACCEPTED;
+            Ok(response)
+        }
+        _ => Err(HttpError::not_found(format!(
+            "endpoint does not exist {:?}",
+            path
+        ))),
+    }
+}
+
+fn check_admin_token(expected: Option<&str>, token: Option<&str>) -> Result<(), HttpError> {
+    let expected = match expected {
+        Some(expected) => expected,
+        None => {
+            return Err(HttpError::not_found(
+                "admin endpoints are disabled".to_string(),
+            ))
+        }
+    };
+    // compares every byte, so that the response time doesn't tell how much of the token matched
+    let token = token.unwrap_or("");
+    let diff = expected
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    if diff != 0 || expected.len() != token.len() {
+        return Err(HttpError::new(
+            ErrorCode::Unauthorized,
+            "missing or invalid X-Admin-Token".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn handle_request(
+    method: Method,
+    uri: Uri,
+    body: &[u8],
+    content_type: ContentType,
     query: &Arc<Query>,
+    ctx: &ReadContext,
     config: &Config,
 ) -> Result<Response<Body>, HttpError> {
     // TODO it looks hyper does not have routing and query parsing :(
-    let uri = req.uri();
     let path: Vec<&str> = uri.path().split('/').skip(1).collect();
     info!("path {:?}", path);
-    match (
-        req.method(),
-        path.get(0),
-        path.get(1),
-        path.get(2),
-        path.get(3),
-    ) {
+    match (&method, path.get(0), path.get(1), path.get(2), path.get(3)) {
         (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None) => http_message(
             StatusCode::OK,
-            query.get_best_header_hash().be_hex_string(),
+            query.get_best_header_hash(ctx).be_hex_string(),
             TTL_SHORT,
         ),
 
         (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"height"), None) => http_message(
             StatusCode::OK,
-            query.get_best_height().to_string(),
+            query.get_best_height(ctx).to_string(),
             TTL_SHORT,
         ),
 
+        (&Method::GET, Some(&"blocks"), Some(&"headers"), Some(start), Some(count)) => {
+            let start = start.parse::<usize>()?;
+            let count = count.parse::<usize>()?;
+            if count == 0 || count > HEADERS_LIMIT {
+                bail!(HttpError::from(format!(
+                    "count must be between 1 and {}",
+                    HEADERS_LIMIT
+                )));
+            }
+            let heights: Vec<usize> = (start..start.saturating_add(count)).collect();
+            let headers = query.get_headers(ctx, &heights);
+            let last = headers
+                .last()
+                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let ttl = ttl_by_depth(Some(last.height()), ctx);
+            let mut raw = Vec::with_capacity(headers.len() * 80);
+            for entry in &headers {
+                raw.extend(serialize(entry.header()));
+            }
+            binary_response(raw, ttl)
+        }
         (&Method::GET, Some(&"blocks"), start_height, None, None) => {
             let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
-            blocks(&query, start_height)
+            blocks(query, ctx, start_height, content_type)
         }
         (&Method::GET, Some(&"block-height"), Some(height), None, None) => {
             let height = height.parse::<usize>()?;
-            let headers = query.get_headers(&[height]);
+            let headers = query.get_headers(ctx, &[height]);
             let header = headers
                 .get(0)
                 .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
-            let ttl = ttl_by_depth(Some(height), query);
+            let ttl = ttl_by_depth(Some(height), ctx);
             http_message(StatusCode::OK, header.hash().be_hex_string(), ttl)
         }
         (&Method::GET, Some(&"block"), Some(hash), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let blockhm = query.get_block_header_with_meta(&hash)?;
+            let blockhm = query.get_block_header_with_meta(ctx, &hash)?;
             let block_value = BlockValue::from(blockhm);
-            json_response(block_value, TTL_LONG)
+            data_response(content_type, block_value, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"status"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let status = query.get_block_status(&hash);
-            let ttl = ttl_by_depth(status.height, query);
-            json_response(status, ttl)
+            let status = query.get_block_status(ctx, &hash);
+            let ttl = ttl_by_depth(status.height, ctx);
+            data_response(content_type, status, ttl)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"header"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let header = query.get_header_by_hash(ctx, &hash)?;
+            let ttl = ttl_by_depth(Some(header.height()), ctx);
+            http_message(StatusCode::OK, hex::encode(serialize(header.header())), ttl)
+        }
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"raw"), None) => {
+            let hash = Sha256dHash::from_hex(hash)?;
+            let status = query.get_block_status(ctx, &hash);
+            let raw = query.get_block_raw(&hash)?;
+            binary_response(raw, ttl_by_depth(status.height, ctx))
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txids"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let txids = query
-                .get_block_txids(&hash)
-                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
-            json_response(txids, TTL_LONG)
+            let txids = query.get_block_txids(ctx, &hash)?;
+            data_response(content_type, txids, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txs"), start_index) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let txids = query
-                .get_block_txids(&hash)
-                .map_err(|_| HttpError::not_found("Block not found".to_string()))?;
+            let txids = query.get_block_txids(ctx, &hash)?;
 
             let start_index = start_index
                 .map_or(0u32, |el| el.parse().unwrap_or(0))
@@ -487,19 +1343,20 @@ fn handle_request(
                 .take(TX_LIMIT)
                 .map(|txid| {
                     query
-                        .load_txn(&txid, Some(&hash))
+                        .load_txn(ctx, &txid, Some(&hash))
                         .map(TransactionValue::from)
                 })
                 .collect::<Result<Vec<TransactionValue>, _>>()?;
-            attach_txs_data(&mut txs, config, query);
-            json_response(txs, TTL_LONG)
+            attach_txs_data(&mut txs, config, query, ctx)?;
+            data_response(content_type, txs, TTL_LONG)
         }
         (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None)
         | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None) => {
             // @TODO create new AddressStatsValue struct?
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            match query.status(&script_hash[..]) {
-                Ok(status) => json_response(
+            match query.status(ctx, &script_hash[..]) {
+                Ok(status) => data_response(
+                    content_type,
                     json!({
                         *script_type: script_str,
                         "tx_count": status.history().len(),
@@ -511,10 +1368,8 @@ fn handle_request(
                 ),
 
                 // if the address has too many txs, just return the address with no additional info (but no error)
-                Err(errors::Error(errors::ErrorKind::Msg(ref msg), _))
-                    if *msg == "Too many txs".to_string() =>
-                {
-                    json_response(json!({ *script_type: script_str }), TTL_SHORT)
+                Err(errors::Error(errors::ErrorKind::TooManyTxs(_), _)) => {
+                    data_response(content_type, json!({ *script_type: script_str }), TTL_SHORT)
                 }
 
                 Err(err) => bail!(err),
@@ -539,11 +1394,11 @@ fn handle_request(
                 .max(0u32) as usize;
 
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            let status = query.status(&script_hash[..])?;
+            let status = query.status(ctx, &script_hash[..])?;
             let txs = status.history_txs();
 
             if txs.len() == 0 {
-                return json_response(json!([]), TTL_SHORT);
+                return data_response(content_type, json!([]), TTL_SHORT);
             } else if start_index >= txs.len() {
                 bail!(HttpError::not_found("start index out of range".to_string()));
             } else if start_index % TX_LIMIT != 0 {
@@ -559,9 +1414,9 @@ fn handle_request(
                 .take(TX_LIMIT)
                 .map(|t| TransactionValue::from((*t).clone()))
                 .collect();
-            attach_txs_data(&mut txs, config, query);
+            attach_txs_data(&mut txs, config, query, ctx)?;
 
-            json_response(txs, TTL_SHORT)
+            data_response(content_type, txs, TTL_SHORT)
         }
         (&Method::GET, Some(script_type @ &"address"), Some(script_str), Some(&"utxo"), None)
         | (
@@ -572,51 +1427,130 @@ fn handle_request(
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, &config.network_type)?;
-            let status = query.status(&script_hash[..])?;
-            let utxos: Vec<UtxoValue> = status
+            let status = query.status(ctx, &script_hash[..])?;
+            let utxos = status
                 .unspent()
                 .into_iter()
-                .map(|o| UtxoValue::from(o.clone()))
+                .map(|o| UtxoValue::try_from(o.clone()))
+                .collect::<Result<Vec<UtxoValue>, HttpError>>()?;
+            // @XXX no paging, but query.status(ctx) is limited to 30 funding txs
+            data_response(content_type, utxos, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"xpub"), Some(xpub), None, None) => {
+            let (descriptor, status) = xpub_scan(xpub, query, ctx, config)?;
+            let used_addresses: Vec<Value> = status
+                .used
+                .iter()
+                .map(|addr| {
+                    json!({
+                        "address": addr.address.to_string(),
+                        "path": addr.path(),
+                        "tx_count": addr.status.history().len(),
+                        "confirmed_balance": addr.status.confirmed_balance(),
+                        "mempool_balance": addr.status.mempool_balance(),
+                        "total_received": addr.status.total_received(),
+                    })
+                })
+                .collect();
+            data_response(
+                content_type,
+                json!({
+                    "xpub": xpub,
+                    "script_type": descriptor.script_type().name(),
+                    "tx_count": status.history_txs().len(),
+                    "confirmed_balance": status.confirmed_balance(),
+                    "mempool_balance": status.mempool_balance(),
+                    "total_received": status.total_received(),
+                    "used_addresses": used_addresses,
+                    "next_receive_index": status.next_receive_index,
+                    "next_change_index": status.next_change_index,
+                }),
+                TTL_SHORT,
+            )
+        }
+        (&Method::GET, Some(&"xpub"), Some(xpub), Some(&"utxo"), None) => {
+            let (_, status) = xpub_scan(xpub, query, ctx, config)?;
+            let utxos = status
+                .unspent()
+                .into_iter()
+                .map(|(addr, out)| {
+                    let mut value = serde_json::to_value(UtxoValue::try_from(out.clone())?)?;
+                    value["address"] = json!(addr.address.to_string());
+                    value["path"] = json!(addr.path());
+                    Ok(value)
+                })
+                .collect::<Result<Vec<Value>, HttpError>>()?;
+            data_response(content_type, utxos, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"xpub"), Some(xpub), Some(&"txs"), start_index) => {
+            let start_index = start_index
+                .map_or(0u32, |el| el.parse().unwrap_or(0))
+                .max(0u32) as usize;
+
+            let (_, status) = xpub_scan(xpub, query, ctx, config)?;
+            let txs = status.history_txs();
+
+            if txs.len() == 0 {
+                return data_response(content_type, json!([]), TTL_SHORT);
+            } else if start_index >= txs.len() {
+                bail!(HttpError::not_found("start index out of range".to_string()));
+            } else if start_index % TX_LIMIT != 0 {
+                bail!(HttpError::from(format!(
+                    "start index must be a multipication of {}",
+                    TX_LIMIT
+                )));
+            }
+
+            let mut txs = txs
+                .iter()
+                .skip(start_index)
+                .take(TX_LIMIT)
+                .map(|t| TransactionValue::from((*t).clone()))
                 .collect();
-            // @XXX no paging, but query.status() is limited to 30 funding txs
-            json_response(utxos, TTL_SHORT)
+            attach_txs_data(&mut txs, config, query, ctx)?;
+
+            data_response(content_type, txs, TTL_SHORT)
         }
+        (&Method::GET, Some(&"search"), Some(q), None, None) => data_response(
+            content_type,
+            search(q.trim(), query, ctx, config),
+            TTL_SHORT,
+        ),
         (&Method::GET, Some(&"tx"), Some(hash), None, None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let transaction = query
-                .load_txn(&hash, None)
-                .map_err(|_| HttpError::not_found("Transaction not found".to_string()))?;
-            let status = query.get_tx_status(&hash)?;
-            let ttl = ttl_by_depth(status.block_height, query);
+            let transaction = query.load_txn(ctx, &hash, None)?;
+            let status = query.get_tx_status(ctx, &hash)?;
+            let ttl = ttl_by_depth(status.block_height, ctx);
 
             let mut value = TransactionValue::from(transaction);
             value.status = Some(status);
-            let value = attach_tx_data(value, config, query);
-            json_response(value, ttl)
+            let value = attach_tx_data(value, config, query, ctx)?;
+            data_response(content_type, value, ttl)
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"hex"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let rawtx = query
-                .load_raw_txn(&hash, None)
-                .map_err(|_| HttpError::not_found("Transaction not found".to_string()))?;
-            let ttl = ttl_by_depth(query.get_tx_status(&hash)?.block_height, query);
+            let rawtx = query.load_raw_txn(ctx, &hash, None)?;
+            let ttl = ttl_by_depth(query.get_tx_status(ctx, &hash)?.block_height, ctx);
             http_message(StatusCode::OK, hex::encode(rawtx), ttl)
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let status = query.get_tx_status(&hash)?;
-            let ttl = ttl_by_depth(status.block_height, query);
-            json_response(status, ttl)
+            let status = query.get_tx_status(ctx, &hash)?;
+            let ttl = ttl_by_depth(status.block_height, ctx);
+            data_response(content_type, status, ttl)
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"merkle-proof"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let status = query.get_tx_status(&hash)?;
+            let status = query.get_tx_status(ctx, &hash)?;
             if !status.confirmed {
-                bail!("Transaction is unconfirmed".to_string())
+                bail!(HttpError::not_found(
+                    "Transaction is unconfirmed".to_string()
+                ))
             };
-            let proof = query.get_merkle_proof(&hash, &status.block_hash.unwrap())?;
-            let ttl = ttl_by_depth(status.block_height, query);
-            json_response(
+            let proof = query.get_merkle_proof(ctx, &hash, &status.block_hash.unwrap())?;
+            let ttl = ttl_by_depth(status.block_height, ctx);
+            data_response(
+                content_type,
                 json!({ "block_height": status.block_height, "merkle": proof.0, "pos": proof.1 }),
                 ttl,
             )
@@ -624,36 +1558,99 @@ fn handle_request(
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"outspend"), Some(index)) => {
             let hash = Sha256dHash::from_hex(hash)?;
             let outpoint = (hash, index.parse::<usize>()?);
-            let spend = query.find_spending_by_outpoint(outpoint)?.map_or_else(
-                || SpendingValue::default(),
-                |spend| SpendingValue::from(spend),
-            );
+            let spend = query
+                .find_spending_by_outpoint(ctx, outpoint)?
+                .map_or_else(|| Ok(SpendingValue::default()), SpendingValue::try_from)?;
             let ttl = ttl_by_depth(
                 spend
                     .status
                     .as_ref()
                     .and_then(|ref status| status.block_height),
-                query,
+                ctx,
             );
-            json_response(spend, ttl)
+            data_response(content_type, spend, ttl)
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"outspends"), None) => {
             let hash = Sha256dHash::from_hex(hash)?;
-            let tx = query
-                .load_txn(&hash, None)
-                .map_err(|_| HttpError::not_found("Transaction not found".to_string()))?;
-            let spends: Vec<SpendingValue> = query
-                .find_spending_for_funding_tx(tx)?
+            let tx = query.load_txn(ctx, &hash, None)?;
+            let spends = query
+                .find_spending_for_funding_tx(ctx, tx)?
                 .into_iter()
                 .map(|spend| {
-                    spend.map_or_else(
-                        || SpendingValue::default(),
-                        |spend| SpendingValue::from(spend),
-                    )
+                    spend.map_or_else(|| Ok(SpendingValue::default()), SpendingValue::try_from)
                 })
-                .collect();
+                .collect::<Result<Vec<SpendingValue>, HttpError>>()?;
             // @TODO long ttl if all outputs are either spent long ago or unspendable
-            json_response(spends, TTL_SHORT)
+            data_response(content_type, spends, TTL_SHORT)
+        }
+        (&Method::POST, Some(&"tx"), None, None, None) => {
+            let txhex = String::from_utf8(body.to_vec())
+                .map_err(|_| HttpError::from("Invalid transaction hex".to_string()))?;
+            let tx: Transaction = deserialize(&hex::decode(txhex.trim())?)
+                .map_err(|_| HttpError::from("Invalid transaction".to_string()))?;
+            let txid = query.broadcast(&tx)?;
+            http_message(StatusCode::OK, txid.be_hex_string(), 0)
+        }
+        (&Method::POST, Some(&"txs"), None, None, None) => {
+            let txids = parse_batch(body)?
+                .iter()
+                .map(|txid| Sha256dHash::from_hex(txid))
+                .collect::<Result<Vec<Sha256dHash>, _>>()?;
+            let mut ttl = TTL_LONG;
+            let mut txs = txids
+                .iter()
+                .map(|txid| {
+                    let transaction = query.load_txn(ctx, txid, None)?;
+                    let status = query.get_tx_status(ctx, txid)?;
+                    ttl = ttl.min(ttl_by_depth(status.block_height, ctx));
+
+                    let mut value = TransactionValue::from(transaction);
+                    value.status = Some(status);
+                    Ok(value)
+                })
+                .collect::<Result<Vec<TransactionValue>, HttpError>>()?;
+            // prevouts are looked up once for the whole batch
+            attach_txs_data(&mut txs, config, query, ctx)?;
+            data_response(content_type, txs, ttl)
+        }
+        (&Method::POST, Some(&"outspends"), None, None, None) => {
+            let txids = parse_batch(body)?
+                .iter()
+                .map(|txid| Sha256dHash::from_hex(txid))
+                .collect::<Result<Vec<Sha256dHash>, _>>()?;
+            let spends = txids
+                .iter()
+                .map(|txid| {
+                    let tx = query.load_txn(ctx, txid, None)?;
+                    query
+                        .find_spending_for_funding_tx(ctx, tx)?
+                        .into_iter()
+                        .map(|spend| {
+                            spend.map_or_else(
+                                || Ok(SpendingValue::default()),
+                                SpendingValue::try_from,
+                            )
+                        })
+                        .collect()
+                })
+                .collect::<Result<Vec<Vec<SpendingValue>>, HttpError>>()?;
+            data_response(content_type, spends, TTL_SHORT)
+        }
+        (&Method::POST, Some(&"addresses"), Some(&"utxo"), None, None) => {
+            let utxos = parse_batch(body)?
+                .iter()
+                .map(|addr| {
+                    let script_hash = address_to_scripthash(addr, &config.network_type)?;
+                    let status = query.status(ctx, &script_hash[..])?;
+                    status
+                        .unspent()
+                        .into_iter()
+                        .map(|o| UtxoValue::try_from(o.clone()))
+                        .collect()
+                })
+                .collect::<Result<Vec<Vec<UtxoValue>>, HttpError>>()?;
+            // results are returned in the same order as the requested addresses
+            data_response(content_type, utxos, TTL_SHORT)
         }
         _ => Err(HttpError::not_found(format!(
             "endpoint does not exist {:?}",
@@ -675,30 +1672,52 @@ fn http_message(
         .unwrap())
 }
 
-fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
-    let value = serde_json::to_string(&value)?;
+fn binary_response(data: Vec<u8>, ttl: u32) -> Result<Response<Body>, HttpError> {
     Ok(Response::builder()
-        .header("Content-Type", "application/json")
+        .header("Content-Type", "application/octet-stream")
         .header("Cache-Control", format!("public, max-age={:}", ttl))
-        .body(Body::from(value))
+        .body(Body::from(data))
         .unwrap())
 }
 
-fn blocks(query: &Arc<Query>, start_height: Option<usize>) -> Result<Response<Body>, HttpError> {
+fn data_response<T: Serialize>(
+    content_type: ContentType,
+    value: T,
+    ttl: u32,
+) -> Result<Response<Body>, HttpError> {
+    let body = match content_type {
+        ContentType::Json => serde_json::to_vec(&value)?,
+        ContentType::Cbor => serde_cbor::to_vec(&value)?,
+        ContentType::MsgPack => rmp_serde::to_vec_named(&value)?,
+    };
+    Ok(Response::builder()
+        .header("Content-Type", content_type.mime())
+        .header("Vary", "Accept")
+        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .body(Body::from(body))
+        .unwrap())
+}
+
+fn blocks(
+    query: &Arc<Query>,
+    ctx: &ReadContext,
+    start_height: Option<usize>,
+    content_type: ContentType,
+) -> Result<Response<Body>, HttpError> {
     let mut values = Vec::new();
     let mut current_hash = match start_height {
         Some(height) => query
-            .get_headers(&[height])
+            .get_headers(ctx, &[height])
             .get(0)
             .ok_or(HttpError::not_found("Block not found".to_string()))?
             .hash()
             .clone(),
-        None => query.get_best_header()?.hash().clone(),
+        None => query.get_best_header(ctx)?.hash().clone(),
     };
 
     let zero = [0u8; 32];
     for _ in 0..BLOCK_LIMIT {
-        let blockhm = query.get_block_header_with_meta(&current_hash)?;
+        let blockhm = query.get_block_header_with_meta(ctx, &current_hash)?;
         current_hash = blockhm.header_entry.header().prev_blockhash.clone();
         values.push(BlockValue::from(blockhm));
 
@@ -706,7 +1725,60 @@ fn blocks(query: &Arc<Query>, start_height: Option<usize>) -> Result<Response<Bo
             break;
         }
     }
-    json_response(values, TTL_SHORT)
+    data_response(content_type, values, TTL_SHORT)
+}
+
+fn parse_batch(body: &[u8]) -> Result<Vec<String>, HttpError> {
+    let items: Vec<String> = serde_json::from_slice(body)
+        .map_err(|_| HttpError::from("Invalid request body, expected a JSON array".to_string()))?;
+    if items.len() > BATCH_LIMIT {
+        bail!(HttpError::from(format!(
+            "Too many items in batch (limit is {})",
+            BATCH_LIMIT
+        )));
+    }
+    Ok(items)
+}
+
+fn block_search_result(header: &HeaderEntry) -> Value {
+    json!({ "type": "block", "hash": header.hash(), "height": header.height() })
+}
+
+fn tx_search_result(txid: &Sha256dHash) -> Value {
+    json!({ "type": "transaction", "txid": txid })
+}
+
+/// Resolves a search box input into typed results: block heights, full or partial block hashes
+/// and txids, and full or partial addresses (partial confirmed ones in full mode only).
+fn search(q: &str, query: &Arc<Query>, ctx: &ReadContext, config: &Config) -> Vec<Value> {
+    let mut results = vec![];
+    if let Ok(height) = q.parse::<usize>() {
+        for header in query.get_headers(ctx, &[height]) {
+            results.push(block_search_result(&header));
+        }
+    }
+    if q.len() == 64 {
+        if let Ok(hash) = Sha256dHash::from_hex(q) {
+            if let Ok(header) = query.get_header_by_hash(ctx, &hash) {
+                results.push(block_search_result(&header));
+            }
+            if query.has_txn(ctx, &hash) {
+                results.push(tx_search_result(&hash));
+            }
+        }
+    } else if q.len() >= SEARCH_MIN_PREFIX_LEN && q.chars().all(|c| c.is_digit(16)) {
+        let (headers, txids) = query.search_by_prefix(ctx, q, SEARCH_LIMIT);
+        results.extend(headers.iter().map(block_search_result));
+        results.extend(txids.iter().map(tx_search_result));
+    }
+    if address_to_scripthash(q, &config.network_type).is_ok() {
+        results.push(json!({ "type": "address", "address": q }));
+    } else if q.len() >= SEARCH_MIN_PREFIX_LEN {
+        for address in query.search_addresses_by_prefix(ctx, q, SEARCH_LIMIT) {
+            results.push(json!({ "type": "address", "address": address }));
+        }
+    }
+    results
 }
 
 fn to_scripthash(
@@ -731,21 +1803,127 @@ fn address_to_scripthash(addr: &str, network: &Network) -> Result<FullHash, Http
     Ok(compute_script_hash(&addr.script_pubkey().into_bytes()))
 }
 
-#[derive(Debug)]
-struct HttpError(StatusCode, String);
+fn xpub_scan(
+    xpub: &str,
+    query: &Arc<Query>,
+    ctx: &ReadContext,
+    config: &Config,
+) -> Result<(Descriptor, XpubStatus), HttpError> {
+    // descriptors may arrive with their parentheses percent-encoded
+    let xpub = percent_decode(xpub.as_bytes())
+        .decode_utf8()
+        .map_err(|_| HttpError::from("Invalid xpub encoding".to_string()))?;
+    let descriptor = Descriptor::parse(&xpub)
+        .map_err(|_| HttpError::from("Invalid xpub or descriptor".to_string()))?;
+    let network = descriptor.network();
+    if network != config.network_type
+        && !(network == Network::Testnet && config.network_type == Network::Regtest)
+    {
+        bail!(HttpError::from("Xpub on invalid network".to_string()))
+    }
+    let status = xpub::scan(
+        query,
+        ctx,
+        &descriptor,
+        config.network_type,
+        config.xpub_gap_limit,
+    )?;
+    Ok((descriptor, status))
+}
+
+/// Stable, machine-readable error codes. These are part of the API and must not be renamed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    InvalidRequest,
+    NotFound,
+    PayloadTooLarge,
+    TooManyTxs,
+    TxRejected,
+    RateLimited,
+    NotReady,
+    Unauthorized,
+    Conflict,
+    DaemonUnavailable,
+    DaemonError,
+    InternalError,
+}
+
+impl ErrorCode {
+    fn status(&self) -> StatusCode {
+        match *self {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::TooManyTxs | ErrorCode::TxRejected => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::NotReady | ErrorCode::DaemonUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::DaemonError | ErrorCode::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HttpError {
+    code: ErrorCode,
+    message: String,
+    /// The daemon's JSONRPC error code, for errors forwarded from it (i.e. rejected broadcasts)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rpc_code: Option<i64>,
+}
 
 impl HttpError {
+    fn new(code: ErrorCode, message: String) -> Self {
+        HttpError {
+            code,
+            message,
+            rpc_code: None,
+        }
+    }
     fn not_found(msg: String) -> Self {
-        HttpError(StatusCode::NOT_FOUND, msg)
+        HttpError::new(ErrorCode::NotFound, msg)
     }
     fn generic() -> Self {
-        HttpError::from("We encountered an error. Please try again later.".to_string())
+        HttpError::new(
+            ErrorCode::InternalError,
+            "We encountered an error. Please try again later.".to_string(),
+        )
+    }
+    // see bitcoind's src/rpc/protocol.h for the codes
+    fn from_rpc_error(code: i64, message: String) -> Self {
+        let error_code = match code {
+            -5 => ErrorCode::NotFound,                // RPC_INVALID_ADDRESS_OR_KEY
+            -8 | -22 => ErrorCode::InvalidRequest, // RPC_INVALID_PARAMETER, RPC_DESERIALIZATION_ERROR
+            -25 | -26 | -27 => ErrorCode::TxRejected, // RPC_VERIFY_ERROR/_REJECTED/_ALREADY_IN_CHAIN
+            _ => ErrorCode::DaemonError,
+        };
+        HttpError {
+            code: error_code,
+            message,
+            rpc_code: Some(code),
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        self.code.status()
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let body = serde_json::to_vec(&json!({ "error": self }))
+            .expect("failed to serialize error response");
+        Response::builder()
+            .status(self.status())
+            .header("Content-Type", "application/json")
+            .body(Body::from(body))
+            .unwrap()
     }
 }
 
 impl From<String> for HttpError {
     fn from(msg: String) -> Self {
-        HttpError(StatusCode::BAD_REQUEST, msg)
+        HttpError::new(ErrorCode::InvalidRequest, msg)
     }
 }
 impl From<ParseIntError> for HttpError {
@@ -766,20 +1944,49 @@ impl From<FromHexError> for HttpError {
         HttpError::from("Invalid hex string".to_string())
     }
 }
+impl HttpError {
+    fn from_kind(kind: &errors::ErrorKind) -> Option<HttpError> {
+        match *kind {
+            errors::ErrorKind::RpcError(_, code, ref message) => {
+                Some(HttpError::from_rpc_error(code, message.clone()))
+            }
+            errors::ErrorKind::TxNotFound(_) | errors::ErrorKind::BlockNotFound(_) => {
+                Some(HttpError::not_found(kind.to_string()))
+            }
+            errors::ErrorKind::TooManyTxs(_) => Some(HttpError::new(
+                ErrorCode::TooManyTxs,
+                "Sorry! Addresses with a large number of transactions aren\'t currently supported."
+                    .to_string(),
+            )),
+            errors::ErrorKind::BackupInProgress => {
+                Some(HttpError::new(ErrorCode::Conflict, kind.to_string()))
+            }
+            errors::ErrorKind::Connection(_) => Some(HttpError::new(
+                ErrorCode::DaemonUnavailable,
+                "The daemon is currently unavailable. Please try again later.".to_string(),
+            )),
+            _ => None,
+        }
+    }
+}
+
 impl From<errors::Error> for HttpError {
     fn from(e: errors::Error) -> Self {
         warn!("errors::Error: {:?}", e);
-        match e.description().to_string().as_ref() {
-            "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}" => {
-                HttpError::not_found("Block not found".to_string())
+        // the first known kind wins, since `chain_err` wraps e.g. daemon errors with some context.
+        // The chain is walked through the boxed causes, as `Error::iter()` items can't be downcast.
+        let mut next = Some(&e);
+        while let Some(error) = next {
+            if let Some(http_error) = HttpError::from_kind(error.kind()) {
+                return http_error;
             }
-            "Too many txs" => HttpError(
-                StatusCode::TOO_MANY_REQUESTS,
-                "Sorry! Addresses with a large number of transactions aren\'t currently supported."
-                    .to_string(),
-            ),
-            _ => HttpError::generic(),
+            next = error
+                .1
+                .next_error
+                .as_ref()
+                .and_then(|cause| cause.downcast_ref::<errors::Error>());
         }
+        HttpError::generic()
     }
 }
 impl From<serde_json::Error> for HttpError {
@@ -788,6 +1995,16 @@ impl From<serde_json::Error> for HttpError {
         HttpError::generic()
     }
 }
+impl From<serde_cbor::Error> for HttpError {
+    fn from(_e: serde_cbor::Error) -> Self {
+        HttpError::generic()
+    }
+}
+impl From<rmp_serde::encode::Error> for HttpError {
+    fn from(_e: rmp_serde::encode::Error) -> Self {
+        HttpError::generic()
+    }
+}
 impl From<encode::Error> for HttpError {
     fn from(_e: encode::Error) -> Self {
         //HttpError::from(e.description().to_string())
@@ -797,9 +2014,20 @@ impl From<encode::Error> for HttpError {
 
 #[cfg(test)]
 mod tests {
-    use rest::HttpError;
-    use serde_json::Value;
+    use bitcoin::util::hash::Sha256dHash;
+    use errors::{self, ResultExt};
+    use futures::stream;
+    use hyper::rt::Future;
+    use hyper::Body;
+    use hyper::StatusCode;
+    use query::FundingOutput;
+    use rest::{
+        check_admin_token, read_body, ContentEncoding, ContentType, ErrorCode, HttpError, UtxoValue,
+    };
+    use serde_json::{self, Value};
     use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::io;
 
     #[test]
     fn test_parse_query_param() {
@@ -841,6 +2069,218 @@ mod tests {
         assert_eq!(10, limit);
     }
 
+    #[test]
+    fn test_parse_batch() {
+        use rest::{parse_batch, BATCH_LIMIT};
+
+        let items = parse_batch(br#"["a", "b"]"#).unwrap();
+        assert_eq!(items, vec!["a".to_string(), "b".to_string()]);
+        assert!(parse_batch(b"[]").unwrap().is_empty());
+
+        assert!(parse_batch(b"").is_err());
+        assert!(parse_batch(br#"{"txids": []}"#).is_err());
+
+        let too_many = serde_json::to_vec(&vec!["a"; BATCH_LIMIT + 1]).unwrap();
+        assert!(parse_batch(&too_many).is_err());
+    }
+
+    #[test]
+    fn test_content_type_from_accept() {
+        assert_eq!(ContentType::from_accept(None), ContentType::Json);
+        assert_eq!(ContentType::from_accept(Some("*/*")), ContentType::Json);
+        assert_eq!(
+            ContentType::from_accept(Some("application/cbor")),
+            ContentType::Cbor
+        );
+        assert_eq!(
+            ContentType::from_accept(Some("text/html, application/msgpack;q=0.9")),
+            ContentType::MsgPack
+        );
+        assert_eq!(
+            ContentType::from_accept(Some("application/json, application/cbor")),
+            ContentType::Json
+        );
+    }
+
+    #[test]
+    fn test_content_encoding_from_accept_encoding() {
+        use rest::{body_digest, etag_matches, make_etag, matching_etag};
+
+        let negotiate = ContentEncoding::from_accept_encoding;
+        assert_eq!(negotiate(None), ContentEncoding::Identity);
+        assert_eq!(negotiate(Some("identity")), ContentEncoding::Identity);
+        assert_eq!(negotiate(Some("gzip, deflate")), ContentEncoding::Gzip);
+        assert_eq!(
+            negotiate(Some("gzip, deflate, br")),
+            ContentEncoding::Brotli
+        );
+        assert_eq!(negotiate(Some("br;q=0, gzip;q=0.5")), ContentEncoding::Gzip);
+
+        let digest = body_digest(b"{}");
+        let etag = make_etag(&digest, ContentEncoding::Gzip);
+        assert_ne!(etag, make_etag(&digest, ContentEncoding::Identity));
+        assert!(etag_matches(&etag, &etag));
+        assert!(etag_matches(&format!("\"abc\", W/{}", etag), &etag));
+        assert!(etag_matches("*", &etag));
+        assert!(!etag_matches("\"abc\"", &etag));
+
+        // conditional requests are answered from the digest, whatever the coding
+        let tags = format!("\"abc\", W/{}", etag);
+        assert_eq!(matching_etag(&tags, &digest), Some(etag.clone()));
+        let identity = make_etag(&digest, ContentEncoding::Identity);
+        assert_eq!(matching_etag(&identity, &digest), Some(identity.clone()));
+        assert_eq!(matching_etag("\"abc\"", &digest), None);
+        assert_eq!(matching_etag(&tags, &digest[..31]), None);
+    }
+
+    #[test]
+    fn test_cors_allowed_origin() {
+        use hyper::header::{HeaderMap, HeaderValue};
+        use rest::ResponseHeaders;
+
+        let headers = |origins: &[&str]| ResponseHeaders {
+            cors_origins: origins.iter().map(|o| o.to_string()).collect(),
+            cors_methods: HeaderValue::from_static("GET"),
+            custom: vec![],
+        };
+        let wallet = Some("https://wallet.example");
+
+        assert_eq!(headers(&[]).allowed_origin(wallet), None);
+        assert_eq!(headers(&["*"]).allowed_origin(None), Some("*".to_string()));
+        let listed = headers(&["https://wallet.example"]);
+        assert_eq!(listed.allowed_origin(wallet), wallet.map(|o| o.to_string()));
+        assert_eq!(listed.allowed_origin(Some("https://evil.example")), None);
+
+        let mut map = HeaderMap::new();
+        listed.apply(&mut map, wallet, true);
+        assert_eq!(map["Access-Control-Allow-Origin"], "https://wallet.example");
+        assert_eq!(map["Access-Control-Allow-Methods"], "GET");
+        assert_eq!(map["Vary"], "Origin");
+    }
+
+    #[test]
+    fn test_error_from_kind() {
+        let rpc_error = |code| {
+            HttpError::from(errors::Error::from(errors::ErrorKind::RpcError(
+                "sendrawtransaction".to_string(),
+                code,
+                "txn-mempool-conflict".to_string(),
+            )))
+        };
+        let rejected = rpc_error(-26);
+        assert_eq!(rejected.code, ErrorCode::TxRejected);
+        assert_eq!(rejected.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            serde_json::to_value(&rejected).unwrap(),
+            json!({ "code": "tx_rejected", "message": "txn-mempool-conflict", "rpc_code": -26 })
+        );
+        assert_eq!(rpc_error(-5).status(), StatusCode::NOT_FOUND);
+        assert_eq!(rpc_error(-22).status(), StatusCode::BAD_REQUEST);
+        assert_eq!(rpc_error(-1).code, ErrorCode::DaemonError);
+
+        let too_many = HttpError::from(errors::Error::from(errors::ErrorKind::TooManyTxs(100)));
+        assert_eq!(too_many.code, ErrorCode::TooManyTxs);
+        let unavailable = HttpError::from(errors::Error::from(errors::ErrorKind::Connection(
+            "no reply from daemon".to_string(),
+        )));
+        assert_eq!(unavailable.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let missing = HttpError::from(errors::Error::from(errors::ErrorKind::TxNotFound(
+            Sha256dHash::default(),
+        )));
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        let busy = HttpError::from(errors::Error::from(errors::ErrorKind::BackupInProgress));
+        assert_eq!(busy.status(), StatusCode::CONFLICT);
+        // wrapped errors are mapped by their cause
+        let chained = Err::<(), _>(errors::Error::from(errors::ErrorKind::Connection(
+            "no reply from daemon".to_string(),
+        )))
+        .chain_err(|| "failed to load transaction")
+        .chain_err(|| "failed to attach prevouts")
+        .unwrap_err();
+        assert_eq!(chained.iter().count(), 3);
+        assert_eq!(HttpError::from(chained).code, ErrorCode::DaemonUnavailable);
+        let other = HttpError::from(errors::Error::from("oops"));
+        assert_eq!(other.code, ErrorCode::InternalError);
+        assert_eq!(
+            serde_json::to_value(&other).unwrap(),
+            json!({ "code": "internal_error", "message": "We encountered an error. Please try again later." })
+        );
+    }
+
+    #[test]
+    fn test_prevtx_error() {
+        use bitcoin::util::hash::Sha256dHash;
+        use rest::prevtx_error;
+
+        let txid = Sha256dHash::from_data(b"prevtx");
+        let (error, missing) = prevtx_error(errors::ErrorKind::TxNotFound(txid).into());
+        assert!(missing);
+        assert_eq!(error.code, ErrorCode::InternalError);
+        let rpc_error = |code| {
+            prevtx_error(
+                errors::ErrorKind::RpcError("getrawtransaction".to_string(), code, "".to_string())
+                    .into(),
+            )
+        };
+        assert!(rpc_error(-5).1);
+        let (error, missing) = rpc_error(-28); // RPC_IN_WARMUP
+        assert!(!missing);
+        assert_eq!(error.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let (error, missing) =
+            prevtx_error(errors::ErrorKind::Connection("no reply from daemon".to_string()).into());
+        assert!(!missing);
+        assert_eq!(error.code, ErrorCode::DaemonUnavailable);
+        let (error, missing) = prevtx_error("cannot parse tx".into());
+        assert!(!missing);
+        assert_eq!(error.code, ErrorCode::InternalError);
+    }
+
+    #[test]
+    fn test_read_body() {
+        let chunks = || {
+            let chunks: Vec<Result<Vec<u8>, io::Error>> = vec![Ok(vec![1; 40]), Ok(vec![2; 40])];
+            Body::wrap_stream(stream::iter_result(chunks))
+        };
+        assert_eq!(
+            read_body(chunks(), 80).wait().unwrap().ok().unwrap().len(),
+            80
+        );
+        let err = read_body(chunks(), 79).wait().unwrap().err().unwrap();
+        assert_eq!(err.code, ErrorCode::PayloadTooLarge);
+        assert_eq!(err.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_check_admin_token() {
+        assert_eq!(
+            check_admin_token(None, Some("secret")).err().unwrap().code,
+            ErrorCode::NotFound
+        );
+        for token in &[
+            None,
+            Some(""),
+            Some("secre"),
+            Some("secret!"),
+            Some("Secret"),
+        ] {
+            assert_eq!(
+                check_admin_token(Some("secret"), *token)
+                    .err()
+                    .unwrap()
+                    .code,
+                ErrorCode::Unauthorized
+            );
+        }
+        assert!(check_admin_token(Some("secret"), Some("secret")).is_ok());
+    }
+
+    #[test]
+    fn test_utxo_without_txn() {
+        let out = FundingOutput::from((Sha256dHash::default(), 0));
+        let err = UtxoValue::try_from(out).err().unwrap();
+        assert_eq!(err.code, ErrorCode::InternalError);
+    }
+
     #[test]
     fn test_parse_value_param() {
         let v: Value = json!({ "confirmations": 10 });
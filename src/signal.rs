@@ -1,27 +1,47 @@
 use chan;
 use chan_signal;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use errors::*;
 
+type ReloadHook = Box<Fn() + Send>;
+
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     signal: chan::Receiver<chan_signal::Signal>,
+    reload_hooks: Arc<Mutex<Vec<ReloadHook>>>,
 }
 
 impl Waiter {
     pub fn new() -> Waiter {
         Waiter {
-            signal: chan_signal::notify(&[chan_signal::Signal::INT, chan_signal::Signal::TERM]),
+            signal: chan_signal::notify(&[
+                chan_signal::Signal::INT,
+                chan_signal::Signal::TERM,
+                chan_signal::Signal::HUP,
+            ]),
+            reload_hooks: Arc::new(Mutex::new(vec![])),
         }
     }
+    /// Registers a callback to run (on the waiting thread) whenever SIGHUP is received.
+    pub fn on_reload(&self, hook: ReloadHook) {
+        self.reload_hooks.lock().unwrap().push(hook);
+    }
     pub fn wait(&self, duration: Duration) -> Result<()> {
         let signal = &self.signal;
         let timeout = chan::after(duration);
         chan_select! {
             signal.recv() -> s => {
-                if let Some(sig) = s {
-                    bail!(ErrorKind::Interrupt(sig));
+                match s {
+                    Some(chan_signal::Signal::HUP) => {
+                        info!("reloading on SIGHUP");
+                        for hook in self.reload_hooks.lock().unwrap().iter() {
+                            hook();
+                        }
+                    }
+                    Some(sig) => bail!(ErrorKind::Interrupt(sig)),
+                    None => (),
                 }
             },
             timeout.recv() => {},
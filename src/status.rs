@@ -0,0 +1,79 @@
+use std::sync::{Arc, RwLock};
+
+use query::Query;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPhase {
+    Starting,
+    BulkImport,
+    Compaction,
+    Catchup,
+    Synced,
+}
+
+/// Progress of the initial sync, as reported by the `/sync` endpoint.
+#[derive(Clone, Debug, Serialize)]
+pub struct SyncInfo {
+    pub phase: SyncPhase,
+    pub indexed_height: Option<usize>,
+    pub daemon_height: Option<u32>,
+    pub daemon_verification_progress: Option<f32>,
+    /// The daemon answered the last status poll.
+    pub daemon_available: bool,
+    pub bulk_files_indexed: usize,
+    pub bulk_files_total: usize,
+    pub fully_compacted: bool,
+    pub mempool_synced: bool,
+    /// The index tip matches the daemon's and the mempool has been loaded.
+    pub ready: bool,
+}
+
+/// Shared between the indexing thread, which reports its progress, and the REST server,
+/// which answers the health checks and only serves queries once the index caught up.
+pub struct SyncStatus {
+    info: RwLock<SyncInfo>,
+    query: RwLock<Option<Arc<Query>>>,
+}
+
+impl SyncStatus {
+    pub fn new() -> Arc<SyncStatus> {
+        Arc::new(SyncStatus {
+            info: RwLock::new(SyncInfo {
+                phase: SyncPhase::Starting,
+                indexed_height: None,
+                daemon_height: None,
+                daemon_verification_progress: None,
+                daemon_available: true,
+                bulk_files_indexed: 0,
+                bulk_files_total: 0,
+                fully_compacted: false,
+                mempool_synced: false,
+                ready: false,
+            }),
+            query: RwLock::new(None),
+        })
+    }
+
+    pub fn info(&self) -> SyncInfo {
+        self.info.read().unwrap().clone()
+    }
+
+    pub fn update<F: FnOnce(&mut SyncInfo)>(&self, f: F) {
+        f(&mut self.info.write().unwrap())
+    }
+
+    pub fn set_phase(&self, phase: SyncPhase) {
+        info!("sync phase: {:?}", phase);
+        self.update(|info| info.phase = phase)
+    }
+
+    /// Makes the query API available, once the index is ready for the first time.
+    pub fn set_query(&self, query: Arc<Query>) {
+        *self.query.write().unwrap() = Some(query);
+    }
+
+    pub fn query(&self) -> Option<Arc<Query>> {
+        self.query.read().unwrap().clone()
+    }
+}
@@ -1,12 +1,15 @@
-use bincode;
 use rocksdb;
 
+use std::cmp::Ordering;
+use std::mem::{self, ManuallyDrop};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use config::Config;
+use replica::ReadOnlyDB;
 use util::Bytes;
 
-const DB_VERSION: u32 = 1;
+use errors::*;
 
 #[derive(Clone)]
 pub struct Row {
@@ -20,13 +23,117 @@ impl Row {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+}
+
+/// The keys starting with a prefix, optionally bounded to `[start, end)`.
+#[derive(Clone, Debug)]
+pub struct ScanRange {
+    lower: Bytes,         // inclusive
+    upper: Option<Bytes>, // exclusive (`None` when the prefix is all 0xFF bytes)
+    direction: Direction,
+}
+
+impl ScanRange {
+    pub fn prefix(prefix: &[u8]) -> ScanRange {
+        // the first key after the prefixed ones
+        let mut upper = prefix.to_vec();
+        while upper.last() == Some(&0xFF) {
+            upper.pop();
+        }
+        let upper = upper.pop().map(|last| {
+            upper.push(last + 1);
+            upper
+        });
+        ScanRange {
+            lower: prefix.to_vec(),
+            upper,
+            direction: Direction::Forward,
+        }
+    }
+
+    /// Skips the keys before `key`.
+    pub fn start(mut self, key: &[u8]) -> ScanRange {
+        if key > &self.lower[..] {
+            self.lower = key.to_vec();
+        }
+        self
+    }
+
+    /// Stops before `key`.
+    pub fn end(mut self, key: &[u8]) -> ScanRange {
+        if self.upper.as_ref().map_or(true, |upper| key < &upper[..]) {
+            self.upper = Some(key.to_vec());
+        }
+        self
+    }
+
+    /// Visits the keys from the last one to the first one.
+    pub fn reverse(mut self) -> ScanRange {
+        self.direction = Direction::Reverse;
+        self
+    }
+
+    pub fn lower_bound(&self) -> &[u8] {
+        &self.lower
+    }
+
+    pub fn upper_bound(&self) -> Option<&[u8]> {
+        self.upper.as_ref().map(|key| &key[..])
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    fn position(&self, key: &[u8]) -> Ordering {
+        if key < &self.lower[..] {
+            Ordering::Less
+        } else if self.upper_bound().map_or(false, |upper| key >= upper) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+
+    /// Trims `rows` (sorted in the scan direction, starting at or before the range) to the range.
+    pub fn clip<'a, I>(self, rows: I) -> Box<Iterator<Item = Row> + 'a>
+    where
+        I: Iterator<Item = Row> + 'a,
+    {
+        let before = match self.direction {
+            Direction::Forward => Ordering::Less,
+            Direction::Reverse => Ordering::Greater,
+        };
+        let range = self.clone();
+        Box::new(
+            rows.skip_while(move |row| range.position(&row.key) == before)
+                .take_while(move |row| self.position(&row.key) == Ordering::Equal),
+        )
+    }
+}
+
 pub trait ReadStore: Sync {
     fn get(&self, key: &[u8]) -> Option<Bytes>;
-    fn scan(&self, prefix: &[u8]) -> Vec<Row>;
+
+    /// Lazily visits the rows in `range`, which must not span several indexes.
+    fn iter_range<'a>(&'a self, range: ScanRange) -> Box<Iterator<Item = Row> + 'a>;
+
+    fn iter_scan<'a>(&'a self, prefix: &[u8]) -> Box<Iterator<Item = Row> + 'a> {
+        self.iter_range(ScanRange::prefix(prefix))
+    }
+
+    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        self.iter_scan(prefix).collect()
+    }
 }
 
 pub trait WriteStore: Sync {
     fn write(&self, rows: Vec<Row>);
+    fn delete(&self, keys: Vec<Bytes>);
     fn flush(&self);
 }
 
@@ -37,20 +144,106 @@ struct Options {
     low_memory: bool,
 }
 
+/// Each index is kept in its own column family, so that it can be tuned, compacted and dropped
+/// separately. Rows are routed by the first byte of their key, and the remaining ones (`L`, `F`,
+/// `C`, ...) are kept in the default column family.
+pub struct Family {
+    pub name: &'static str,
+    pub code: u8,
+    compression: rocksdb::DBCompressionType,
+    bloom_filter: bool, // only useful for point lookups (prefix scans can't use it)
+}
+
+pub const FAMILIES: &[Family] = &[
+    Family {
+        name: "txout",
+        code: b'O',
+        compression: rocksdb::DBCompressionType::None, // hash prefixes don't compress
+        bloom_filter: false,
+    },
+    Family {
+        name: "txin",
+        code: b'I',
+        compression: rocksdb::DBCompressionType::None,
+        bloom_filter: false,
+    },
+    Family {
+        name: "tx",
+        code: b'T',
+        compression: rocksdb::DBCompressionType::Snappy,
+        bloom_filter: false,
+    },
+    Family {
+        name: "rawtx",
+        code: b't',
+        compression: rocksdb::DBCompressionType::Zlib,
+        bloom_filter: true,
+    },
+    Family {
+        name: "header",
+        code: b'B',
+        compression: rocksdb::DBCompressionType::Snappy,
+        bloom_filter: false,
+    },
+    Family {
+        name: "blockmeta",
+        code: b'M',
+        compression: rocksdb::DBCompressionType::Snappy,
+        bloom_filter: true,
+    },
+    Family {
+        name: "blocktxids",
+        code: b'X',
+        compression: rocksdb::DBCompressionType::Snappy,
+        bloom_filter: true,
+    },
+    Family {
+        name: "txidsearch",
+        code: b'S',
+        compression: rocksdb::DBCompressionType::None,
+        bloom_filter: false,
+    },
+    Family {
+        name: "addrsearch",
+        code: b'A',
+        compression: rocksdb::DBCompressionType::Snappy,
+        bloom_filter: false,
+    },
+];
+
+const DEFAULT_FAMILY: &str = "default";
+const SPLIT_BATCH_SIZE: usize = 100_000;
+
+/// The names of all the column families, starting with the default one.
+pub fn family_names() -> Vec<&'static str> {
+    Some(DEFAULT_FAMILY)
+        .into_iter()
+        .chain(FAMILIES.iter().map(|family| family.name))
+        .collect()
+}
+
+pub fn family_by_name(name: &str) -> Option<&'static Family> {
+    FAMILIES.iter().find(|family| family.name == name)
+}
+
+fn family_by_key(key: &[u8]) -> Option<&'static Family> {
+    let code = key.first()?;
+    FAMILIES.iter().find(|family| family.code == *code)
+}
+
 pub struct DBStore {
-    db: rocksdb::DB,
+    db: Arc<rocksdb::DB>, // shared with the snapshots
     opts: Options,
 }
 
 impl DBStore {
-    fn open_opts(opts: Options) -> Self {
-        debug!("opening DB at {:?}", opts.path);
+    fn db_opts(opts: &Options, family: Option<&Family>) -> rocksdb::Options {
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
         // db_opts.set_keep_log_file_num(10);
         db_opts.set_max_open_files(if opts.bulk_import { 16 } else { 256 });
         db_opts.set_compaction_style(rocksdb::DBCompactionStyle::Level);
-        db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy);
         db_opts.set_target_file_size_base(256 << 20);
         db_opts.set_write_buffer_size(256 << 20);
         db_opts.set_disable_auto_compactions(opts.bulk_import); // for initial bulk load
@@ -58,12 +251,73 @@ impl DBStore {
         if opts.low_memory == false {
             db_opts.set_compaction_readahead_size(1 << 20);
         }
-
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
+        match family {
+            Some(family) => {
+                db_opts.set_compression_type(family.compression);
+                if family.bloom_filter {
+                    block_opts.set_bloom_filter(10, false);
+                }
+            }
+            None => db_opts.set_compression_type(rocksdb::DBCompressionType::Snappy),
+        }
+        db_opts.set_block_based_table_factory(&block_opts);
+        db_opts
+    }
+
+    fn open_opts(opts: Options) -> Self {
+        debug!("opening DB at {:?}", opts.path);
+        let mut families = vec![rocksdb::ColumnFamilyDescriptor::new(
+            DEFAULT_FAMILY,
+            DBStore::db_opts(&opts, None),
+        )];
+        for family in FAMILIES {
+            families.push(rocksdb::ColumnFamilyDescriptor::new(
+                family.name,
+                DBStore::db_opts(&opts, Some(family)),
+            ));
+        }
+        let db =
+            rocksdb::DB::open_cf_descriptors(&DBStore::db_opts(&opts, None), &opts.path, families)
+                .unwrap();
+        let store = DBStore {
+            db: Arc::new(db),
             opts,
+        };
+        store.split_default_family();
+        store
+    }
+
+    // Databases created before the index rows were split into column families kept all of them
+    // in the default one. They are moved in atomic batches, so an interrupted run is resumed on
+    // the next startup.
+    fn split_default_family(&self) {
+        for family in FAMILIES {
+            let mut count = 0;
+            let mut batch = rocksdb::WriteBatch::default();
+            for (key, value) in self.db.prefix_iterator(&[family.code]) {
+                if key.first() != Some(&family.code) {
+                    break;
+                }
+                batch.delete(&key).unwrap();
+                batch
+                    .put_cf(cf(&self.db, family.name), &key, &value)
+                    .unwrap();
+                count += 1;
+                if count % SPLIT_BATCH_SIZE == 0 {
+                    self.db
+                        .write(mem::replace(&mut batch, rocksdb::WriteBatch::default()))
+                        .unwrap();
+                }
+            }
+            self.db.write(batch).unwrap();
+            if count > 0 {
+                info!(
+                    "moved {} rows to the `{}` column family",
+                    count, family.name
+                );
+            }
         }
     }
 
@@ -94,66 +348,109 @@ impl DBStore {
 
         let store = DBStore::open_opts(opts);
         info!("starting full compaction");
-        store.db.compact_range(None, None); // would take a while
+        store.db.compact_range(None, None);
+        for family in FAMILIES {
+            store.compact_family(family); // would take a while
+        }
         info!("finished full compaction");
         store
     }
 
-    pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
-        ScanIterator {
-            prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
-            done: false,
+    pub fn compact_family(&self, family: &Family) {
+        debug!("compacting `{}` column family", family.name);
+        self.db
+            .compact_range_cf(cf(&self.db, family.name), None, None);
+    }
+
+    /// Deletes all the rows of `family`, much faster than deleting them one by one.
+    /// Fails if a snapshot of the DB is still alive.
+    pub fn drop_family(&mut self, family: &Family) -> Result<()> {
+        info!("dropping `{}` column family", family.name);
+        let db_opts = DBStore::db_opts(&self.opts, Some(family));
+        let db = Arc::get_mut(&mut self.db).chain_err(|| {
+            format!(
+                "cannot drop the `{}` column family while snapshotted",
+                family.name
+            )
+        })?;
+        db.drop_cf(family.name)
+            .chain_err(|| format!("failed to drop the `{}` column family", family.name))?;
+        db.create_cf(family.name, &db_opts)
+            .chain_err(|| format!("failed to create the `{}` column family", family.name))?;
+        Ok(())
+    }
+
+    /// Atomically deletes `keys` and writes `rows`.
+    pub fn rewrite(&self, keys: Vec<Bytes>, rows: Vec<Row>) {
+        let mut batch = rocksdb::WriteBatch::default();
+        for key in keys {
+            batch.delete_cf(cf_by_key(&self.db, &key), &key).unwrap();
+        }
+        for row in rows {
+            let cf = cf_by_key(&self.db, &row.key);
+            batch.put_cf(cf, &row.key, &row.value).unwrap();
+        }
+        self.db.write(batch).unwrap();
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.opts.path
+    }
+
+    /// Takes a consistent view of the DB, unaffected by later writes.
+    pub fn snapshot(&self) -> DBSnapshot {
+        // the snapshot is only kept in its result
+        let snapshot = unsafe { OwningRef::new(self.db.clone(), |db| db.snapshot()) };
+        DBSnapshot {
+            view: View::Snapshot(snapshot),
         }
     }
 }
 
-pub struct ScanIterator {
-    prefix: Vec<u8>,
-    iter: rocksdb::DBIterator,
-    done: bool,
+fn cf(db: &rocksdb::DB, name: &str) -> rocksdb::ColumnFamily {
+    db.cf_handle(name)
+        .unwrap_or_else(|| panic!("missing column family {}", name))
 }
 
-impl Iterator for ScanIterator {
-    type Item = Row;
+fn family_name(key: &[u8]) -> &'static str {
+    family_by_key(key).map_or(DEFAULT_FAMILY, |family| family.name)
+}
+
+fn cf_by_key(db: &rocksdb::DB, key: &[u8]) -> rocksdb::ColumnFamily {
+    cf(db, family_name(key))
+}
 
-    fn next(&mut self) -> Option<Row> {
-        if self.done {
-            return None;
+fn iterator_mode(range: &ScanRange) -> rocksdb::IteratorMode {
+    match (range.direction(), range.upper_bound()) {
+        (Direction::Forward, _) => {
+            rocksdb::IteratorMode::From(range.lower_bound(), rocksdb::Direction::Forward)
         }
-        let (key, value) = self.iter.next()?;
-        if !key.starts_with(&self.prefix) {
-            self.done = true;
-            return None;
+        (Direction::Reverse, Some(upper)) => {
+            rocksdb::IteratorMode::From(upper, rocksdb::Direction::Reverse)
         }
-        Some(Row {
-            key: key.to_vec(),
-            value: value.to_vec(),
-        })
+        (Direction::Reverse, None) => rocksdb::IteratorMode::End,
     }
 }
 
+fn clip_rows<'a>(range: ScanRange, iter: rocksdb::DBIterator) -> Box<Iterator<Item = Row> + 'a> {
+    range.clip(iter.map(|(key, value)| Row {
+        key: key.to_vec(),
+        value: value.to_vec(),
+    }))
+}
+
 impl ReadStore for DBStore {
     fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+        self.db
+            .get_cf(cf_by_key(&self.db, key), key)
+            .unwrap()
+            .map(|v| v.to_vec())
     }
 
-    // TODO: use generators
-    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
-        let mut rows = vec![];
-        for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
-            prefix,
-            rocksdb::Direction::Forward,
-        )) {
-            if !key.starts_with(prefix) {
-                break;
-            }
-            rows.push(Row {
-                key: key.to_vec(),
-                value: value.to_vec(),
-            });
-        }
-        rows
+    fn iter_range<'a>(&'a self, range: ScanRange) -> Box<Iterator<Item = Row> + 'a> {
+        let cf = cf_by_key(&self.db, range.lower_bound());
+        let iter = self.db.iterator_cf(cf, iterator_mode(&range)).unwrap();
+        clip_rows(range, iter)
     }
 }
 
@@ -161,7 +458,8 @@ impl WriteStore for DBStore {
     fn write(&self, rows: Vec<Row>) {
         let mut batch = rocksdb::WriteBatch::default();
         for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
+            let cf = cf_by_key(&self.db, &row.key);
+            batch.put_cf(cf, &row.key, &row.value).unwrap();
         }
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(!self.opts.bulk_import);
@@ -169,6 +467,10 @@ impl WriteStore for DBStore {
         self.db.write_opt(batch, &opts).unwrap();
     }
 
+    fn delete(&self, keys: Vec<Bytes>) {
+        self.rewrite(keys, vec![]);
+    }
+
     fn flush(&self) {
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
@@ -178,6 +480,131 @@ impl WriteStore for DBStore {
     }
 }
 
+/// Keeps a value borrowing `owner` along with the `Arc` keeping it alive, which lifetimes can't
+/// express (e.g. a `rocksdb::Snapshot` kept along with its DB, so that it can be shared between
+/// requests). The value is only handed out while the wrapper is borrowed, and is dropped before
+/// the `Arc` is released, so the reference it was built from never dangles.
+struct OwningRef<O: 'static, T> {
+    value: ManuallyDrop<T>,
+    owner: Arc<O>,
+}
+
+impl<O, T> OwningRef<O, T> {
+    /// `borrow` must not keep the reference it gets anywhere but in its result.
+    unsafe fn new<F>(owner: Arc<O>, borrow: F) -> OwningRef<O, T>
+    where
+        F: FnOnce(&'static O) -> T,
+    {
+        // the `Arc` keeps `owner` alive, at the same address, until the wrapper is dropped
+        let target: &'static O = &*(&*owner as *const O);
+        OwningRef {
+            value: ManuallyDrop::new(borrow(target)),
+            owner,
+        }
+    }
+
+    fn owner(&self) -> &O {
+        &self.owner
+    }
+}
+
+impl<O, T> Deref for OwningRef<O, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<O, T> Drop for OwningRef<O, T> {
+    fn drop(&mut self) {
+        // `owner` is only released after this
+        unsafe { ManuallyDrop::drop(&mut self.value) }
+    }
+}
+
+// `rocksdb::Snapshot` only holds raw pointers, to an immutable RocksDB snapshot and to the DB
+// (which is `Send` and `Sync`), so it can be read from any thread.
+unsafe impl Send for OwningRef<rocksdb::DB, rocksdb::Snapshot<'static>> {}
+unsafe impl Sync for OwningRef<rocksdb::DB, rocksdb::Snapshot<'static>> {}
+
+enum View {
+    Snapshot(OwningRef<rocksdb::DB, rocksdb::Snapshot<'static>>),
+    ReadOnly(ReadOnlyDB),
+}
+
+/// A read-only view of the DB, as of `DBStore::snapshot()` or `DBSnapshot::open_read_only()`.
+pub struct DBSnapshot {
+    view: View,
+}
+
+impl DBSnapshot {
+    /// Opens the DB written by another process at `path`, as of now (see `ReadOnlyDB`).
+    pub fn open_read_only(path: &Path) -> Result<DBSnapshot> {
+        let db = ReadOnlyDB::open(path, &family_names())?;
+        Ok(DBSnapshot {
+            view: View::ReadOnly(db),
+        })
+    }
+
+    /// Creates a checkpoint of the DB at `path` (see `ReadOnlyDB::checkpoint`), which must not
+    /// exist. Only read-only views can be checkpointed, since RocksDB checkpoints a whole DB
+    /// rather than a snapshot of it.
+    pub fn backup_to(&self, path: &Path) -> Result<()> {
+        match self.view {
+            View::Snapshot(_) => bail!("cannot checkpoint a snapshot, open the DB read-only"),
+            View::ReadOnly(ref db) => db.checkpoint(path),
+        }
+    }
+
+    /// All the rows of a column family (see `family_names`), in key order.
+    pub fn family_rows<'a>(&'a self, name: &str) -> Box<Iterator<Item = Result<Row>> + 'a> {
+        match self.view {
+            View::Snapshot(ref snapshot) => {
+                let iter = snapshot
+                    .iterator_cf(cf(snapshot.owner(), name), rocksdb::IteratorMode::Start)
+                    .unwrap();
+                Box::new(iter.map(|(key, value)| {
+                    Ok(Row {
+                        key: key.to_vec(),
+                        value: value.to_vec(),
+                    })
+                }))
+            }
+            View::ReadOnly(ref db) => Box::new(db.rows(name, None)),
+        }
+    }
+}
+
+impl ReadStore for DBSnapshot {
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        match self.view {
+            View::Snapshot(ref snapshot) => snapshot
+                .get_cf(cf_by_key(snapshot.owner(), key), key)
+                .unwrap()
+                .map(|v| v.to_vec()),
+            View::ReadOnly(ref db) => db.get(family_name(key), key).unwrap(),
+        }
+    }
+
+    fn iter_range<'a>(&'a self, range: ScanRange) -> Box<Iterator<Item = Row> + 'a> {
+        match self.view {
+            View::Snapshot(ref snapshot) => {
+                let cf = cf_by_key(snapshot.owner(), range.lower_bound());
+                let iter = snapshot.iterator_cf(cf, iterator_mode(&range)).unwrap();
+                clip_rows(range, iter)
+            }
+            View::ReadOnly(ref db) => {
+                // `ReadStore` can't report errors, a failed read is fatal as for `get`
+                let rows = db
+                    .rows(family_name(range.lower_bound()), Some(&range))
+                    .map(|row| row.unwrap());
+                range.clip(rows)
+            }
+        }
+    }
+}
+
 impl Drop for DBStore {
     fn drop(&mut self) {
         trace!("closing DB at {:?}", self.opts.path);
@@ -203,19 +630,101 @@ pub fn is_fully_compacted(store: &ReadStore) -> bool {
     marker.is_some()
 }
 
-pub fn verify_index_compatibility(store: &DBStore, config: &Config) {
-    let compatibility_bytes =
-        bincode::serialize(&(config.network_type, DB_VERSION, config.extended_db_enabled)).unwrap();
+#[cfg(test)]
+mod tests {
+    use super::{OwningRef, Row, ScanRange};
+    use std::sync::{Arc, Mutex};
 
-    match store.get(b"C") {
-        None => store.write(vec![Row {
-            key: b"C".to_vec(),
-            value: compatibility_bytes,
-        }]),
-        Some(x) => {
-            if x != compatibility_bytes {
-                panic!("Incompatible database found. Changing --light mode requires a reindex.");
+    fn scan(range: ScanRange, keys: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut rows: Vec<Row> = keys
+            .iter()
+            .map(|key| Row {
+                key: key.to_vec(),
+                value: vec![],
+            })
+            .collect();
+        if range.direction() == super::Direction::Reverse {
+            rows.reverse();
+        }
+        range.clip(rows.into_iter()).map(|row| row.key).collect()
+    }
+
+    #[test]
+    fn test_scan_range() {
+        let keys: &[&[u8]] = &[b"A", b"B\x00", b"B\x01", b"B\x02", b"B\xff", b"C"];
+        assert_eq!(
+            scan(ScanRange::prefix(b"B"), keys),
+            vec![
+                b"B\x00".to_vec(),
+                b"B\x01".to_vec(),
+                b"B\x02".to_vec(),
+                b"B\xff".to_vec()
+            ]
+        );
+        assert_eq!(
+            scan(ScanRange::prefix(b"B").start(b"B\x01").end(b"B\x02"), keys),
+            vec![b"B\x01".to_vec()]
+        );
+        assert_eq!(
+            scan(ScanRange::prefix(b"B").end(b"B\x02").reverse(), keys),
+            vec![b"B\x01".to_vec(), b"B\x00".to_vec()]
+        );
+        assert_eq!(
+            scan(ScanRange::prefix(b"B\xff").reverse(), keys),
+            vec![b"B\xff".to_vec()]
+        );
+        // bounds outside of the prefix don't widen it
+        assert_eq!(
+            scan(
+                ScanRange::prefix(b"B").start(b"A").end(b"D").reverse(),
+                keys
+            )
+            .len(),
+            4
+        );
+        assert!(scan(ScanRange::prefix(b"B").start(b"B\x02").end(b"B\x01"), keys).is_empty());
+
+        let all = ScanRange::prefix(b"\xff\xff");
+        assert_eq!(all.upper_bound(), None);
+    }
+
+    #[test]
+    fn test_owning_ref_drop_order() {
+        struct Logged(&'static str, Arc<Mutex<Vec<&'static str>>>);
+
+        impl Drop for Logged {
+            fn drop(&mut self) {
+                self.1.lock().unwrap().push(self.0);
+            }
+        }
+
+        struct Borrower(&'static Logged, Arc<Mutex<Vec<&'static str>>>);
+
+        impl Drop for Borrower {
+            fn drop(&mut self) {
+                let mut log = self.1.lock().unwrap();
+                // the borrowed value must still be alive
+                assert!(!log.contains(&self.0 .0));
+                log.push("borrower");
             }
         }
+
+        let log = Arc::new(Mutex::new(vec![]));
+        let owner = Arc::new(Logged("owner", log.clone()));
+        let borrower_log = log.clone();
+        let wrapper = unsafe { OwningRef::new(owner, |owner| Borrower(owner, borrower_log)) };
+        assert_eq!(wrapper.0 .0, "owner");
+        drop(wrapper);
+        assert_eq!(*log.lock().unwrap(), vec!["borrower", "owner"]);
+
+        // other references to the owner keep it alive
+        let owner = Arc::new(Logged("shared", log.clone()));
+        let borrower_log = log.clone();
+        let wrapper =
+            unsafe { OwningRef::new(owner.clone(), |owner| Borrower(owner, borrower_log)) };
+        log.lock().unwrap().clear();
+        drop(wrapper);
+        assert_eq!(*log.lock().unwrap(), vec!["borrower"]);
+        assert_eq!(owner.0, "shared");
     }
 }
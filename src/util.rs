@@ -2,9 +2,18 @@ use bitcoin::blockdata::block::{Block, BlockHeader};
 use bitcoin::consensus::encode::serialize;
 use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
 use errors::*;
-use std::collections::HashMap;
+use hex;
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::fs;
+use std::hash::{Hash, Hasher};
 use std::iter::FromIterator;
+use std::ops::Deref;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::Path;
 use std::slice;
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
 use std::thread;
@@ -14,20 +23,148 @@ pub type Bytes = Vec<u8>;
 pub type HeaderMap = HashMap<Sha256dHash, BlockHeader>;
 
 // TODO: consolidate serialization/deserialize code for bincode/bitcoin.
-const HASH_LEN: usize = 32;
-pub const HASH_PREFIX_LEN: usize = 8;
+pub const HASH_LEN: usize = 32;
+pub const DEFAULT_HASH_PREFIX_LEN: usize = 8;
+pub const MIN_HASH_PREFIX_LEN: usize = 4;
 
 pub type FullHash = [u8; HASH_LEN];
-pub type HashPrefix = [u8; HASH_PREFIX_LEN];
 
-pub fn hash_prefix(hash: &[u8]) -> HashPrefix {
-    array_ref![hash, 0, HASH_PREFIX_LEN].clone()
+/// The first bytes of a hash, as kept by the `I` and `O` rows (see `index::RowCodec`).
+/// Stored inline, since one is parsed for every scanned row.
+#[derive(Clone, Copy)]
+pub struct HashPrefix {
+    len: u8,
+    bytes: FullHash,
+}
+
+impl HashPrefix {
+    pub fn new(prefix: &[u8]) -> HashPrefix {
+        assert!(prefix.len() <= HASH_LEN, "hash prefix is too long");
+        let mut bytes = FullHash::default();
+        bytes[..prefix.len()].copy_from_slice(prefix);
+        HashPrefix {
+            len: prefix.len() as u8,
+            bytes,
+        }
+    }
+}
+
+impl Deref for HashPrefix {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl Borrow<[u8]> for HashPrefix {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for HashPrefix {
+    fn eq(&self, other: &HashPrefix) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for HashPrefix {}
+
+impl PartialOrd for HashPrefix {
+    fn partial_cmp(&self, other: &HashPrefix) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HashPrefix {
+    fn cmp(&self, other: &HashPrefix) -> Ordering {
+        self[..].cmp(&other[..])
+    }
+}
+
+impl Hash for HashPrefix {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self[..].hash(state)
+    }
+}
+
+impl fmt::Debug for HashPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HashPrefix({})", hex::encode(&self[..]))
+    }
+}
+
+/// Appends `value` as a LEB128 varint: 7 bits per byte, with the high bit set on all bytes
+/// but the last one. The encoding is prefix-free, so it can be followed by more key bytes.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Returns the decoded value and the number of bytes it used.
+pub fn read_varint(buf: &[u8]) -> Option<(u32, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in buf.iter().enumerate().take(5) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return if value <= u64::from(u32::max_value()) {
+                Some((value as u32, i + 1))
+            } else {
+                None
+            };
+        }
+    }
+    None
 }
 
 pub fn full_hash(hash: &[u8]) -> FullHash {
     array_ref![hash, 0, HASH_LEN].clone()
 }
 
+/// Checks whether the hex representation of `hash` (i.e. its reversed bytes) starts with `prefix`.
+pub fn hash_has_hex_prefix(hash: &Sha256dHash, prefix: &str) -> bool {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let bytes = &hash[..];
+    let prefix = prefix.as_bytes();
+    prefix.len() <= 2 * HASH_LEN
+        && prefix.iter().enumerate().all(|(i, c)| {
+            let byte = bytes[HASH_LEN - 1 - i / 2];
+            let nibble = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+            HEX_DIGITS[nibble as usize] == c.to_ascii_lowercase()
+        })
+}
+
+/// Returns the smallest and largest display-order (i.e. reversed) hashes starting with the hex
+/// `prefix`, or `None` if it isn't a valid hex prefix.
+pub fn hex_prefix_range(prefix: &str) -> Option<(FullHash, FullHash)> {
+    if prefix.len() > 2 * HASH_LEN {
+        return None;
+    }
+    let mut first = [0u8; HASH_LEN];
+    let mut last = [0xffu8; HASH_LEN];
+    for (i, c) in prefix.chars().enumerate() {
+        let nibble = c.to_digit(16)? as u8;
+        if i % 2 == 0 {
+            first[i / 2] = nibble << 4;
+            last[i / 2] = nibble << 4 | 0x0f;
+        } else {
+            first[i / 2] |= nibble;
+            last[i / 2] = last[i / 2] & 0xf0 | nibble;
+        }
+    }
+    Some((first, last))
+}
+
+fn display_order(hash: &Sha256dHash) -> FullHash {
+    let mut bytes = full_hash(&hash[..]);
+    bytes.reverse();
+    bytes
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TransactionStatus {
     pub confirmed: bool,
@@ -142,6 +279,7 @@ impl fmt::Debug for HeaderEntry {
 pub struct HeaderList {
     headers: Vec<HeaderEntry>,
     heights: HashMap<Sha256dHash, usize>,
+    by_display_hash: BTreeMap<FullHash, usize>, // for hex prefix lookups
     tip: Sha256dHash,
 }
 
@@ -150,6 +288,7 @@ impl HeaderList {
         HeaderList {
             headers: vec![],
             heights: HashMap::new(),
+            by_display_hash: BTreeMap::new(),
             tip: Sha256dHash::default(),
         }
     }
@@ -221,11 +360,16 @@ impl HeaderList {
             new_headers.len(),
             new_height
         );
-        self.headers.split_off(new_height); // keep [0..new_height) entries
+        // keep [0..new_height) entries
+        for stale in self.headers.split_off(new_height) {
+            self.by_display_hash.remove(&display_order(stale.hash()));
+        }
         for new_header in new_headers {
             let height = new_header.height();
             assert_eq!(height, self.headers.len());
             self.tip = *new_header.hash();
+            self.by_display_hash
+                .insert(display_order(&self.tip), height);
             self.headers.push(new_header);
             self.heights.insert(self.tip, height);
         }
@@ -241,6 +385,19 @@ impl HeaderList {
         }
     }
 
+    /// Returns up to `limit` headers whose hex blockhash starts with `prefix`, in hex order.
+    pub fn headers_by_hex_prefix(&self, prefix: &str, limit: usize) -> Vec<&HeaderEntry> {
+        let (first, last) = match hex_prefix_range(prefix) {
+            Some(range) => range,
+            None => return vec![],
+        };
+        self.by_display_hash
+            .range(first..=last)
+            .take(limit)
+            .map(|(_, height)| &self.headers[*height])
+            .collect()
+    }
+
     pub fn header_by_height(&self, height: usize) -> Option<&HeaderEntry> {
         self.headers.get(height).map(|entry| {
             assert_eq!(entry.height(), height);
@@ -320,6 +477,22 @@ impl<T> Channel<T> {
     }
 }
 
+/// Binds a Unix domain socket with the given permissions, replacing a stale socket left
+/// behind by a previous run.
+pub fn bind_unix_socket(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{:?} already exists and is not a socket", path);
+        }
+        fs::remove_file(path).chain_err(|| format!("failed to remove stale socket {:?}", path))?;
+    }
+    let listener =
+        UnixListener::bind(path).chain_err(|| format!("failed to bind Unix socket {:?}", path))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .chain_err(|| format!("failed to set permissions of {:?}", path))?;
+    Ok(listener)
+}
+
 pub fn spawn_thread<F, T>(name: &str, f: F) -> thread::JoinHandle<T>
 where
     F: FnOnce() -> T,
@@ -390,3 +563,122 @@ pub fn get_script_asm(script: &Script) -> String {
     let asm = format!("{:?}", script);
     (&asm[7..asm.len() - 1]).to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::blockdata::transaction::Transaction;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use util::{
+        hash_has_hex_prefix, hex_prefix_range, read_varint, write_varint, HeaderEntry, HeaderList,
+    };
+
+    #[test]
+    fn test_hash_has_hex_prefix() {
+        let hex = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+        let hash = Sha256dHash::from_hex(hex).unwrap();
+        assert!(hash_has_hex_prefix(&hash, ""));
+        assert!(hash_has_hex_prefix(&hash, "0000000000"));
+        assert!(hash_has_hex_prefix(&hash, "000000000019d"));
+        assert!(hash_has_hex_prefix(&hash, "000000000019D6"));
+        assert!(hash_has_hex_prefix(&hash, hex));
+        assert!(!hash_has_hex_prefix(&hash, "000000000019e"));
+        assert!(!hash_has_hex_prefix(&hash, "6fe28c0a"));
+        assert!(!hash_has_hex_prefix(&hash, &format!("{}0", hex)));
+    }
+
+    fn test_header(prev_blockhash: Sha256dHash, nonce: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: Sha256dHash::default(),
+            time: 0,
+            bits: 0,
+            nonce,
+            coinbase_txn: Transaction {
+                version: 1,
+                lock_time: 0,
+                input: vec![],
+                output: vec![],
+            },
+            block_hash: Sha256dHash::default(),
+            coinbase_branch_hashes: vec![],
+            coinbase_branch_side_mask: 0,
+            blockchain_branch_hashes: vec![],
+            blockchain_branch_side_mask: 0,
+            parent_version: 0,
+            parent_prev_blockhash: Sha256dHash::default(),
+            parent_merkle_root: Sha256dHash::default(),
+            parent_time: 0,
+            parent_bits: 0,
+            parent_nonce: 0,
+        }
+    }
+
+    #[test]
+    fn test_hex_prefix_range() {
+        let (first, last) = hex_prefix_range("0a1").unwrap();
+        assert_eq!(first[..3], [0x0a, 0x10, 0x00]);
+        assert_eq!(last[..3], [0x0a, 0x1f, 0xff]);
+        let (first, last) = hex_prefix_range("0A").unwrap();
+        assert_eq!(
+            (first[0], first[1], last[0], last[1]),
+            (0x0a, 0x00, 0x0a, 0xff)
+        );
+        let (first, last) = hex_prefix_range("").unwrap();
+        assert_eq!((first, last), ([0; 32], [0xff; 32]));
+        assert!(hex_prefix_range("0g").is_none());
+        assert!(hex_prefix_range(&"0".repeat(65)).is_none());
+    }
+
+    #[test]
+    fn test_headers_by_hex_prefix() {
+        let mut headers = HeaderList::empty();
+        let mut prev_blockhash = Sha256dHash::default();
+        let mut blocks = vec![];
+        for nonce in 0..50 {
+            let header = test_header(prev_blockhash, nonce);
+            prev_blockhash = header.bitcoin_hash();
+            blocks.push(header);
+        }
+        let entries = headers.order(blocks.clone());
+        headers.apply(entries);
+        for entry in headers.iter() {
+            let hex = entry.hash().be_hex_string();
+            let found = headers.headers_by_hex_prefix(&hex[..3], 50);
+            let expected: Vec<&HeaderEntry> = headers
+                .iter()
+                .filter(|h| hash_has_hex_prefix(h.hash(), &hex[..3]))
+                .collect();
+            assert!(found.contains(&entry));
+            assert_eq!(found.len(), expected.len());
+        }
+        assert_eq!(headers.headers_by_hex_prefix("", 10).len(), 10);
+
+        // a reorg replaces the index entries of the stale blocks
+        let stale = blocks[40].bitcoin_hash().be_hex_string();
+        let fork = test_header(blocks[40].prev_blockhash, 1000);
+        let entries = headers.order(vec![fork.clone()]);
+        headers.apply(entries);
+        assert_eq!(headers.len(), 41);
+        assert!(headers.headers_by_hex_prefix(&stale, 1).is_empty());
+        let fork_hex = fork.bitcoin_hash().be_hex_string();
+        assert_eq!(headers.headers_by_hex_prefix(&fork_hex, 1)[0].height(), 40);
+        assert_eq!(headers.headers_by_hex_prefix("", 100).len(), 41);
+    }
+
+    #[test]
+    fn test_varint() {
+        for &value in &[0, 1, 127, 128, 300, 65535, 65536, u32::max_value()] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value);
+            buf.push(0xff); // trailing key bytes are left alone
+            assert_eq!(read_varint(&buf), Some((value, buf.len() - 1)));
+        }
+        let mut buf = vec![];
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0xac, 0x02]);
+        assert_eq!(read_varint(&buf[..1]), None);
+        assert_eq!(read_varint(&[0x80; 6]), None);
+    }
+}
@@ -0,0 +1,681 @@
+use bincode;
+use bitcoin::blockdata::block::Block;
+use bitcoin::blockdata::transaction::Transaction;
+use bitcoin::consensus::encode::deserialize;
+use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+use std::collections::{HashMap, HashSet};
+
+use daemon::Daemon;
+use index::{
+    index_block, index_transaction, last_indexed_block, read_indexed_headers, txid_search_key,
+    RawTxRow, RowCodec, TxRow,
+};
+use metrics::{Counter, CounterVec, Gauge, MetricOpts, Metrics};
+use query::get_block_txids;
+use store::{DBStore, ReadStore, Row, WriteStore};
+use util::{Bytes, HashPrefix, HeaderEntry, HeaderList};
+
+use errors::*;
+
+const VERIFY_BATCH_SIZE: usize = 10; // blocks fetched from the daemon at once
+
+/// How the stored rows of some blocks differ from the rows recomputed from the daemon's blocks.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub blocks: usize,
+    pub missing: usize,
+    pub mismatched: usize,
+    pub extra: usize,
+}
+
+struct Stats {
+    height: Gauge,
+    blocks: Counter,
+    rows: CounterVec,
+}
+
+impl Stats {
+    fn new(metrics: &Metrics) -> Stats {
+        Stats {
+            height: metrics.gauge(MetricOpts::new(
+                "verify_height",
+                "Last verified block's height",
+            )),
+            blocks: metrics.counter(MetricOpts::new("verify_blocks", "# of verified blocks")),
+            rows: metrics.counter_vec(
+                MetricOpts::new(
+                    "verify_rows",
+                    "# of rows differing from the daemon's blocks",
+                ),
+                &["kind"],
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct BlockDiff {
+    missing: Vec<Row>,
+    mismatched: Vec<Row>,
+    extra: Vec<Bytes>,
+}
+
+/// Recomputes the rows of the indexed blocks in `[from, to]` and compares them with the stored
+/// ones, since an unclean shutdown during the bulk import (which skips the WAL) may lose rows.
+/// With `repair`, the expected rows are written and the extra ones deleted.
+pub fn verify_index(
+    store: &DBStore,
+    daemon: &Daemon,
+    metrics: &Metrics,
+    (from, to): (usize, usize),
+    codec: RowCodec,
+    extended_db_enabled: bool,
+    repair: bool,
+) -> Result<VerifyReport> {
+    let headers = read_indexed_headers(store);
+    check_range(&headers, from, to)?;
+    let stats = Stats::new(metrics);
+    let mut report = VerifyReport::default();
+    info!("verifying blocks {}..{} (repair: {})", from, to, repair);
+    let heights: Vec<usize> = (from..=to).collect();
+    for chunk in heights.chunks(VERIFY_BATCH_SIZE) {
+        let blockhashes: Vec<Sha256dHash> = chunk
+            .iter()
+            .map(|height| *headers.header_by_height(*height).unwrap().hash())
+            .collect();
+        let blocks = daemon.getblocks(&blockhashes)?;
+        for (height, block) in chunk.iter().zip(blocks.iter()) {
+            let rows = index_block(codec, block, *height as u32, extended_db_enabled);
+            let diff = diff_block(store, codec, &rows);
+            if !diff.missing.is_empty() || !diff.mismatched.is_empty() || !diff.extra.is_empty() {
+                warn!(
+                    "block {} at height {}: {} missing, {} mismatched and {} extra rows",
+                    blockhashes[height - chunk[0]],
+                    height,
+                    diff.missing.len(),
+                    diff.mismatched.len(),
+                    diff.extra.len()
+                );
+            }
+            stats
+                .rows
+                .with_label_values(&["missing"])
+                .inc_by(diff.missing.len() as i64);
+            stats
+                .rows
+                .with_label_values(&["mismatched"])
+                .inc_by(diff.mismatched.len() as i64);
+            stats
+                .rows
+                .with_label_values(&["extra"])
+                .inc_by(diff.extra.len() as i64);
+            report.missing += diff.missing.len();
+            report.mismatched += diff.mismatched.len();
+            report.extra += diff.extra.len();
+            if repair {
+                let mut rows = diff.missing;
+                rows.extend(diff.mismatched);
+                store.rewrite(diff.extra, rows);
+            }
+            report.blocks += 1;
+            stats.blocks.inc();
+            stats.height.set(*height as i64);
+        }
+        debug!("verified blocks up to {}", chunk.last().unwrap());
+    }
+    info!("verification done: {:?}", report);
+    Ok(report)
+}
+
+// Progress of a reindex (see `reindex_range`), so that an interrupted one is completed by the
+// next startup. Meanwhile, the last indexed block (`L`) isn't moved.
+const REINDEX_PROGRESS_KEY: &[u8] = b"R";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum ReindexPhase {
+    /// Deleting the `I` and `O` rows of the transactions without a `t` row, by scanning them all
+    Scanning,
+    /// Deleting the rows of the indexed blocks, from height `next`
+    Deleting { next: u32 },
+    /// Indexing the daemon's blocks, from height `next` (after the block `last`)
+    Rebuilding { next: u32, last: Sha256dHash },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReindexProgress {
+    from: u32,
+    to: u32,
+    phase: ReindexPhase,
+}
+
+impl ReindexProgress {
+    fn to_row(&self) -> Row {
+        Row {
+            key: REINDEX_PROGRESS_KEY.to_vec(),
+            value: bincode::serialize(self).unwrap(),
+        }
+    }
+}
+
+fn read_reindex_progress(store: &ReadStore) -> Result<Option<ReindexProgress>> {
+    match store.get(REINDEX_PROGRESS_KEY) {
+        Some(value) => Ok(Some(
+            bincode::deserialize(&value).chain_err(|| "invalid reindex progress row")?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// The range of an interrupted reindex, which must be completed before serving the index.
+pub fn interrupted_reindex(store: &ReadStore) -> Result<Option<(usize, usize)>> {
+    Ok(
+        read_reindex_progress(store)?
+            .map(|progress| (progress.from as usize, progress.to as usize)),
+    )
+}
+
+/// Deletes the rows of the indexed blocks in `[from, to]`, and indexes the daemon's blocks at
+/// these heights instead (e.g. after a reorg was mishandled). Returns the last indexed block.
+pub fn reindex_range(
+    store: &DBStore,
+    daemon: &Daemon,
+    (from, to): (usize, usize),
+    codec: RowCodec,
+    extended_db_enabled: bool,
+) -> Result<Sha256dHash> {
+    let headers = read_indexed_headers(store);
+    check_range(&headers, from, to)?;
+    if from > 0 {
+        let daemon_header = &daemon.getblockheaders(&[from - 1])?[0];
+        if headers.header_by_height(from - 1).unwrap().header() != daemon_header {
+            bail!(
+                "the daemon's chain differs below height {}, reindex from an earlier height",
+                from
+            );
+        }
+    }
+    reindex_blocks(
+        store,
+        &headers,
+        (from, to),
+        codec,
+        extended_db_enabled,
+        |heights| {
+            let blockhashes: Vec<Sha256dHash> = daemon
+                .getblockheaders(heights)?
+                .iter()
+                .map(|header| header.bitcoin_hash())
+                .collect();
+            daemon.getblocks(&blockhashes)
+        },
+    )
+}
+
+// The old blocks' rows are found in the DB, since the daemon may not have them anymore (e.g.
+// stale blocks). They are all deleted before any new one is written, since transactions moved
+// by a reorg keep their rows' keys.
+fn reindex_blocks<S, F>(
+    store: &S,
+    headers: &HeaderList,
+    (from, to): (usize, usize),
+    codec: RowCodec,
+    extended_db_enabled: bool,
+    mut get_blocks: F,
+) -> Result<Sha256dHash>
+where
+    S: ReadStore + WriteStore,
+    F: FnMut(&[usize]) -> Result<Vec<Block>>,
+{
+    let mut progress = match read_reindex_progress(store)? {
+        Some(progress) => {
+            if (progress.from as usize, progress.to as usize) != (from, to) {
+                bail!(
+                    "the reindex of blocks {}..{} was interrupted, complete it first",
+                    progress.from,
+                    progress.to
+                );
+            }
+            info!("resuming the reindex of blocks {}..{}", from, to);
+            progress
+        }
+        None => {
+            info!("reindexing blocks {}..{}", from, to);
+            ReindexProgress {
+                from: from as u32,
+                to: to as u32,
+                phase: ReindexPhase::Scanning,
+            }
+        }
+    };
+    let old_blocks: Vec<&HeaderEntry> = (from..=to)
+        .map(|height| headers.header_by_height(height).unwrap())
+        .collect();
+    if progress.phase == ReindexPhase::Scanning {
+        store.write(vec![progress.to_row()]);
+        let txids = old_txids(store, &old_blocks)?;
+        let count = delete_scanned_rows(store, codec, &txids);
+        debug!("deleted {} rows of transactions without raw rows", count);
+        progress.phase = ReindexPhase::Deleting { next: from as u32 };
+        store.write(vec![progress.to_row()]);
+    }
+    if let ReindexPhase::Deleting { next } = progress.phase {
+        let old_blocks = &old_blocks[next as usize - from..];
+        let txids = old_txids(store, old_blocks)?;
+        for entry in old_blocks {
+            let keys = old_block_rows(store, codec, entry, &txids[entry.hash()])?;
+            store.delete(keys);
+            progress.phase = ReindexPhase::Deleting {
+                next: entry.height() as u32 + 1,
+            };
+            store.write(vec![progress.to_row()]);
+        }
+        debug!("deleted blocks {}..{}", from, to);
+        progress.phase = ReindexPhase::Rebuilding {
+            next: from as u32,
+            last: Sha256dHash::default(),
+        };
+        store.write(vec![progress.to_row()]);
+    }
+    let mut tip = match progress.phase {
+        ReindexPhase::Rebuilding { next, last } => {
+            let heights: Vec<usize> = (next as usize..=to).collect();
+            let mut last = last;
+            for chunk in heights.chunks(VERIFY_BATCH_SIZE) {
+                let blocks = get_blocks(chunk)?;
+                for (height, block) in chunk.iter().zip(blocks.iter()) {
+                    let rows = index_block(codec, block, *height as u32, extended_db_enabled);
+                    // rows left by earlier bugs, which the old blocks' rows didn't cover
+                    store.delete(diff_block(store, codec, &rows).extra);
+                    store.write(rows);
+                    last = block.bitcoin_hash();
+                }
+                progress.phase = ReindexPhase::Rebuilding {
+                    next: *chunk.last().unwrap() as u32 + 1,
+                    last,
+                };
+                store.write(vec![progress.to_row()]);
+                debug!("reindexed blocks up to {}", chunk.last().unwrap());
+            }
+            last
+        }
+        _ => unreachable!("the old rows are all deleted above"),
+    };
+    // the blocks indexed after the range are still valid if the block at `to` didn't change
+    if tip == *headers.header_by_height(to).unwrap().hash() {
+        tip = *headers.tip();
+    }
+    store.write(vec![last_indexed_block(&tip)]);
+    store.delete(vec![REINDEX_PROGRESS_KEY.to_vec()]);
+    info!(
+        "reindexed blocks {}..{}, last indexed block is {}",
+        from, to, tip
+    );
+    Ok(tip)
+}
+
+// The transactions of each block which are still confirmed by it, listed by its `X` row (or by
+// scanning the `T` rows, without `X` rows), since some may have been confirmed elsewhere since.
+fn old_txids(
+    store: &ReadStore,
+    blocks: &[&HeaderEntry],
+) -> Result<HashMap<Sha256dHash, Vec<Sha256dHash>>> {
+    let confirmed_by = |txid: &Sha256dHash, entry: &HeaderEntry| match txrow_by_txid(store, txid) {
+        Some(row) => row.height as usize == entry.height() && row.blockhash == *entry.hash(),
+        None => true, // its rows may still be left
+    };
+    let mut result = HashMap::new();
+    let mut unlisted = HashMap::new();
+    for entry in blocks {
+        let txids = match get_block_txids(store, entry.hash())? {
+            Some(txids) => txids
+                .into_iter()
+                .filter(|txid| confirmed_by(txid, entry))
+                .collect(),
+            None => {
+                unlisted.insert(*entry.hash(), entry.height() as u32);
+                vec![]
+            }
+        };
+        result.insert(*entry.hash(), txids);
+    }
+    if !unlisted.is_empty() {
+        for row in store.iter_scan(b"T") {
+            let row = TxRow::from_row(&row);
+            if unlisted.get(&row.blockhash) == Some(&row.height) {
+                let txid: Sha256dHash = deserialize(&row.key.txid).unwrap();
+                result.get_mut(&row.blockhash).unwrap().push(txid);
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn txrow_by_txid(store: &ReadStore, txid: &Sha256dHash) -> Option<TxRow> {
+    let key = TxRow::filter_full(txid);
+    let value = store.get(&key)?;
+    Some(TxRow::from_row(&Row { key, value }))
+}
+
+// The `I` and `O` rows of the transactions without a `t` row can only be found by their txid
+// prefix, by scanning all of them. The prefixes shared with other transactions are skipped, so
+// that their rows are kept (possibly leaving some of the old rows behind).
+fn delete_scanned_rows<S: ReadStore + WriteStore>(
+    store: &S,
+    codec: RowCodec,
+    txids: &HashMap<Sha256dHash, Vec<Sha256dHash>>,
+) -> usize {
+    let old_txids: HashSet<&Sha256dHash> = txids.values().flatten().collect();
+    let prefixes: HashSet<HashPrefix> = old_txids
+        .iter()
+        .filter(|txid| store.get(&RawTxRow::filter_full(txid)).is_none())
+        .map(|txid| codec.hash_prefix(&txid[..]))
+        .filter(|prefix| {
+            store
+                .iter_scan(&TxRow::filter_prefix(prefix))
+                .map(|row| deserialize(&TxRow::from_row(&row).key.txid).unwrap())
+                .all(|txid: Sha256dHash| old_txids.contains(&txid))
+        })
+        .collect();
+    if prefixes.is_empty() {
+        return 0;
+    }
+    let mut count = 0;
+    for code in &[b"I", b"O"] {
+        let mut keys = vec![];
+        for row in store.iter_scan(*code) {
+            let txid_prefix = &row.key[row.key.len() - codec.hash_prefix_len()..];
+            if prefixes.contains(txid_prefix) {
+                keys.push(row.key);
+            }
+        }
+        count += keys.len();
+        store.delete(keys);
+    }
+    count
+}
+
+// The rows written for `entry`'s block and its transactions (but its header, which is still
+// needed to read the chain up to the last indexed block), as far as they can be found. The `A`
+// rows are kept, since other transactions may pay the same addresses.
+fn old_block_rows(
+    store: &ReadStore,
+    codec: RowCodec,
+    entry: &HeaderEntry,
+    txids: &[Sha256dHash],
+) -> Result<Vec<Bytes>> {
+    let mut keys = vec![];
+    for txid in txids {
+        keys.push(TxRow::filter_full(txid));
+        keys.push(txid_search_key(txid));
+        let key = RawTxRow::filter_full(txid);
+        let value = match store.get(&key) {
+            Some(value) => value,
+            None => continue, // see `delete_scanned_rows`
+        };
+        let rawtx = RawTxRow::from_row(&Row {
+            key: key.clone(),
+            value,
+        })
+        .rawtx;
+        let txn: Transaction =
+            deserialize(&rawtx).chain_err(|| format!("invalid raw transaction row of {}", txid))?;
+        let mut rows = vec![];
+        index_transaction(codec, &txn, entry.height() as u32, entry.hash(), &mut rows);
+        keys.extend(
+            rows.into_iter()
+                .map(|row| row.key)
+                .filter(|key| key[0] != b'T'),
+        );
+        keys.push(key);
+    }
+    keys.push([b"M", &entry.hash()[..]].concat());
+    keys.push([b"X", &entry.hash()[..]].concat());
+    Ok(keys)
+}
+
+fn check_range(headers: &HeaderList, from: usize, to: usize) -> Result<()> {
+    if from > to || to >= headers.len() {
+        bail!(
+            "invalid range {}..{}, {} blocks are indexed",
+            from,
+            to,
+            headers.len()
+        );
+    }
+    Ok(())
+}
+
+// Extra rows can only be found under the keys the block's rows are looked up by (e.g. the spent
+// outpoint of `I` rows), for the block's own transactions.
+fn diff_block(store: &ReadStore, codec: RowCodec, rows: &[Row]) -> BlockDiff {
+    let expected: HashMap<&[u8], &[u8]> = rows
+        .iter()
+        .map(|row| (&row.key[..], &row.value[..]))
+        .collect();
+    let txid_prefixes: HashSet<HashPrefix> = rows
+        .iter()
+        .filter(|row| row.key[0] == b'T')
+        .map(|row| codec.hash_prefix(&TxRow::from_row(row).key.txid))
+        .collect();
+    let mut diff = BlockDiff::default();
+    let mut scanned = HashSet::new();
+    for row in rows {
+        match store.get(&row.key) {
+            None => diff.missing.push(row.clone()),
+            Some(ref value) if *value != row.value => diff.mismatched.push(row.clone()),
+            Some(_) => (),
+        }
+        if row.key[0] != b'I' && row.key[0] != b'O' {
+            continue; // the other rows are keyed by a full hash
+        }
+        let lookup_prefix = &row.key[..row.key.len() - codec.hash_prefix_len()];
+        if !scanned.insert(lookup_prefix) {
+            continue;
+        }
+        for stored in store.iter_scan(lookup_prefix) {
+            let txid_prefix = &stored.key[stored.key.len() - codec.hash_prefix_len()..];
+            if txid_prefixes.contains(txid_prefix) && !expected.contains_key(&stored.key[..]) {
+                diff.extra.push(stored.key);
+            }
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_block, reindex_blocks, REINDEX_PROGRESS_KEY};
+    use bitcoin::blockdata::block::{Block, BlockHeader};
+    use bitcoin::blockdata::script::Script;
+    use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::hash::{BitcoinHash, Sha256dHash};
+    use fake::MemStore;
+    use index::{index_block, last_indexed_block, read_indexed_headers, RowCodec, TxInRow};
+    use index::{TxOutRow, TxRow};
+    use std::collections::BTreeMap;
+    use store::{ReadStore, WriteStore};
+    use util::Bytes;
+
+    fn txn(prev_txid: Sha256dHash, value: u64) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: prev_txid,
+                    vout: 0,
+                },
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![value as u8]),
+            }],
+        }
+    }
+
+    fn block(prev_blockhash: Sha256dHash, nonce: u32, txdata: Vec<Transaction>) -> Block {
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: Sha256dHash::default(),
+            time: 0,
+            bits: 0,
+            nonce,
+            coinbase_txn: Transaction {
+                version: 1,
+                lock_time: 0,
+                input: vec![],
+                output: vec![],
+            },
+            block_hash: Sha256dHash::default(),
+            coinbase_branch_hashes: vec![],
+            coinbase_branch_side_mask: 0,
+            blockchain_branch_hashes: vec![],
+            blockchain_branch_side_mask: 0,
+            parent_version: 0,
+            parent_prev_blockhash: Sha256dHash::default(),
+            parent_merkle_root: Sha256dHash::default(),
+            parent_time: 0,
+            parent_bits: 0,
+            parent_nonce: 0,
+        };
+        Block { header, txdata }
+    }
+
+    // indexes `blocks` as the best chain
+    fn index_chain(codec: RowCodec, blocks: &[&Block], extended_db_enabled: bool) -> MemStore {
+        let store = MemStore::default();
+        for (height, block) in blocks.iter().enumerate() {
+            store.write(index_block(
+                codec,
+                block,
+                height as u32,
+                extended_db_enabled,
+            ));
+        }
+        store.write(vec![last_indexed_block(
+            &blocks.last().unwrap().bitcoin_hash(),
+        )]);
+        store
+    }
+
+    fn last_indexed(store: &MemStore) -> Bytes {
+        store.get(b"L").unwrap()
+    }
+
+    #[test]
+    fn test_reindex_blocks() {
+        let codec = RowCodec::new(8, Network::Regtest);
+        let coinbase = txn(Sha256dHash::default(), 50);
+        let genesis = block(Sha256dHash::default(), 0, vec![coinbase.clone()]);
+        let stale = block(
+            genesis.bitcoin_hash(),
+            1,
+            vec![txn(coinbase.txid(), 10), txn(coinbase.txid(), 11)],
+        );
+        let replacement = block(genesis.bitcoin_hash(), 2, vec![txn(coinbase.txid(), 12)]);
+
+        // the stale block is replaced, and its rows are found through its `X` and `t` rows
+        let store = index_chain(codec, &[&genesis, &stale], true);
+        let headers = read_indexed_headers(&store);
+        let stale_rows = index_block(codec, &stale, 1, true);
+        assert_eq!(
+            last_indexed(&store),
+            last_indexed_block(&stale.bitcoin_hash()).value
+        );
+        let tip = reindex_blocks(&store, &headers, (1, 1), codec, true, |heights| {
+            assert_eq!(heights, &[1]);
+            // deleted before rebuilding, while `L` is kept until the end
+            for row in &stale_rows {
+                let kept = store.get(&row.key).is_some();
+                assert_eq!(kept, row.key[0] == b'B', "{:?}", row.key);
+            }
+            assert_eq!(
+                last_indexed(&store),
+                last_indexed_block(&stale.bitcoin_hash()).value
+            );
+            assert!(store.get(REINDEX_PROGRESS_KEY).is_some());
+            Ok(vec![replacement.clone()])
+        })
+        .unwrap();
+        assert_eq!(tip, replacement.bitcoin_hash());
+        assert_eq!(last_indexed(&store), last_indexed_block(&tip).value);
+        assert!(store.get(REINDEX_PROGRESS_KEY).is_none());
+        let mut expected = index_chain(codec, &[&genesis, &replacement], true).rows();
+        for row in stale_rows.iter().filter(|row| row.key[0] == b'B') {
+            expected.insert(row.key.clone(), row.value.clone()); // a stale header
+        }
+        assert_eq!(store.rows(), expected);
+
+        // without `X` and `t` rows, the `T` rows and then the `I` and `O` rows are scanned, and
+        // the later blocks are kept
+        let next = block(
+            stale.bitcoin_hash(),
+            3,
+            vec![txn(stale.txdata[0].txid(), 5)],
+        );
+        let store = index_chain(codec, &[&genesis, &stale, &next], false);
+        let left = TxInRow::new(codec, &stale.txdata[1].txid(), &next.txdata[0].input[0]).to_row();
+        store.write(vec![left.clone()]); // left by an earlier bug
+        let mut expected = store.rows();
+        let headers = read_indexed_headers(&store);
+        let tip = reindex_blocks(&store, &headers, (1, 1), codec, false, |_| {
+            assert!(store.get(&left.key).is_none());
+            assert!(store
+                .get(&TxRow::filter_full(&stale.txdata[0].txid()))
+                .is_none());
+            assert_eq!(
+                last_indexed(&store),
+                last_indexed_block(&next.bitcoin_hash()).value
+            );
+            Ok(vec![stale.clone()])
+        })
+        .unwrap();
+        assert_eq!(tip, next.bitcoin_hash());
+        expected.remove(&left.key);
+        assert_eq!(store.rows(), expected);
+    }
+
+    #[test]
+    fn test_diff_block() {
+        let txid = Sha256dHash::from_data(b"tx");
+        let other_txid = Sha256dHash::from_data(b"other tx");
+        let earlier_txid = Sha256dHash::from_data(b"earlier tx");
+        let blockhash = Sha256dHash::from_data(b"block");
+        let codec = RowCodec::new(8, Network::Regtest);
+        let output = |value| TxOut {
+            value,
+            script_pubkey: Script::new(),
+        };
+        let rows = vec![
+            TxRow::new(&txid, 10, &blockhash).to_row(),
+            TxOutRow::new(codec, &txid, &output(1)).to_row(),
+            TxRow::new(&other_txid, 10, &blockhash).to_row(),
+        ];
+        // an earlier transaction funding the same script isn't extra
+        let funding = TxOutRow::new(codec, &earlier_txid, &output(2)).to_row();
+        let mut stored: BTreeMap<Bytes, Bytes> = BTreeMap::new();
+        stored.insert(funding.key, funding.value);
+
+        let diff = diff_block(&MemStore::new(stored.clone()), codec, &rows);
+        assert_eq!(diff.missing.len(), 3);
+        assert!(diff.mismatched.is_empty() && diff.extra.is_empty());
+
+        for row in &rows {
+            stored.insert(row.key.clone(), row.value.clone());
+        }
+        let moved = TxRow::new(&txid, 11, &blockhash).to_row();
+        stored.insert(moved.key, moved.value);
+        let extra = TxOutRow::new(codec, &other_txid, &output(3)).to_row();
+        stored.insert(extra.key.clone(), extra.value);
+        let diff = diff_block(&MemStore::new(stored), codec, &rows);
+        assert!(diff.missing.is_empty());
+        assert_eq!(diff.mismatched.len(), 1);
+        assert_eq!(diff.mismatched[0].key, rows[0].key);
+        assert_eq!(diff.extra, vec![extra.key]);
+    }
+}
@@ -0,0 +1,285 @@
+use bitcoin::network::constants::Network;
+use bitcoin::util::address::Address;
+use bitcoin::util::bip32::{ChildNumber, ExtendedPubKey};
+use bitcoin::util::hash::Sha256dHash;
+use secp256k1::{Secp256k1, VerifyOnly};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use index::compute_script_hash;
+use query::{FundingOutput, Query, ReadContext, Status, TxnHeight};
+use util::spawn_thread;
+
+use errors::*;
+
+const CHAINS: [u32; 2] = [0, 1]; // receive and change chains
+const MAX_DERIVED_ADDRESSES: u32 = 1000; // per chain, bounds the work done by a single scan
+const LOOKUP_THREADS: usize = 4; // per window of derived addresses
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScriptType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+}
+
+impl ScriptType {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2shP2wpkh => "p2sh-p2wpkh",
+            ScriptType::P2wpkh => "p2wpkh",
+        }
+    }
+}
+
+pub struct Descriptor {
+    xpub: ExtendedPubKey,
+    script_type: ScriptType,
+}
+
+fn strip<'a>(s: &'a str, prefix: &str, suffix: &str) -> Option<&'a str> {
+    if s.len() >= prefix.len() + suffix.len() && s.starts_with(prefix) && s.ends_with(suffix) {
+        Some(&s[prefix.len()..s.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+impl Descriptor {
+    /// Parses either a bare xpub (treated as P2PKH) or one of the `pkh(<xpub>)`,
+    /// `sh(wpkh(<xpub>))` and `wpkh(<xpub>)` output descriptors.
+    pub fn parse(s: &str) -> Result<Descriptor> {
+        let (script_type, key) = if let Some(key) = strip(s, "sh(wpkh(", "))") {
+            (ScriptType::P2shP2wpkh, key)
+        } else if let Some(key) = strip(s, "wpkh(", ")") {
+            (ScriptType::P2wpkh, key)
+        } else if let Some(key) = strip(s, "pkh(", ")") {
+            (ScriptType::P2pkh, key)
+        } else {
+            (ScriptType::P2pkh, s)
+        };
+        let xpub = ExtendedPubKey::from_str(key).chain_err(|| "invalid extended public key")?;
+        Ok(Descriptor { xpub, script_type })
+    }
+
+    pub fn network(&self) -> Network {
+        self.xpub.network
+    }
+
+    pub fn script_type(&self) -> ScriptType {
+        self.script_type
+    }
+
+    /// Derives the address at `<xpub>/<chain>/<index>`.
+    pub fn derive(
+        &self,
+        secp: &Secp256k1<VerifyOnly>,
+        chain: u32,
+        index: u32,
+        network: Network,
+    ) -> Result<Address> {
+        let path = [
+            ChildNumber::from_normal_idx(chain),
+            ChildNumber::from_normal_idx(index),
+        ];
+        let key = self
+            .xpub
+            .derive_pub(secp, &path)
+            .chain_err(|| format!("failed to derive {}/{}", chain, index))?;
+        Ok(match self.script_type {
+            ScriptType::P2pkh => Address::p2pkh(&key.public_key, network),
+            ScriptType::P2shP2wpkh => Address::p2shwpkh(&key.public_key, network),
+            ScriptType::P2wpkh => Address::p2wpkh(&key.public_key, network),
+        })
+    }
+}
+
+pub struct DerivedAddress {
+    pub address: Address,
+    pub chain: u32,
+    pub index: u32,
+    pub status: Status,
+}
+
+impl DerivedAddress {
+    pub fn path(&self) -> String {
+        format!("{}/{}", self.chain, self.index)
+    }
+}
+
+pub struct XpubStatus {
+    pub used: Vec<DerivedAddress>,
+    pub next_receive_index: u32,
+    pub next_change_index: u32,
+}
+
+impl XpubStatus {
+    pub fn confirmed_balance(&self) -> i64 {
+        self.used.iter().map(|a| a.status.confirmed_balance()).sum()
+    }
+
+    pub fn mempool_balance(&self) -> i64 {
+        self.used.iter().map(|a| a.status.mempool_balance()).sum()
+    }
+
+    pub fn total_received(&self) -> i64 {
+        self.used.iter().map(|a| a.status.total_received()).sum()
+    }
+
+    /// Transactions touching any of the used addresses, deduplicated and sorted like
+    /// `Status::history_txs()`.
+    pub fn history_txs(&self) -> Vec<&TxnHeight> {
+        let mut txns_map = BTreeMap::<Sha256dHash, &TxnHeight>::new();
+        for addr in &self.used {
+            for t in addr.status.history_txs() {
+                txns_map.insert(t.txn.txid(), t);
+            }
+        }
+        let mut txns: Vec<&TxnHeight> = txns_map.into_iter().map(|item| item.1).collect();
+        txns.sort_by(|a, b| b.height.cmp(&a.height));
+        txns
+    }
+
+    pub fn unspent(&self) -> Vec<(&DerivedAddress, &FundingOutput)> {
+        let mut outputs: Vec<(&DerivedAddress, &FundingOutput)> = self
+            .used
+            .iter()
+            .flat_map(|addr| {
+                addr.status
+                    .unspent()
+                    .into_iter()
+                    .map(move |out| (addr, out))
+            })
+            .collect();
+        outputs.sort_by_key(|&(_, out)| out.height);
+        outputs
+    }
+}
+
+fn load_statuses(
+    query: &Arc<Query>,
+    ctx: &ReadContext,
+    addresses: Vec<(u32, Address)>,
+) -> Result<Vec<(u32, Address, Status)>> {
+    // each thread looks up a contiguous chunk, so that a window costs a bounded number of threads
+    let chunk_size = (addresses.len() + LOOKUP_THREADS - 1) / LOOKUP_THREADS;
+    let handles: Vec<_> = addresses
+        .chunks(chunk_size.max(1))
+        .map(|chunk| {
+            let query = query.clone();
+            let ctx = ctx.clone();
+            let chunk = chunk.to_vec();
+            spawn_thread("xpub", move || {
+                chunk
+                    .into_iter()
+                    .map(|(index, address)| {
+                        let script_hash =
+                            compute_script_hash(&address.script_pubkey().into_bytes());
+                        query
+                            .status(&ctx, &script_hash[..])
+                            .map(|status| (index, address, status))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+        })
+        .collect();
+    let mut result = vec![];
+    for handle in handles {
+        match handle.join() {
+            Ok(statuses) => result.extend(statuses?),
+            Err(_) => bail!("xpub status lookup panicked"),
+        }
+    }
+    Ok(result)
+}
+
+/// Scans the receive and change chains until `gap_limit` consecutive unused addresses are found,
+/// querying each window of derived addresses on a few threads.
+pub fn scan(
+    query: &Arc<Query>,
+    ctx: &ReadContext,
+    descriptor: &Descriptor,
+    network: Network,
+    gap_limit: u32,
+) -> Result<XpubStatus> {
+    let secp = Secp256k1::verification_only();
+    let mut used = vec![];
+    let mut next_index = [0u32; 2];
+    for &chain in CHAINS.iter() {
+        let mut start = 0;
+        let mut unused_run = 0;
+        while unused_run < gap_limit && start < MAX_DERIVED_ADDRESSES {
+            let end = (start + gap_limit).min(MAX_DERIVED_ADDRESSES);
+            let addresses = (start..end)
+                .map(|index| Ok((index, descriptor.derive(&secp, chain, index, network)?)))
+                .collect::<Result<Vec<(u32, Address)>>>()?;
+            for (index, address, status) in load_statuses(query, ctx, addresses)? {
+                if status.history().is_empty() {
+                    unused_run += 1;
+                } else {
+                    unused_run = 0;
+                    next_index[chain as usize] = index + 1;
+                    used.push(DerivedAddress {
+                        address,
+                        chain,
+                        index,
+                        status,
+                    });
+                }
+            }
+            start = end;
+        }
+    }
+    Ok(XpubStatus {
+        used,
+        next_receive_index: next_index[0],
+        next_change_index: next_index[1],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::network::constants::Network;
+    use secp256k1::Secp256k1;
+    use xpub::{Descriptor, ScriptType};
+
+    // BIP32 test vector 1, chain m/0H/1
+    const XPUB: &str = "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ";
+
+    #[test]
+    fn test_parse_descriptor() {
+        let cases = [
+            (XPUB.to_string(), ScriptType::P2pkh),
+            (format!("pkh({})", XPUB), ScriptType::P2pkh),
+            (format!("wpkh({})", XPUB), ScriptType::P2wpkh),
+            (format!("sh(wpkh({}))", XPUB), ScriptType::P2shP2wpkh),
+        ];
+        for &(ref s, script_type) in cases.iter() {
+            let descriptor = Descriptor::parse(s).unwrap();
+            assert_eq!(descriptor.script_type(), script_type);
+            assert_eq!(descriptor.network(), Network::Bitcoin);
+        }
+        assert!(Descriptor::parse("xpub").is_err());
+        assert!(Descriptor::parse(&format!("sh({})", XPUB)).is_err());
+    }
+
+    #[test]
+    fn test_derive() {
+        let secp = Secp256k1::verification_only();
+        let descriptor = Descriptor::parse(XPUB).unwrap();
+        let receive = descriptor.derive(&secp, 0, 0, Network::Bitcoin).unwrap();
+        let change = descriptor.derive(&secp, 1, 0, Network::Bitcoin).unwrap();
+        assert!(receive.script_pubkey().is_p2pkh());
+        assert_ne!(receive, change);
+
+        let descriptor = Descriptor::parse(&format!("sh(wpkh({}))", XPUB)).unwrap();
+        let address = descriptor.derive(&secp, 0, 0, Network::Bitcoin).unwrap();
+        assert!(address.script_pubkey().is_p2sh());
+
+        let descriptor = Descriptor::parse(&format!("wpkh({})", XPUB)).unwrap();
+        let address = descriptor.derive(&secp, 0, 0, Network::Bitcoin).unwrap();
+        assert!(address.script_pubkey().is_v0_p2wpkh());
+    }
+}